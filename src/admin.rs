@@ -1,4 +1,8 @@
 use crate::database::{Database, LicenceKey};
+use crate::control::{CtlRequest, CtlResponse, CtlSender};
+use crate::key_cache::KeyCache;
+use crate::key_format;
+use crate::key_io;
 use axum::{
     extract::{Path, Query, Extension, Form},
     http::{header, Request, StatusCode},
@@ -7,7 +11,7 @@ use axum::{
     routing::{get, post},
     Json, Router,
 };
-use headers::authorization::{Authorization, Basic};
+use headers::authorization::{Authorization, Basic, Bearer};
 use headers::HeaderMapExt;
 // removed duplicate import of StatusCode
 use serde::{Deserialize, Serialize};
@@ -16,6 +20,8 @@ use std::net::SocketAddr;
 #[derive(Clone)]
 pub struct AdminState {
     pub db: Database,
+    pub keys: KeyCache,
+    pub ctl: CtlSender,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,11 +34,42 @@ struct ListParams {
 #[derive(Debug, Deserialize)]
 struct CreateKeyForm {
     key: Option<String>,
+    prefix: Option<String>, // reseller prefix of generated keys, see key-prefixes
     duration: Option<String>, // e.g. 10d,2w,3m,1y,permanent
     note: Option<String>,
     max_bind_ids: Option<i32>,
 }
 
+#[derive(Debug, Deserialize)]
+struct GenerateParams {
+    prefix: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkParams {
+    count: usize,
+    prefix: Option<String>,
+    duration: Option<String>,
+    note: Option<String>,
+    max_bind_ids: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct BulkResponse {
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportParams {
+    format: Option<String>, // csv or json, guessed from the body if not set
+    dry_run: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportParams {
+    format: Option<String>, // csv (default) or json
+}
+
 #[derive(Debug, Deserialize)]
 struct ExtendParams {
     option: String, // 1d,7d,1m,1q,1y
@@ -44,20 +81,55 @@ struct ListResponse {
     items: Vec<LicenceKey>,
 }
 
-async fn auth_middleware<B>(req: Request<B>, next: Next<B>) -> Response {
-    let user = std::env::var("ADMIN_USER").unwrap_or_else(|_| "elonlee".to_string());
-    let pass = std::env::var("ADMIN_PASS").unwrap_or_else(|_| "Yiner520@".to_string());
-    let unauthorized = || {
-        (StatusCode::UNAUTHORIZED,
-         [(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Basic realm=\"Admin\""))],
-         "Unauthorized").into_response()
+// compare digests in constant time, which leaks neither the secret nor its length
+fn secure_eq(a: &str, b: &str) -> bool {
+    use sodiumoxide::crypto::hash::sha256;
+    sodiumoxide::utils::memcmp(&sha256::hash(a.as_bytes()).0, &sha256::hash(b.as_bytes()).0)
+}
+
+/// Whether ADMIN_TOKEN, or ADMIN_USER and ADMIN_PASS, are set. The admin UI,
+/// the keys API and the control APIs are only served then.
+pub fn has_admin_credentials() -> bool {
+    let settings = crate::settings::get();
+    let set = |x: &Option<String>| x.as_deref().map_or(false, |x| !x.is_empty());
+    set(&settings.admin_token) || (set(&settings.admin_user) && set(&settings.admin_pass))
+}
+
+fn authorized<B>(req: &Request<B>) -> bool {
+    let settings = crate::settings::get();
+    let token = settings.admin_token.as_deref().unwrap_or_default();
+    if !token.is_empty() {
+        if let Some(Authorization(bearer)) = req.headers().typed_get::<Authorization<Bearer>>() {
+            if secure_eq(bearer.token(), token) {
+                return true;
+            }
+        }
+    }
+    let (user, pass) = match (settings.admin_user.as_deref(), settings.admin_pass.as_deref()) {
+        (Some(user), Some(pass)) if !user.is_empty() && !pass.is_empty() => (user, pass),
+        _ => return false,
     };
     if let Some(Authorization(basic)) = req.headers().typed_get::<Authorization<Basic>>() {
-        let u = basic.username();
-        let p = basic.password();
-        if u == user && p == pass {
-            return next.run(req).await;
-        }
+        return secure_eq(basic.username(), user) & secure_eq(basic.password(), pass);
+    }
+    false
+}
+
+fn unauthorized() -> Response {
+    (StatusCode::UNAUTHORIZED,
+     [(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Basic realm=\"Admin\""))],
+     "Unauthorized").into_response()
+}
+
+/// Basic auth with ADMIN_USER/ADMIN_PASS, or a bearer token if ADMIN_TOKEN is
+/// set, for the admin APIs of hbbs and hbbr. There are no default credentials,
+/// every request is refused until they are set.
+pub async fn auth_middleware<B>(req: Request<B>, next: Next<B>) -> Response {
+    if !has_admin_credentials() {
+        return (StatusCode::FORBIDDEN, "Set admin-token or admin-user and admin-pass").into_response();
+    }
+    if authorized(&req) {
+        return next.run(req).await;
     }
     unauthorized()
 }
@@ -80,20 +152,32 @@ fn ttl_seconds_for_option(option: &str) -> Option<i64> {
     Some(seconds.max(0))
 }
 
-fn generate_default_key() -> String {
-    // 32位 UUID（无中划线）
-    uuid::Uuid::new_v4().simple().to_string()
+fn expired_at_for(duration: Option<&str>) -> i64 {
+    match duration {
+        Some(d) => match ttl_seconds_for_option(d) {
+            Some(sec) => chrono::Utc::now().timestamp() + sec,
+            None => key_io::PERMANENT, // 近似永久
+        },
+        None => chrono::Utc::now().timestamp() + key_io::DEFAULT_TTL,
+    }
 }
 
-fn is_valid_key_format(s: &str) -> bool {
-    let ok_len = s.len() == 32;
-    ok_len && s.chars().all(|c| c.is_ascii_hexdigit())
+fn bad_request(err: impl ToString) -> Response {
+    (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+}
+
+async fn generate_key(Query(p): Query<GenerateParams>) -> Response {
+    match key_format::generate(p.prefix.as_deref()) {
+        Ok(key) => key.into_response(),
+        Err(err) => bad_request(err),
+    }
 }
 
 async fn list_keys(Extension(state): Extension<AdminState>, Query(p): Query<ListParams>) -> Json<ListResponse> {
     if let Some(k) = p.key.as_ref().map(|s| s.trim()).filter(|s| !s.is_empty()) {
         let mut items = vec![];
-        if let Ok(Some(rec)) = state.db.get_key(k).await {
+        let k = key_format::stored(k);
+        if let Ok(Some(rec)) = state.db.get_key(&k).await {
             items.push(rec);
         }
         return Json(ListResponse { total: items.len() as i64, items });
@@ -106,31 +190,86 @@ async fn list_keys(Extension(state): Extension<AdminState>, Query(p): Query<List
 }
 
 async fn create_key(Extension(state): Extension<AdminState>, Form(p): Form<CreateKeyForm>) -> Html<String> {
-    let mut key = p.key.unwrap_or_else(generate_default_key);
-    if key.trim().is_empty() {
-        return Html("<p style='color:red'>Key不能为空</p>".to_string());
-    }
-    if !is_valid_key_format(&key) {
-        return Html("<p style='color:red'>Key必须为32位UUID（无中划线）</p>".to_string());
-    }
+    // 留空则按 key-format 生成
+    let key = match p.key.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+        Some(k) => key_format::normalize(k),
+        None => key_format::generate(p.prefix.as_deref()).map_err(|e| e.to_string()),
+    };
+    let key = match key {
+        Ok(key) => key,
+        Err(err) => return Html(format!("<p style='color:red'>Key格式错误: {}</p>", err)),
+    };
     if state.db.key_exists(&key).await.unwrap_or(false) {
         return Html("<p style='color:red'>Key已存在</p>".to_string());
     }
-    let expired_at = match p.duration.as_deref() {
-        Some(d) => match ttl_seconds_for_option(d) {
-            Some(sec) => chrono::Utc::now().timestamp() + sec,
-            None => i64::MAX / 2, // 近似永久
-        },
-        None => chrono::Utc::now().timestamp() + 86400 * 30,
-    };
+    let expired_at = expired_at_for(p.duration.as_deref());
     let max_bind = p.max_bind_ids.unwrap_or(3).clamp(1, 1000);
     let _ = state.db.insert_key(&key, expired_at, true, p.note.as_deref(), max_bind).await;
+    state.keys.refresh(&key).await;
     Html(format!("<meta http-equiv=\"refresh\" content=\"0;url=/admin\"><p>Created key: {}</p>", key))
 }
 
+async fn bulk_create(Extension(state): Extension<AdminState>, Json(p): Json<BulkParams>) -> Response {
+    let res = key_io::bulk_create(
+        &state.db,
+        p.count,
+        expired_at_for(p.duration.as_deref().filter(|x| !x.trim().is_empty())),
+        p.note.as_deref().filter(|x| !x.is_empty()),
+        p.max_bind_ids.unwrap_or(key_io::DEFAULT_MAX_BIND),
+        p.prefix.as_deref(),
+    )
+    .await;
+    match res {
+        Ok(keys) => {
+            for key in keys.iter() {
+                state.keys.refresh(key).await;
+            }
+            Json(BulkResponse { keys }).into_response()
+        }
+        Err(err) => bad_request(err),
+    }
+}
+
+async fn import_keys(Extension(state): Extension<AdminState>, Query(p): Query<ImportParams>, body: String) -> Response {
+    let format = match p.format.as_deref().map(str::parse) {
+        Some(Ok(format)) => format,
+        Some(Err(err)) => return bad_request(err),
+        None => key_io::Format::detect(&body),
+    };
+    match key_io::import(&state.db, &body, format, p.dry_run.unwrap_or(false)).await {
+        Ok(report) => {
+            for key in report.imported_keys() {
+                state.keys.refresh(key).await;
+            }
+            Json(report).into_response()
+        }
+        Err(err) => bad_request(err),
+    }
+}
+
+async fn export_keys(Extension(state): Extension<AdminState>, Query(p): Query<ExportParams>) -> Response {
+    let format = match p.format.as_deref().map(str::parse) {
+        Some(Ok(format)) => format,
+        Some(Err(err)) => return bad_request(err),
+        None => key_io::Format::Csv,
+    };
+    match key_io::export(&state.db, format).await {
+        Ok(text) => {
+            let file = match format {
+                key_io::Format::Csv => "attachment; filename=\"keys.csv\"",
+                key_io::Format::Json => "attachment; filename=\"keys.json\"",
+            };
+            ([(header::CONTENT_TYPE, format.content_type()), (header::CONTENT_DISPOSITION, file)], text)
+                .into_response()
+        }
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
 async fn set_key_max_bind(Extension(state): Extension<AdminState>, Path((key, n)): Path<(String, i32)>) -> Html<String> {
     let n = n.clamp(1, 1000);
     let _ = state.db.set_key_max_bind(&key, n).await;
+    state.keys.refresh(&key).await;
     Html("<meta http-equiv=\"refresh\" content=\"0;url=/admin\">".to_string())
 }
 
@@ -141,14 +280,24 @@ async fn extend_key(Extension(state): Extension<AdminState>, Path(key): Path<Str
         // 永久：设置为很大时间
         let _ = state.db.extend_key_by(&key, (i64::MAX/2) - chrono::Utc::now().timestamp()).await;
     }
+    state.keys.refresh(&key).await;
     Html("<meta http-equiv=\"refresh\" content=\"0;url=/admin\">".to_string())
 }
 
 async fn set_key_active(Extension(state): Extension<AdminState>, Path((key, flag)): Path<(String, i32)>) -> Html<String> {
     let _ = state.db.set_key_active(&key, flag != 0).await;
+    state.keys.refresh(&key).await;
     Html("<meta http-equiv=\"refresh\" content=\"0;url=/admin\">".to_string())
 }
 
+async fn control(Extension(state): Extension<AdminState>, Json(req): Json<CtlRequest>) -> CtlResponse {
+    let (tx, rx) = hbb_common::tokio::sync::oneshot::channel();
+    if state.ctl.send((req, tx)).is_err() {
+        return CtlResponse::err("server is not running");
+    }
+    rx.await.unwrap_or_else(|_| CtlResponse::err("no response from server"))
+}
+
 async fn index_html() -> Html<String> {
     let html = r#"<!doctype html>
 <html>
@@ -166,9 +315,10 @@ async fn index_html() -> Html<String> {
   <body>
     <h2>Keys Admin</h2>
     <div style='margin-bottom:12px;'>
-      <input id='searchKey' placeholder='按Key查询（32位HEX）' style='width:260px' />
+      <input id='searchKey' placeholder='按Key查询' style='width:260px' />
       <button type='button' onclick='searchByKey()'>查询</button>
       <button type='button' onclick='openCreate()'>新增</button>
+      <a href='/api/keys/export?format=csv'>导出CSV</a>
     </div>
 
     <div id='createModal' style='display:none; position:fixed; left:0; top:0; right:0; bottom:0; background:rgba(0,0,0,.35);'>
@@ -176,9 +326,12 @@ async fn index_html() -> Html<String> {
         <h3>创建新Key</h3>
         <form id='createForm' method='post' action='/api/keys' onsubmit='return submitCreate(event)'>
           <div style='margin-bottom:8px'>
-            <label>Key（32位UUID，无-）: <input id='key' name='key' maxlength='32' pattern='[0-9a-fA-F]{32}' /></label>
+            <label>Key（留空自动生成）: <input id='key' name='key' maxlength='64' /></label>
             <button type='button' onclick='gen()'>生成</button>
           </div>
+          <div style='margin-bottom:8px'>
+            <label>前缀: <input id='prefix' name='prefix' maxlength='16' placeholder='key-prefixes 之一，留空不加前缀' /></label>
+          </div>
           <div style='margin-bottom:8px'>
             <label>时长: <input id='duration' name='duration' placeholder='如: 10d/2w/3m/1y/permanent' /></label>
           </div>
@@ -197,7 +350,10 @@ async fn index_html() -> Html<String> {
     </div>
     <div id='list'></div>
     <script>
-      function gen(){ fetch('/api/keys/generate').then(r=>r.text()).then(t=>{ document.getElementById('key').value = t; }); }
+      function gen(){
+        const prefix = encodeURIComponent(document.getElementById('prefix').value);
+        fetch(`/api/keys/generate?prefix=${prefix}`).then(r=>r.text()).then(t=>{ document.getElementById('key').value = t; });
+      }
       let offset = 0, limit = 20; let currentKey = '';
       async function load() {
         const q = currentKey ? `&key=${encodeURIComponent(currentKey)}` : '';
@@ -250,22 +406,36 @@ async fn index_html() -> Html<String> {
     Html(html.to_string())
 }
 
-pub async fn spawn_admin(db: Database, base_port: i32) {
-    let state = AdminState { db };
-    let app = Router::new()
-        .route("/admin", get(index_html))
-        .route("/api/keys", get(list_keys).post(create_key))
-        .route("/api/keys/generate", get(|| async { generate_default_key() }))
-        .route("/api/keys/:key/extend", get(extend_key))
-        .route("/api/keys/:key/max/:n", get(set_key_max_bind))
-        .route("/api/keys/:key/active/:flag", get(set_key_active))
-        .layer(middleware::from_fn(auth_middleware))
-        .layer(axum::Extension(state));
+pub async fn spawn_admin(db: Database, keys: KeyCache, ctl: CtlSender, base_port: i32) {
+    let state = AdminState { db, keys, ctl };
+    let app = Router::new().route("/readyz", get(crate::shutdown::readyz));
+    let app = if has_admin_credentials() {
+        app.merge(
+            Router::new()
+                .route("/admin", get(index_html))
+                .route("/api/keys", get(list_keys).post(create_key))
+                .route("/api/keys/generate", get(generate_key))
+                .route("/api/keys/bulk", post(bulk_create))
+                .route("/api/keys/import", post(import_keys))
+                .route("/api/keys/export", get(export_keys))
+                .route("/api/keys/:key/extend", get(extend_key))
+                .route("/api/keys/:key/max/:n", get(set_key_max_bind))
+                .route("/api/keys/:key/active/:flag", get(set_key_active))
+                .route("/api/ctl", post(control))
+                .layer(middleware::from_fn(auth_middleware)),
+        )
+    } else {
+        hbb_common::log::warn!(
+            "Admin UI, keys and control API disabled, set admin-token or admin-user and admin-pass"
+        );
+        app
+    };
+    let app = app.layer(axum::Extension(state));
 
     // Bind to localhost only
-    let port = std::env::var("ADMIN_PORT")
-        .ok()
-        .and_then(|v| v.parse::<i32>().ok())
+    let port = crate::settings::get()
+        .admin_port
+        .map(|x| x as i32)
         .unwrap_or(base_port + 100);
     let addr: SocketAddr = format!("0.0.0.0:{}", port).parse().unwrap();
     hbb_common::tokio::spawn(async move {
@@ -273,7 +443,9 @@ pub async fn spawn_admin(db: Database, base_port: i32) {
             hbb_common::log::error!("Admin server failed: {}", e);
         }
     });
-    hbb_common::log::info!("Admin UI: http://{}/admin", addr);
+    if has_admin_credentials() {
+        hbb_common::log::info!("Admin UI: http://{}/admin", addr);
+    }
 }
 
 
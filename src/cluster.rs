@@ -0,0 +1,384 @@
+// Peer location sharing between several hbbs nodes.
+//
+// Every node periodically announces the peers registered on it to the other
+// nodes over UDP, one JSON message per datagram. A punch hole or relay request
+// for a peer registered on another node is delivered to that node, which sends
+// it to the peer over its own UDP socket (the one the peer's NAT mapping points
+// to), and remembers where the requester is waiting so that the peer's answer
+// can be sent back.
+//
+// Datagrams are only accepted from the configured address and port of a node,
+// and carry an HMAC-SHA256 with the key derived from `cluster-secret` over a
+// timestamp, a nonce and the JSON message. Stale or replayed datagrams are
+// dropped.
+use hbb_common::{
+    bail, log,
+    protobuf::Message as _,
+    rendezvous_proto::RendezvousMessage,
+    sleep,
+    tokio::{
+        self,
+        net::UdpSocket,
+        sync::{mpsc, Mutex, RwLock},
+    },
+    try_into_v4, ResultType,
+};
+use serde_derive::{Deserialize, Serialize};
+use sodiumoxide::crypto::{auth::hmacsha256, hash::sha256};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Instant,
+};
+
+pub(crate) const ANNOUNCE_INTERVAL: u64 = 5_000;
+// a remote peer is forgotten if not announced again within this time (ms)
+const PEER_TIMEOUT: u128 = ANNOUNCE_INTERVAL as u128 * 3;
+// how long a node waits for the answer to a delivered request (ms)
+const ORIGIN_TIMEOUT: u128 = 30_000;
+const PEERS_PER_DATAGRAM: usize = 100;
+const MAX_DATAGRAM: usize = 65_507;
+// how far the timestamp of a datagram may be off (ms)
+const MAX_SKEW: i64 = 30_000;
+// tag, timestamp, nonce
+const HEADER_LEN: usize = hmacsha256::TAGBYTES + 8 + 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum ClusterMsg {
+    /// Peers currently registered on the sending node, with their socket address.
+    Peers(Vec<(String, SocketAddr)>),
+    /// Send `msg`, a serialized `RendezvousMessage`, to the peer `to`.
+    /// `from` is the requester, waiting on the sending node.
+    Deliver {
+        to: SocketAddr,
+        from: SocketAddr,
+        msg: Vec<u8>,
+    },
+    /// Answer for `to`, a requester waiting on the receiving node.
+    Reply { to: SocketAddr, msg: Vec<u8> },
+}
+
+struct RemotePeer {
+    node: SocketAddr,
+    addr: SocketAddr,
+    tm: Instant,
+}
+
+#[derive(Clone)]
+pub(crate) struct Cluster {
+    socket: Arc<UdpSocket>,
+    nodes: Arc<Vec<SocketAddr>>,
+    peers: Arc<RwLock<HashMap<String, RemotePeer>>>,
+    origins: Arc<Mutex<HashMap<SocketAddr, (SocketAddr, Instant)>>>,
+    key: Arc<hmacsha256::Key>,
+    // nonce -> timestamp of the datagrams seen within MAX_SKEW
+    seen: Arc<Mutex<HashMap<u64, i64>>>,
+}
+
+fn derive_key(secret: &str) -> hmacsha256::Key {
+    hmacsha256::Key(sha256::hash(secret.as_bytes()).0)
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+fn seal(key: &hmacsha256::Key, msg: &ClusterMsg, tm: i64, nonce: u64) -> ResultType<Vec<u8>> {
+    let mut data = vec![0u8; hmacsha256::TAGBYTES];
+    data.extend(tm.to_be_bytes());
+    data.extend(nonce.to_be_bytes());
+    data.extend(serde_json::to_vec(msg)?);
+    let tag = hmacsha256::authenticate(&data[hmacsha256::TAGBYTES..], key);
+    data[..hmacsha256::TAGBYTES].copy_from_slice(&tag.0);
+    Ok(data)
+}
+
+// (message, timestamp, nonce) of an authentic datagram that is not stale
+fn open(key: &hmacsha256::Key, data: &[u8], now: i64) -> ResultType<(ClusterMsg, i64, u64)> {
+    if data.len() < HEADER_LEN {
+        bail!("short datagram");
+    }
+    let (tag, body) = data.split_at(hmacsha256::TAGBYTES);
+    let Some(tag) = hmacsha256::Tag::from_slice(tag) else {
+        bail!("short datagram");
+    };
+    if !hmacsha256::verify(&tag, body, key) {
+        bail!("bad signature, is cluster-secret the same on all nodes?");
+    }
+    let tm = i64::from_be_bytes(body[..8].try_into()?);
+    let nonce = u64::from_be_bytes(body[8..16].try_into()?);
+    if (now - tm).abs() > MAX_SKEW {
+        bail!("stale datagram, {} ms off", now - tm);
+    }
+    Ok((serde_json::from_slice(&body[16..])?, tm, nonce))
+}
+
+impl Cluster {
+    pub(crate) async fn new(port: u16, nodes: &[String], secret: &str) -> ResultType<Self> {
+        if secret.is_empty() {
+            bail!("cluster-nodes needs cluster-secret");
+        }
+        let nodes: Vec<SocketAddr> = nodes
+            .iter()
+            .filter_map(|x| {
+                let host = if x.contains(':') {
+                    x.to_owned()
+                } else {
+                    format!("{x}:{port}")
+                };
+                host.to_socket_addrs().ok()?.next().map(try_into_v4)
+            })
+            .collect();
+        let ip: IpAddr = if nodes.iter().all(|x| x.is_ipv4()) {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(ip, port)).await?;
+        log::info!("Listening on udp :{}, cluster nodes: {:?}", port, nodes);
+        Ok(Self {
+            socket: Arc::new(socket),
+            nodes: Arc::new(nodes),
+            peers: Default::default(),
+            origins: Default::default(),
+            key: Arc::new(derive_key(secret)),
+            seen: Default::default(),
+        })
+    }
+
+    #[inline]
+    fn is_node(&self, addr: SocketAddr) -> bool {
+        self.nodes.contains(&addr)
+    }
+
+    // the message of an authentic datagram seen for the first time
+    async fn accept(&self, data: &[u8]) -> ResultType<ClusterMsg> {
+        let (msg, tm, nonce) = open(&self.key, data, now_ms())?;
+        if self.seen.lock().await.insert(nonce, tm).is_some() {
+            bail!("replayed datagram");
+        }
+        Ok(msg)
+    }
+
+    /// Receive messages from the other nodes. Peer announcements are handled
+    /// here, deliveries and replies are passed on to `tx`.
+    pub(crate) fn spawn(&self, tx: mpsc::UnboundedSender<ClusterMsg>) {
+        let me = self.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            loop {
+                let (n, src) = match me.socket.recv_from(&mut buf).await {
+                    Ok(x) => x,
+                    Err(err) => {
+                        log::error!("cluster recv failed: {}", err);
+                        sleep(1.).await;
+                        continue;
+                    }
+                };
+                let src = try_into_v4(src);
+                if !me.is_node(src) {
+                    log::warn!("Ignore cluster message from unknown node {}", src);
+                    continue;
+                }
+                let msg = match me.accept(&buf[..n]).await {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        log::warn!("Ignore cluster message from {}: {}", src, err);
+                        continue;
+                    }
+                };
+                match &msg {
+                    ClusterMsg::Peers(peers) => {
+                        let now = Instant::now();
+                        let mut lock = me.peers.write().await;
+                        for (id, addr) in peers {
+                            lock.insert(
+                                id.clone(),
+                                RemotePeer {
+                                    node: src,
+                                    addr: *addr,
+                                    tm: now,
+                                },
+                            );
+                        }
+                        continue;
+                    }
+                    ClusterMsg::Deliver { from, .. } => {
+                        me.origins
+                            .lock()
+                            .await
+                            .insert(*from, (src, Instant::now()));
+                    }
+                    ClusterMsg::Reply { .. } => {}
+                }
+                tx.send(msg).ok();
+            }
+        });
+    }
+
+    /// Tell the other nodes which peers are registered here, and forget
+    /// stale state received from them.
+    pub(crate) async fn announce(&self, peers: Vec<(String, SocketAddr)>) {
+        for chunk in peers.chunks(PEERS_PER_DATAGRAM) {
+            self.send_all(&ClusterMsg::Peers(chunk.to_vec())).await;
+        }
+        self.peers
+            .write()
+            .await
+            .retain(|_, x| x.tm.elapsed().as_millis() < PEER_TIMEOUT);
+        self.origins
+            .lock()
+            .await
+            .retain(|_, x| x.1.elapsed().as_millis() < ORIGIN_TIMEOUT);
+        let now = now_ms();
+        self.seen
+            .lock()
+            .await
+            .retain(|_, tm| (now - *tm).abs() <= MAX_SKEW);
+    }
+
+    /// Returns (node, peer address) of a peer registered on another node.
+    pub(crate) async fn locate(&self, id: &str) -> Option<(SocketAddr, SocketAddr)> {
+        match self.peers.read().await.get(id) {
+            Some(x) if x.tm.elapsed().as_millis() < PEER_TIMEOUT => Some((x.node, x.addr)),
+            _ => None,
+        }
+    }
+
+    /// Returns the node a requester is waiting on, if it is not this one.
+    pub(crate) async fn origin(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        match self.origins.lock().await.get(&addr) {
+            Some((node, tm)) if tm.elapsed().as_millis() < ORIGIN_TIMEOUT => Some(*node),
+            _ => None,
+        }
+    }
+
+    pub(crate) async fn deliver(
+        &self,
+        node: SocketAddr,
+        to: SocketAddr,
+        from: SocketAddr,
+        msg: &RendezvousMessage,
+    ) -> ResultType<()> {
+        log::debug!("Deliver to {} via node {}, requested by {}", to, node, from);
+        let msg = ClusterMsg::Deliver {
+            to,
+            from,
+            msg: msg.write_to_bytes()?,
+        };
+        self.send(&msg, node).await
+    }
+
+    pub(crate) async fn reply(
+        &self,
+        node: SocketAddr,
+        to: SocketAddr,
+        msg: &RendezvousMessage,
+    ) -> ResultType<()> {
+        log::debug!("Reply to {} via node {}", to, node);
+        let msg = ClusterMsg::Reply {
+            to,
+            msg: msg.write_to_bytes()?,
+        };
+        self.send(&msg, node).await
+    }
+
+    async fn send(&self, msg: &ClusterMsg, node: SocketAddr) -> ResultType<()> {
+        let nonce = u64::from_ne_bytes(
+            sodiumoxide::randombytes::randombytes(8)
+                .try_into()
+                .unwrap_or_default(),
+        );
+        let bytes = seal(&self.key, msg, now_ms(), nonce)?;
+        let node = match (self.socket.local_addr(), node) {
+            (Ok(SocketAddr::V6(_)), SocketAddr::V4(v4)) => {
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            }
+            _ => node,
+        };
+        self.socket.send_to(&bytes, node).await?;
+        Ok(())
+    }
+
+    async fn send_all(&self, msg: &ClusterMsg) {
+        for node in self.nodes.iter() {
+            if let Err(err) = self.send(msg, *node).await {
+                log::error!("Failed to send to cluster node {}: {}", node, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let key = derive_key("secret");
+        let msg = ClusterMsg::Deliver {
+            to: "1.2.3.4:5".parse().unwrap(),
+            from: "6.7.8.9:10".parse().unwrap(),
+            msg: vec![1, 2, 3],
+        };
+        let data = seal(&key, &msg, 1_000_000, 42).unwrap();
+        match open(&key, &data, 1_000_000 + MAX_SKEW).unwrap() {
+            (ClusterMsg::Deliver { to, from, msg }, 1_000_000, 42) => {
+                assert_eq!(to, "1.2.3.4:5".parse().unwrap());
+                assert_eq!(from, "6.7.8.9:10".parse().unwrap());
+                assert_eq!(msg, vec![1, 2, 3]);
+            }
+            x => panic!("{:?}", x),
+        }
+        let peers = ClusterMsg::Peers(vec![(
+            "123456789".to_owned(),
+            "1.2.3.4:5".parse().unwrap(),
+        )]);
+        let data = seal(&key, &peers, 0, 1).unwrap();
+        assert!(matches!(
+            open(&key, &data, 0),
+            Ok((ClusterMsg::Peers(x), 0, 1)) if x.len() == 1
+        ));
+    }
+
+    #[test]
+    fn test_replay() {
+        replay();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn replay() {
+        let node = "127.0.0.1:21120".to_owned();
+        let cluster = Cluster::new(0, &[node.clone()], "secret").await.unwrap();
+        assert!(cluster.is_node(node.parse().unwrap()));
+        // same host, other port
+        assert!(!cluster.is_node("127.0.0.1:21121".parse().unwrap()));
+        let data = seal(&cluster.key, &ClusterMsg::Peers(vec![]), now_ms(), 7).unwrap();
+        assert!(cluster.accept(&data).await.is_ok());
+        assert!(cluster.accept(&data).await.is_err());
+        assert!(Cluster::new(0, &[node], "").await.is_err());
+    }
+
+    #[test]
+    fn test_reject() {
+        let key = derive_key("secret");
+        let msg = ClusterMsg::Reply {
+            to: "1.2.3.4:5".parse().unwrap(),
+            msg: vec![],
+        };
+        let data = seal(&key, &msg, 0, 1).unwrap();
+        // unsigned JSON as sent before
+        assert!(open(&key, &serde_json::to_vec(&msg).unwrap(), 0).is_err());
+        assert!(open(&key, &data[..HEADER_LEN - 1], 0).is_err());
+        assert!(open(&derive_key("other"), &data, 0).is_err());
+        assert!(open(&key, &data, MAX_SKEW + 1).is_err());
+        let mut forged = data.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(open(&key, &forged, 0).is_err());
+        // a newer timestamp under the old tag
+        let mut forged = data;
+        forged[hmacsha256::TAGBYTES + 7] ^= 1;
+        assert!(open(&key, &forged, 0).is_err());
+    }
+}
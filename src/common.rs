@@ -1,6 +1,6 @@
 use clap::App;
 use hbb_common::{
-    allow_err, anyhow::{Context, Result}, get_version_number, log, tokio, ResultType
+    allow_err, anyhow::{Context, Result}, bail, get_version_number, log, tokio, ResultType
 };
 use ini::Ini;
 use sodiumoxide::crypto::sign;
@@ -8,6 +8,7 @@ use std::{
     io::prelude::*,
     io::Read,
     net::SocketAddr,
+    path::Path,
     time::{Instant, SystemTime},
 };
 
@@ -60,27 +61,32 @@ pub fn init_args(args: &str, name: &str, about: &str) {
         .about(about)
         .args_from_usage(args)
         .get_matches();
-    if let Ok(v) = Ini::load_from_file(".env") {
-        if let Some(section) = v.section(None::<String>) {
-            section
-                .iter()
-                .for_each(|(k, v)| std::env::set_var(arg_name(k), v));
+    // a TOML config is loaded by settings
+    let config = matches
+        .value_of("config")
+        .filter(|x| !x.ends_with(".toml"))
+        .map(|x| x.to_owned());
+    // command line first, then the environment, then the config file, then
+    // .env, which is loaded after by settings::load_dotenv
+    for (k, v) in matches.args {
+        if let Some(v) = v.vals.first() {
+            std::env::set_var(arg_name(k), v.to_string_lossy().to_string());
         }
     }
-    if let Some(config) = matches.value_of("config") {
-        if let Ok(v) = Ini::load_from_file(config) {
+    if let Some(file) = config {
+        if let Ok(v) = Ini::load_from_file(file) {
             if let Some(section) = v.section(None::<String>) {
-                section
-                    .iter()
-                    .for_each(|(k, v)| std::env::set_var(arg_name(k), v));
+                section.iter().for_each(|(k, v)| {
+                    let k = arg_name(k);
+                    if std::env::var_os(&k).is_none()
+                        && std::env::var_os(k.replace('-', "_")).is_none()
+                    {
+                        std::env::set_var(k, v);
+                    }
+                });
             }
         }
     }
-    for (k, v) in matches.args {
-        if let Some(v) = v.vals.first() {
-            std::env::set_var(arg_name(k), v.to_string_lossy().to_string());
-        }
-    }
 }
 
 #[allow(dead_code)]
@@ -95,6 +101,70 @@ pub fn get_arg_or(name: &str, default: String) -> String {
     std::env::var(arg_name(name)).unwrap_or(default)
 }
 
+/// Option shared by hbbs and hbbr: hbbs passes its options through `get_arg`,
+/// hbbr reads plain environment variables, e.g. TLS_CERT for "tls-cert".
+#[allow(dead_code)]
+pub fn get_shared_arg(name: &str) -> String {
+    let v = get_arg(name);
+    if !v.is_empty() {
+        return v;
+    }
+    std::env::var(name.to_uppercase().replace('-', "_")).unwrap_or_default()
+}
+
+/// `name` in the data directory (`data-dir`, default the working directory).
+/// Absolute paths stay as they are.
+#[allow(dead_code)]
+pub fn data_path(name: &str) -> String {
+    let dir = get_shared_arg("data-dir");
+    if dir.is_empty() || name.is_empty() || Path::new(name).is_absolute() {
+        return name.to_owned();
+    }
+    Path::new(&dir).join(name).to_string_lossy().to_string()
+}
+
+/// Write a private key file, readable by the owner only. It is written to a
+/// temporary file next to `path` and renamed over it, so a crash never leaves
+/// a partly written key and a replaced key gets the owner only mode too.
+#[allow(dead_code)]
+pub fn write_key_file(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    // not a valid staged key name, see keyring
+    let tmp = format!("{}.tmp~", path);
+    std::fs::remove_file(&tmp).ok();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let res = options.open(&tmp).and_then(|mut file| {
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    });
+    if res.is_err() {
+        std::fs::remove_file(&tmp).ok();
+    }
+    res
+}
+
+/// Refuse a private key file that everyone may read.
+#[allow(dead_code)]
+pub fn check_key_file(path: &str) -> ResultType<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o004 != 0 {
+            bail!("{} is readable by everyone, run chmod 600 {}", path, path);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
 #[allow(dead_code)]
 #[inline]
 pub fn now() -> u64 {
@@ -105,21 +175,21 @@ pub fn now() -> u64 {
 }
 
 pub fn gen_sk(wait: u64) -> (String, Option<sign::SecretKey>) {
-    let sk_file = "id_ed25519";
-    if wait > 0 && !std::path::Path::new(sk_file).exists() {
+    let sk_file = data_path("id_ed25519");
+    if wait > 0 && !Path::new(&sk_file).exists() {
         std::thread::sleep(std::time::Duration::from_millis(wait));
     }
-    if let Ok(mut file) = std::fs::File::open(sk_file) {
+    if let Ok(mut file) = std::fs::File::open(&sk_file) {
+        if let Err(err) = check_key_file(&sk_file) {
+            println!("Fatal error: {err}.");
+            std::process::exit(1);
+        }
         let mut contents = String::new();
         if file.read_to_string(&mut contents).is_ok() {
-            let contents = contents.trim();
-            let sk = base64::decode(contents).unwrap_or_default();
-            if sk.len() == sign::SECRETKEYBYTES {
-                let mut tmp = [0u8; sign::SECRETKEYBYTES];
-                tmp[..].copy_from_slice(&sk);
-                let pk = base64::encode(&tmp[sign::SECRETKEYBYTES / 2..]);
+            if let Ok(sk) = crate::server_key::parse(&contents) {
+                let pk = base64::encode(&sk.0[sign::SECRETKEYBYTES / 2..]);
                 log::info!("Private key comes from {}", sk_file);
-                return (pk, Some(sign::SecretKey(tmp)));
+                return (pk, Some(sk));
             } else {
                 // don't use log here, since it is async
                 println!("Fatal error: malformed private key in {sk_file}.");
@@ -141,41 +211,47 @@ pub fn gen_sk(wait: u64) -> (String, Option<sign::SecretKey>) {
         let pub_file = format!("{sk_file}.pub");
         if let Ok(mut f) = std::fs::File::create(&pub_file) {
             f.write_all(pk.as_bytes()).ok();
-            if let Ok(mut f) = std::fs::File::create(sk_file) {
-                let s = base64::encode(&sk);
-                if f.write_all(s.as_bytes()).is_ok() {
-                    log::info!("Private/public key written to {}/{}", sk_file, pub_file);
-                    log::debug!("Public key: {}", pk);
-                    return (pk, Some(sk));
-                }
+            let s = base64::encode(&sk);
+            if write_key_file(&sk_file, s.as_bytes()).is_ok() {
+                log::info!("Private/public key written to {}/{}", sk_file, pub_file);
+                log::debug!("Public key: {}", pk);
+                return (pk, Some(sk));
             }
         }
     }
     ("".to_owned(), None)
 }
 
+/// Wait for a signal to terminate, call `on_hangup` on SIGHUP.
 #[cfg(unix)]
-pub async fn listen_signal() -> Result<()> {
+pub async fn listen_signal(on_hangup: fn()) -> Result<()> {
     use hbb_common::tokio;
     use hbb_common::tokio::signal::unix::{signal, SignalKind};
 
-    tokio::spawn(async {
-        let mut s = signal(SignalKind::terminate())?;
-        let terminate = s.recv();
-        let mut s = signal(SignalKind::interrupt())?;
-        let interrupt = s.recv();
-        let mut s = signal(SignalKind::quit())?;
-        let quit = s.recv();
-
-        tokio::select! {
-            _ = terminate => {
-                log::info!("signal terminate");
-            }
-            _ = interrupt => {
-                log::info!("signal interrupt");
-            }
-            _ = quit => {
-                log::info!("signal quit");
+    tokio::spawn(async move {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut quit = signal(SignalKind::quit())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => {
+                    log::info!("signal terminate");
+                    break;
+                }
+                _ = interrupt.recv() => {
+                    log::info!("signal interrupt");
+                    break;
+                }
+                _ = quit.recv() => {
+                    log::info!("signal quit");
+                    break;
+                }
+                _ = hangup.recv() => {
+                    log::info!("signal hangup");
+                    on_hangup();
+                }
             }
         }
         Ok(())
@@ -184,7 +260,7 @@ pub async fn listen_signal() -> Result<()> {
 }
 
 #[cfg(not(unix))]
-pub async fn listen_signal() -> Result<()> {
+pub async fn listen_signal(_on_hangup: fn()) -> Result<()> {
     let () = std::future::pending().await;
     unreachable!();
 }
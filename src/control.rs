@@ -0,0 +1,75 @@
+// JSON control API of hbbs and hbbr, served under `/api/ctl` by the admin
+// (hbbs) and status (hbbr) HTTP servers behind the admin authentication. Only
+// served when admin-token or admin-user and admin-pass are set, never with the
+// default credentials.
+//
+// POST /api/ctl {"cmd": "relay-servers", "args": ["a.example.com,b.example.com"]}
+//
+// Commands and arguments are the long names of the loopback text commands
+// (`h` lists them); the answer is a `CtlResponse`.
+use axum::{http::StatusCode, response::IntoResponse, Json};
+use hbb_common::tokio::sync::{mpsc, oneshot};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CtlRequest {
+    pub cmd: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CtlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub data: Value,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub error: String,
+}
+
+impl CtlResponse {
+    pub fn ok(data: impl Into<Value>) -> Self {
+        Self {
+            ok: true,
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn err(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: error.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn unknown(cmd: &str) -> Self {
+        Self::err(format!("unknown command: {cmd}"))
+    }
+}
+
+impl IntoResponse for CtlResponse {
+    fn into_response(self) -> axum::response::Response {
+        let status = if self.ok {
+            StatusCode::OK
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Channel to the hbbs main loop, which owns the state the commands act on.
+pub type CtlSender = mpsc::UnboundedSender<(CtlRequest, oneshot::Sender<CtlResponse>)>;
+pub type CtlReceiver = mpsc::UnboundedReceiver<(CtlRequest, oneshot::Sender<CtlResponse>)>;
+
+/// Parse an on/off argument as accepted by the text commands (Y/N) and JSON (true/false).
+pub fn parse_flag(v: &str) -> Option<bool> {
+    match v.to_lowercase().as_str() {
+        "y" | "yes" | "true" | "on" | "1" => Some(true),
+        "n" | "no" | "false" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
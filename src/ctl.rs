@@ -0,0 +1,524 @@
+// `rustdesk-utils ctl`: send control commands to a running hbbs or hbbr.
+//
+// By default the loopback text commands are used (hbbs on its NAT test port,
+// hbbr on its main port, both only from 127.0.0.1). With `--api` the
+// authenticated JSON control API is used instead, which also works remotely.
+use clap::{App, AppSettings, Arg, ErrorKind};
+use hbbs::control::{CtlRequest, CtlResponse};
+use serde_json::Value;
+use std::{
+    io::{Read, Write},
+    net::TcpStream,
+    process,
+    time::Duration,
+};
+
+const EXIT_USAGE: i32 = 1;
+const EXIT_CONNECTION: i32 = 2;
+const EXIT_SERVER: i32 = 3;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Verb {
+    server: &'static str,
+    name: &'static str,
+    short: &'static str,
+    args: &'static str,
+    help: &'static str,
+    // number of required arguments
+    required: usize,
+}
+
+const fn verb(
+    server: &'static str,
+    name: &'static str,
+    short: &'static str,
+    args: &'static str,
+    required: usize,
+    help: &'static str,
+) -> Verb {
+    Verb {
+        server,
+        name,
+        short,
+        args,
+        help,
+        required,
+    }
+}
+
+#[rustfmt::skip]
+const VERBS: &[Verb] = &[
+    verb("hbbs", "relay-servers", "rs", "[<server,...>]", 0, "Show or set the relay servers"),
+    verb("hbbs", "relay-load", "rl", "", 0, "Show the load reported by the relay servers"),
+    verb("hbbs", "reload-geo", "rg", "", 0, "Reload the GeoIP database and relay metadata"),
+    verb("hbbs", "ip-blocker", "ib", "[<ip>|<offset>] [-]", 0, "Show or remove (-) rate limited IPs"),
+    verb("hbbs", "ip-changes", "ic", "[<id>|<offset>] [-]", 0, "Show or remove (-) IP changes of IDs"),
+    verb("hbbs", "always-use-relay", "aur", "[Y|N]", 0, "Show or set forced relaying"),
+    verb("hbbs", "test-geo", "tg", "<ip1> [<ip2>]", 1, "Show the relay selected for two peers"),
+    verb("hbbs", "policy", "p", "[<ip> -]", 0, "Show rate limit statistics or unblock an IP"),
+    verb("hbbs", "deny-add", "da", "<ip|cidr...> [duration] [reason]", 1, "Add IPs to the deny list"),
+    verb("hbbs", "deny-remove", "dr", "<ip|ip...|all>", 1, "Remove IPs from the deny list"),
+    verb("hbbs", "deny", "d", "[<ip>]", 0, "Show the deny list or check an IP"),
+    verb("hbbs", "allow-add", "aa", "<ip|cidr...> [duration] [reason]", 1, "Add IPs to the allow list"),
+    verb("hbbs", "allow-remove", "ar", "<ip|ip...|all>", 1, "Remove IPs from the allow list"),
+    verb("hbbs", "allow", "a", "[<ip>]", 0, "Show the allow list or check an IP"),
+    verb("hbbr", "blacklist-add", "ba", "<ip|cidr...> [duration] [reason]", 1, "Add IPs to the speed limited blacklist"),
+    verb("hbbr", "blacklist-remove", "br", "<ip|ip...|all>", 1, "Remove IPs from the blacklist"),
+    verb("hbbr", "blacklist", "b", "[<ip>]", 0, "Show the blacklist or check an IP"),
+    verb("hbbr", "blocklist-add", "Ba", "<ip|cidr...> [duration] [reason]", 1, "Add IPs to the blocklist"),
+    verb("hbbr", "blocklist-remove", "Br", "<ip|ip...|all>", 1, "Remove IPs from the blocklist"),
+    verb("hbbr", "blocklist", "B", "[<ip>]", 0, "Show the blocklist or check an IP"),
+    verb("hbbr", "downgrade-threshold", "dt", "[<value>]", 0, "Show or set the downgrade threshold"),
+    verb("hbbr", "downgrade-start-check", "t", "[<seconds>]", 0, "Show or set when downgrade checks start"),
+    verb("hbbr", "limit-speed", "ls", "[<Mb/s>]", 0, "Show or set the speed of blacklisted IPs"),
+    verb("hbbr", "total-bandwidth", "tb", "[<Mb/s>]", 0, "Show or set the total bandwidth"),
+    verb("hbbr", "single-bandwidth", "sb", "[<Mb/s>]", 0, "Show or set the bandwidth per connection"),
+    verb("hbbr", "usage", "u", "", 0, "Show the usage of active relays"),
+];
+
+fn verbs_help() -> String {
+    let mut res = "COMMANDS:\n".to_owned();
+    for server in ["hbbs", "hbbr"] {
+        res += &format!("    {server}:\n");
+        for v in VERBS.iter().filter(|v| v.server == server) {
+            let usage = format!("{}({}) {}", v.name, v.short, v.args);
+            res += &format!("        {:<50} {}\n", usage, v.help);
+        }
+    }
+    res
+}
+
+/// Why ctl stops without anything (more) to print.
+#[derive(Debug, PartialEq)]
+enum Exit {
+    /// --help or --version, printed to stdout with status 0
+    Help(String),
+    /// rejected by clap, whose message is printed as is
+    Usage(String),
+    /// printed as "ERROR: <msg>" with the given status
+    Error(String, i32),
+}
+
+impl Exit {
+    fn exit(self) -> ! {
+        match self {
+            Exit::Help(msg) => {
+                println!("{msg}");
+                process::exit(0);
+            }
+            Exit::Usage(msg) => {
+                eprintln!("{msg}");
+                process::exit(EXIT_USAGE);
+            }
+            Exit::Error(msg, code) => {
+                eprintln!("ERROR: {msg}");
+                process::exit(code);
+            }
+        }
+    }
+}
+
+struct Invocation {
+    verb: &'static Verb,
+    args: Vec<String>,
+    port: u16,
+    json: bool,
+    // the host and credentials of the JSON API, None for the loopback commands
+    api: Option<(String, Auth)>,
+}
+
+pub fn run(args: &[String]) {
+    let ctl = parse(args).unwrap_or_else(|exit| exit.exit());
+    if let Some((host, auth)) = &ctl.api {
+        let req = CtlRequest {
+            cmd: ctl.verb.name.to_owned(),
+            args: ctl.args.clone(),
+        };
+        let res = call_api(host, ctl.port, auth, &req)
+            .unwrap_or_else(|err| Exit::Error(err, EXIT_CONNECTION).exit());
+        print!("{}", render_response(&res, ctl.json));
+        if !res.ok {
+            Exit::Error(res.error, EXIT_SERVER).exit();
+        }
+    } else {
+        let mut cmd = ctl.verb.short.to_owned();
+        for arg in ctl.args.iter() {
+            cmd.push(' ');
+            cmd.push_str(arg);
+        }
+        let res = call_loopback(ctl.port, &cmd)
+            .unwrap_or_else(|err| Exit::Error(err, EXIT_CONNECTION).exit());
+        print!("{}", render_loopback(&res, ctl.json));
+    }
+}
+
+fn parse(args: &[String]) -> Result<Invocation, Exit> {
+    let help = verbs_help();
+    let matches = App::new("rustdesk-utils ctl")
+        .about("Send a control command to a running hbbs or hbbr")
+        .setting(AppSettings::TrailingVarArg)
+        .after_help(help.as_str())
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .takes_value(true)
+                .help(
+                    "Sets the port (default: 21115 for hbbs, 21117 for hbbr, 100 more with --api)",
+                ),
+        )
+        .arg(
+            Arg::with_name("api")
+                .long("api")
+                .takes_value(true)
+                .value_name("HOST")
+                .help(
+                    "Uses the authenticated JSON API on HOST instead of the loopback text commands",
+                ),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .takes_value(true)
+                .help("Sets the API user (default: ADMIN_USER)"),
+        )
+        .arg(
+            Arg::with_name("pass")
+                .long("pass")
+                .takes_value(true)
+                .help("Sets the API password (default: ADMIN_PASS)"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .takes_value(true)
+                .help("Sets the API bearer token (default: ADMIN_TOKEN)"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["table", "json"])
+                .default_value("table")
+                .help("Sets the output format"),
+        )
+        .arg(
+            Arg::with_name("server")
+                .required(true)
+                .possible_values(&["hbbs", "hbbr"]),
+        )
+        .arg(Arg::with_name("command").required(true))
+        .arg(Arg::with_name("args").multiple(true))
+        .get_matches_from_safe(args);
+    let matches = match matches {
+        Ok(m) => m,
+        Err(err) => {
+            return Err(match err.kind {
+                ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => Exit::Help(err.message),
+                _ => Exit::Usage(err.message),
+            })
+        }
+    };
+    let server = matches.value_of("server").unwrap_or_default();
+    let command = matches.value_of("command").unwrap_or_default();
+    let args: Vec<String> = matches
+        .values_of("args")
+        .map(|x| x.map(|x| x.to_owned()).collect())
+        .unwrap_or_default();
+    let Some(verb) = VERBS
+        .iter()
+        .find(|v| v.server == server && (v.name == command || v.short == command))
+    else {
+        return Err(Exit::Error(
+            format!("unknown {server} command: {command}\n\n{help}"),
+            EXIT_USAGE,
+        ));
+    };
+    if args.len() < verb.required {
+        return Err(Exit::Error(
+            format!("usage: {} {} {}", server, verb.name, verb.args),
+            EXIT_USAGE,
+        ));
+    }
+    let json = matches.value_of("format") == Some("json");
+    let port = match matches.value_of("port") {
+        Some(p) => p
+            .parse::<u16>()
+            .map_err(|_| Exit::Error(format!("invalid port: {p}"), EXIT_USAGE))?,
+        None => {
+            // the admin/status servers listen on the main port + 100
+            match (server, matches.is_present("api")) {
+                ("hbbs", false) => 21115,
+                ("hbbs", true) => 21216,
+                (_, false) => 21117,
+                (_, true) => 21217,
+            }
+        }
+    };
+    let api = matches.value_of("api").map(|host| {
+        let env = |name: &str| std::env::var(name).unwrap_or_default();
+        let auth = Auth {
+            user: matches
+                .value_of("user")
+                .map(|x| x.to_owned())
+                .unwrap_or_else(|| env("ADMIN_USER")),
+            pass: matches
+                .value_of("pass")
+                .map(|x| x.to_owned())
+                .unwrap_or_else(|| env("ADMIN_PASS")),
+            token: matches
+                .value_of("token")
+                .map(|x| x.to_owned())
+                .unwrap_or_else(|| env("ADMIN_TOKEN")),
+        };
+        (host.to_owned(), auth)
+    });
+    Ok(Invocation {
+        verb,
+        args,
+        port,
+        json,
+        api,
+    })
+}
+
+fn render_response(res: &CtlResponse, json: bool) -> String {
+    if json {
+        serde_json::to_string_pretty(res).unwrap_or_default() + "\n"
+    } else if res.ok {
+        table(&res.data)
+    } else {
+        String::new()
+    }
+}
+
+fn render_loopback(res: &str, json: bool) -> String {
+    if json {
+        let lines: Vec<&str> = res.lines().collect();
+        serde_json::to_string_pretty(&CtlResponse::ok(lines)).unwrap_or_default() + "\n"
+    } else {
+        res.to_owned()
+    }
+}
+
+struct Auth {
+    user: String,
+    pass: String,
+    token: String,
+}
+
+fn call_loopback(port: u16, cmd: &str) -> Result<String, String> {
+    let addr = format!("127.0.0.1:{port}");
+    let mut stream = TcpStream::connect_timeout(&addr.parse().unwrap(), TIMEOUT)
+        .map_err(|err| format!("failed to connect to {addr}: {err}"))?;
+    stream.set_read_timeout(Some(TIMEOUT)).ok();
+    stream
+        .write_all(cmd.as_bytes())
+        .map_err(|err| format!("failed to send to {addr}: {err}"))?;
+    // the server answers and closes the connection
+    let mut res = String::new();
+    stream
+        .read_to_string(&mut res)
+        .map_err(|err| format!("failed to read from {addr}: {err}"))?;
+    Ok(res)
+}
+
+fn call_api(host: &str, port: u16, auth: &Auth, req: &CtlRequest) -> Result<CtlResponse, String> {
+    let base = if host.starts_with("http://") || host.starts_with("https://") {
+        host.trim_end_matches('/').to_owned()
+    } else {
+        format!("http://{host}:{port}")
+    };
+    let url = format!("{base}/api/ctl");
+    let client = reqwest::blocking::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .map_err(|err| err.to_string())?;
+    let mut builder = client.post(&url).json(req);
+    builder = if !auth.token.is_empty() {
+        builder.bearer_auth(&auth.token)
+    } else {
+        builder.basic_auth(&auth.user, Some(&auth.pass))
+    };
+    let res = builder
+        .send()
+        .map_err(|err| format!("failed to call {url}: {err}"))?;
+    let status = res.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        return Ok(CtlResponse::err("unauthorized"));
+    }
+    res.json::<CtlResponse>()
+        .map_err(|err| format!("invalid response from {url} ({status}): {err}"))
+}
+
+fn cell(v: &Value) -> String {
+    match v {
+        Value::Null => "-".to_owned(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn table(v: &Value) -> String {
+    let mut res = String::new();
+    match v {
+        Value::Null => {}
+        Value::Array(rows) if rows.iter().all(|x| x.is_object()) && !rows.is_empty() => {
+            let mut columns: Vec<&String> = vec![];
+            for row in rows.iter().filter_map(|x| x.as_object()) {
+                for k in row.keys() {
+                    if !columns.contains(&k) {
+                        columns.push(k);
+                    }
+                }
+            }
+            let cells: Vec<Vec<String>> = rows
+                .iter()
+                .map(|row| columns.iter().map(|c| cell(&row[c.as_str()])).collect())
+                .collect();
+            let widths: Vec<usize> = columns
+                .iter()
+                .enumerate()
+                .map(|(i, c)| cells.iter().map(|r| r[i].len()).fold(c.len(), usize::max))
+                .collect();
+            let mut line = |values: Vec<&str>| {
+                let v: Vec<String> = values
+                    .iter()
+                    .zip(widths.iter())
+                    .map(|(v, &w)| format!("{v:<w$}"))
+                    .collect();
+                res += v.join("  ").trim_end();
+                res.push('\n');
+            };
+            let header: Vec<String> = columns.iter().map(|c| c.to_uppercase()).collect();
+            line(header.iter().map(|x| x.as_str()).collect());
+            for row in cells.iter() {
+                line(row.iter().map(|x| x.as_str()).collect());
+            }
+        }
+        Value::Array(items) => {
+            for x in items {
+                res += &format!("{}\n", cell(x));
+            }
+        }
+        Value::Object(map) => {
+            let width = map.keys().map(|k| k.len()).max().unwrap_or(0);
+            for (k, v) in map {
+                res += &format!("{k:<width$}  {}\n", cell(v));
+            }
+        }
+        v => res += &format!("{}\n", cell(v)),
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse_args(args: &str) -> Result<Invocation, Exit> {
+        let args: Vec<String> = args.split_whitespace().map(|x| x.to_owned()).collect();
+        parse(&args)
+    }
+
+    fn parse_err(args: &str) -> Exit {
+        match parse_args(args) {
+            Ok(_) => panic!("{args} should not parse"),
+            Err(exit) => exit,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let ctl = parse_args("ctl hbbs rs a,b").unwrap();
+        assert_eq!(ctl.verb.name, "relay-servers");
+        assert_eq!(ctl.args, vec!["a,b"]);
+        assert_eq!(ctl.port, 21115);
+        assert!(!ctl.json && ctl.api.is_none());
+        let ctl = parse_args("ctl -f json hbbr blacklist-add 1.2.3.4 1h spam").unwrap();
+        assert_eq!(ctl.verb.short, "ba");
+        assert_eq!(ctl.args, vec!["1.2.3.4", "1h", "spam"]);
+        assert_eq!(ctl.port, 21117);
+        assert!(ctl.json);
+        // the same verb name on the other server is not accepted
+        assert!(parse_args("ctl hbbs blacklist").is_err());
+        let ctl = parse_args("ctl --api example.com --token t hbbs policy").unwrap();
+        assert_eq!(ctl.port, 21216);
+        let (host, auth) = ctl.api.unwrap();
+        assert_eq!(host, "example.com");
+        assert_eq!(auth.token, "t");
+        assert_eq!(parse_args("ctl --api h hbbr u").unwrap().port, 21217);
+        assert_eq!(parse_args("ctl -p 1234 --api h hbbr u").unwrap().port, 1234);
+    }
+
+    #[test]
+    fn test_exit() {
+        assert!(matches!(parse_err("ctl --help"), Exit::Help(msg) if msg.contains("COMMANDS:")));
+        assert!(matches!(parse_err("ctl"), Exit::Usage(_)));
+        assert!(matches!(parse_err("ctl hbbx rs"), Exit::Usage(_)));
+        assert!(matches!(parse_err("ctl -f xml hbbs rs"), Exit::Usage(_)));
+        assert!(matches!(
+            parse_err("ctl hbbs nope"),
+            Exit::Error(msg, EXIT_USAGE) if msg.starts_with("unknown hbbs command: nope")
+        ));
+        assert_eq!(
+            parse_err("ctl hbbs tg"),
+            Exit::Error("usage: hbbs test-geo <ip1> [<ip2>]".to_owned(), EXIT_USAGE)
+        );
+        assert_eq!(
+            parse_err("ctl -p 70000 hbbs rs"),
+            Exit::Error("invalid port: 70000".to_owned(), EXIT_USAGE)
+        );
+    }
+
+    #[test]
+    fn test_cell() {
+        assert_eq!(cell(&Value::Null), "-");
+        assert_eq!(cell(&json!("a b")), "a b");
+        assert_eq!(cell(&json!(12)), "12");
+        assert_eq!(cell(&json!(true)), "true");
+        assert_eq!(cell(&json!([1, "x"])), r#"[1,"x"]"#);
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(table(&Value::Null), "");
+        assert_eq!(table(&json!("done")), "done\n");
+        assert_eq!(table(&json!(["a", 1, null])), "a\n1\n-\n");
+        assert_eq!(table(&json!([])), "");
+        assert_eq!(
+            table(&json!({"ip": "1.2.3.4", "count": 3})),
+            "count  3\nip     1.2.3.4\n"
+        );
+        // columns are the union of all keys, in order of first appearance
+        assert_eq!(
+            table(&json!([
+                {"ip": "10.0.0.1", "hits": 12},
+                {"ip": "1.2.3.4", "reason": "spam"},
+            ])),
+            "HITS  IP        REASON\n\
+             12    10.0.0.1  -\n\
+             -     1.2.3.4   spam\n"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let res = CtlResponse::ok(json!(["x"]));
+        assert_eq!(render_response(&res, false), "x\n");
+        assert_eq!(
+            render_response(&res, true),
+            "{\n  \"ok\": true,\n  \"data\": [\n    \"x\"\n  ]\n}\n"
+        );
+        // the error itself is reported when exiting
+        let res = CtlResponse::err("bad");
+        assert_eq!(render_response(&res, false), "");
+        assert!(render_response(&res, true).contains("\"error\": \"bad\""));
+        assert_eq!(render_loopback("a\nb\n", false), "a\nb\n");
+        let res: CtlResponse = serde_json::from_str(&render_loopback("a\nb\n", true)).unwrap();
+        assert!(res.ok);
+        assert_eq!(res.data, json!(["a", "b"]));
+    }
+}
@@ -63,7 +63,9 @@ impl CustomKeyManager {
                         Ok(expired) => {
                             let expired_utc = expired.with_timezone(&Utc);
                             if expired_utc > now {
-                                key_map.insert(custom_key.key, expired_utc);
+                                if let Some(key) = canonical_key(&custom_key.key) {
+                                    key_map.insert(key, expired_utc);
+                                }
                             }
                         }
                         Err(_) => {
@@ -126,10 +128,11 @@ impl CustomKeyManager {
                                 Ok(expired) => {
                                     let expired_utc = expired.with_timezone(&Utc);
                                     if expired_utc > now {
-                        let key = custom_key.key.clone();
-                        let expired = custom_key.expired.clone();
-                        key_map.insert(custom_key.key, expired_utc);
-                        hbb_common::log::info!("Loaded custom key: {} (expires: {})", key, expired);
+                        let Some(key) = canonical_key(&custom_key.key) else {
+                            continue;
+                        };
+                        hbb_common::log::info!("Loaded custom key: {} (expires: {})", key, custom_key.expired);
+                        key_map.insert(key, expired_utc);
                                     } else {
                                         hbb_common::log::warn!("Custom key {} has expired: {}", custom_key.key, custom_key.expired);
                                     }
@@ -204,7 +207,9 @@ impl CustomKeyManager {
                                 Ok(expired) => {
                                     let expired_utc = expired.with_timezone(&Utc);
                                     if expired_utc > now {
-                                        key_map.insert(custom_key.key, expired_utc);
+                                        if let Some(key) = canonical_key(&custom_key.key) {
+                                            key_map.insert(key, expired_utc);
+                                        }
                                     }
                                 }
                                 Err(e) => {
@@ -229,8 +234,11 @@ impl CustomKeyManager {
     }
 
     pub async fn is_valid_key(&self, key: &str) -> bool {
+        let Some(key) = lookup_key(key) else {
+            return false;
+        };
         let keys = self.keys.read().await;
-        if let Some(expired) = keys.get(key) {
+        if let Some(expired) = keys.get(&key) {
             let now = Utc::now();
             if *expired > now {
                 return true;
@@ -261,6 +269,25 @@ impl CustomKeyManager {
     }
 }
 
+// The file accepts keys in any format, as before key-formats: keys in an
+// accepted format are stored in canonical form, the others as they are.
+fn lookup_key(key: &str) -> Option<String> {
+    let key = key.trim();
+    if key.is_empty() {
+        return None;
+    }
+    Some(crate::key_format::stored(key))
+}
+
+// The key of a file entry, None with a warning if it is empty.
+fn canonical_key(key: &str) -> Option<String> {
+    let res = lookup_key(key);
+    if res.is_none() {
+        hbb_common::log::warn!("Skipped empty custom key");
+    }
+    res
+}
+
 impl Clone for CustomKeyManager {
     fn clone(&self) -> Self {
         Self {
@@ -287,25 +314,32 @@ mod tests {
             keys: vec![
                 CustomKey {
                     key: "test123".to_string(),
-                    expired: "2025-12-31T23:59:59Z".to_string(),
+                    expired: "2099-12-31T23:59:59Z".to_string(),
                 },
                 CustomKey {
                     key: "expired123".to_string(),
                     expired: "2020-01-01T00:00:00Z".to_string(),
                 },
+                CustomKey {
+                    key: "0123456789ABCDEF0123456789ABCDEF".to_string(),
+                    expired: "2099-12-31T23:59:59Z".to_string(),
+                },
             ],
         };
         
         let content = serde_json::to_string_pretty(&config).unwrap();
         fs::write(config_path, content).unwrap();
         
-        let manager = CustomKeyManager::new(config_path);
+        let manager = CustomKeyManager::new(config_path).await;
         
         // Test valid key
         assert!(manager.is_valid_key("test123").await);
         
         // Test expired key
         assert!(!manager.is_valid_key("expired123").await);
+
+        // Keys in an accepted format match in any case
+        assert!(manager.is_valid_key("0123456789abcdef0123456789abcdef").await);
         
         // Test non-existent key
         assert!(!manager.is_valid_key("nonexistent").await);
@@ -48,13 +48,33 @@ pub struct Peer {
 }
 
 impl Database {
+    /// `db-url`, else db_v2.sqlite3 in the data directory.
+    pub fn default_url() -> String {
+        crate::settings::get().db_url.clone().unwrap_or_else(|| {
+            let mut db = "db_v2.sqlite3".to_owned();
+            if !crate::common::get_shared_arg("data-dir").is_empty() {
+                return crate::common::data_path(&db);
+            }
+            #[cfg(all(windows, not(debug_assertions)))]
+            {
+                if let Some(path) = hbb_common::config::Config::icon_path().parent() {
+                    db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
+                }
+            }
+            #[cfg(not(windows))]
+            {
+                db = format!("./{db}");
+            }
+            db
+        })
+    }
+
     pub async fn new(url: &str) -> ResultType<Database> {
         if !std::path::Path::new(url).exists() {
             std::fs::File::create(url).ok();
         }
-        let n: usize = std::env::var("MAX_DATABASE_CONNECTIONS")
-            .unwrap_or_else(|_| "1".to_owned())
-            .parse()
+        let n = crate::settings::get()
+            .max_database_connections
             .unwrap_or(1);
         log::debug!("MAX_DATABASE_CONNECTIONS={}", n);
         let pool = Pool::new(
@@ -113,6 +133,14 @@ impl Database {
             "create index if not exists index_licence_keys_active on licence_keys (active);",
             "create index if not exists index_licence_keys_expired_at on licence_keys (expired_at);",
             "create index if not exists index_bindings_key on licence_key_bindings (licence_key);",
+            // hex keys are looked up in lower case, see key_format
+            // 'or ignore' keeps a key as it is if its lower case form exists too
+            "update or ignore licence_keys set licence_key = lower(licence_key)
+                where length(licence_key) = 32 and licence_key glob '*[A-F]*'
+                and licence_key not glob '*[^0-9A-Fa-f]*';",
+            "update or ignore licence_key_bindings set licence_key = lower(licence_key)
+                where length(licence_key) = 32 and licence_key glob '*[A-F]*'
+                and licence_key not glob '*[^0-9A-Fa-f]*';",
         ] {
             let _ = sqlx::query(stmt)
                 .execute(self.pool.get().await?.deref_mut())
@@ -121,6 +149,17 @@ impl Database {
         Ok(())
     }
 
+    /// Wait for the queries in progress, e.g. before exiting.
+    pub async fn flush(&self) {
+        loop {
+            let status = self.pool.status();
+            if status.available as i64 >= status.size as i64 {
+                break;
+            }
+            hbb_common::sleep(0.05).await;
+        }
+    }
+
     pub async fn get_peer(&self, id: &str) -> ResultType<Option<Peer>> {
         let row = sqlx::query(
             "select guid, id, uuid, pk, user, status, info from peer where id = ?",
@@ -208,6 +247,34 @@ impl Database {
         Ok(())
     }
 
+    /// Insert keys and their bindings, all or none.
+    pub async fn insert_keys(&self, keys: &[NewKey]) -> ResultType<()> {
+        let now = chrono::Utc::now().timestamp();
+        let mut conn = self.pool.get().await?;
+        let mut tx = conn.begin().await?;
+        for k in keys {
+            sqlx::query("insert into licence_keys(licence_key, registered_at, expired_at, active, note, max_bind_ids) values(?, ?, ?, ?, ?, ?)")
+            .bind(&k.key)
+            .bind(now)
+            .bind(k.expired_at)
+            .bind(if k.active { 1 } else { 0 })
+            .bind(&k.note)
+            .bind(k.max_bind_ids)
+            .execute(&mut *tx)
+            .await?;
+            for peer_id in k.bindings.iter() {
+                sqlx::query("insert or ignore into licence_key_bindings(licence_key, peer_id, bound_at) values(?, ?, ?)")
+                .bind(&k.key)
+                .bind(peer_id)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
     pub async fn extend_key_by(&self, key: &str, seconds: i64) -> ResultType<()> {
         // if not exists, no-op
         sqlx::query("update licence_keys set expired_at = expired_at + ? where licence_key = ?")
@@ -292,6 +359,63 @@ impl Database {
         Ok(())
     }
 
+    pub async fn list_all_keys(&self) -> ResultType<Vec<LicenceKey>> {
+        let rows = sqlx::query(
+            "select licence_key, registered_at, expired_at, active, note, max_bind_ids from licence_keys",
+        )
+        .fetch_all(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                let licence_key: String = r.try_get("licence_key").unwrap_or_default();
+                let registered_at: i64 = r.try_get("registered_at").unwrap_or_default();
+                let expired_at: i64 = r.try_get("expired_at").unwrap_or_default();
+                let active: i64 = r.try_get("active").unwrap_or_default();
+                let note: Option<String> = r.try_get("note").ok();
+                let max_bind_ids: i64 = r.try_get("max_bind_ids").unwrap_or(3);
+                LicenceKey { licence_key, registered_at, expired_at, active, note, max_bind_ids }
+            })
+            .collect())
+    }
+
+    // Returns (licence_key, peer_id) pairs, optionally restricted to one key
+    pub async fn list_bindings(&self, key: Option<&str>) -> ResultType<Vec<(String, String)>> {
+        let rows = match key {
+            Some(key) => {
+                sqlx::query("select licence_key, peer_id from licence_key_bindings where licence_key = ?")
+                    .bind(key)
+                    .fetch_all(self.pool.get().await?.deref_mut())
+                    .await?
+            }
+            None => {
+                sqlx::query("select licence_key, peer_id from licence_key_bindings")
+                    .fetch_all(self.pool.get().await?.deref_mut())
+                    .await?
+            }
+        };
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                (
+                    r.try_get::<String, _>("licence_key").unwrap_or_default(),
+                    r.try_get::<String, _>("peer_id").unwrap_or_default(),
+                )
+            })
+            .collect())
+    }
+
+    pub async fn insert_binding(&self, key: &str, peer_id: &str) -> ResultType<()> {
+        let now = chrono::Utc::now().timestamp();
+        sqlx::query("insert or ignore into licence_key_bindings(licence_key, peer_id, bound_at) values(?, ?, ?)")
+        .bind(key)
+        .bind(peer_id)
+        .bind(now)
+        .execute(self.pool.get().await?.deref_mut())
+        .await?;
+        Ok(())
+    }
+
     pub async fn ensure_binding_allowed(&self, key: &str, peer_id: &str) -> ResultType<bool> {
         // If already bound, allow
         let exists = sqlx::query("select 1 as x from licence_key_bindings where licence_key = ? and peer_id = ? limit 1")
@@ -391,6 +515,17 @@ pub struct LicenceKey {
     pub max_bind_ids: i64,
 }
 
+/// A key to insert with `insert_keys`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NewKey {
+    pub key: String,
+    pub expired_at: i64,
+    pub active: bool,
+    pub note: Option<String>,
+    pub max_bind_ids: i64,
+    pub bindings: Vec<String>,
+}
+
 #[cfg(test)]
 mod tests {
     use hbb_common::tokio;
@@ -425,4 +560,48 @@ mod tests {
         }
         hbb_common::futures::future::join_all(jobs).await;
     }
+
+    #[test]
+    fn test_lower_hex_keys() {
+        lower_hex_keys();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn lower_hex_keys() {
+        let path = std::env::temp_dir().join(format!("lower_hex_{}.sqlite3", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let path = path.to_str().unwrap();
+        let upper = "0123456789ABCDEF0123456789ABCDEF";
+        let lower = upper.to_lowercase();
+        let db = super::Database::new(path).await.unwrap();
+        // as stored before hex keys were lowered
+        db.insert_keys(&[super::NewKey {
+            key: upper.to_owned(),
+            expired_at: i64::MAX / 2,
+            active: true,
+            max_bind_ids: 3,
+            bindings: vec!["123456789".to_owned()],
+            ..Default::default()
+        }])
+        .await
+        .unwrap();
+        db.insert_keys(&[super::NewKey {
+            key: "not-hex-ABC".to_owned(),
+            ..Default::default()
+        }])
+        .await
+        .unwrap();
+        let db = super::Database::new(path).await.unwrap();
+        assert_eq!(crate::key_format::stored(upper), lower);
+        assert!(db.get_key(upper).await.unwrap().is_none());
+        assert!(db.get_key(&lower).await.unwrap().is_some());
+        assert!(db.key_exists("not-hex-ABC").await.unwrap());
+        assert_eq!(
+            db.list_bindings(Some(&lower)).await.unwrap(),
+            vec![(lower.clone(), "123456789".to_owned())]
+        );
+        let allowed = db.ensure_binding_allowed(&lower, "123456789").await;
+        assert!(allowed.unwrap());
+        std::fs::remove_file(path).ok();
+    }
 }
@@ -0,0 +1,393 @@
+// Relay selection by location.
+//
+// Peer and relay positions come from a local GeoIP database (MaxMind MMDB,
+// e.g. GeoLite2-City.mmdb). Relays can be described further in a JSON file:
+//
+// { "relays": [ { "host": "relay-eu.example.com", "region": "EU", "capacity": 200 },
+//               { "host": "10.0.0.5", "lat": 31.23, "lon": 121.47 } ] }
+//
+// `region` is matched against the continent or country code of the peers,
+// `capacity` is a relative weight (default 100), `lat`/`lon` override the
+// position found in the database, e.g. for relays with private addresses.
+//
+// Relay host names are resolved in the background, never while selecting:
+// a relay is left out until it is located, and one that could not be located
+// is tried again after a while.
+use hbb_common::{bail, log, ResultType};
+use maxminddb::{geoip2, Reader};
+use serde_derive::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    net::{IpAddr, ToSocketAddrs},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_CAPACITY: u32 = 100;
+// cost factor for each peer located in the relay's region
+const REGION_FACTOR: f64 = 0.75;
+const EARTH_RADIUS_KM: f64 = 6371.;
+// how long before a relay that is not located is resolved again
+const RESOLVE_RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Location {
+    lat: f64,
+    lon: f64,
+    continent: String,
+    country: String,
+}
+
+fn default_capacity() -> u32 {
+    DEFAULT_CAPACITY
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct RelayMeta {
+    pub host: String,
+    #[serde(default)]
+    pub region: String,
+    #[serde(default = "default_capacity")]
+    pub capacity: u32,
+    #[serde(default)]
+    pub lat: Option<f64>,
+    #[serde(default)]
+    pub lon: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RelayMetaFile {
+    relays: Vec<RelayMeta>,
+}
+
+// the position of a relay, None while being resolved or if it failed
+struct Resolved {
+    location: Option<Location>,
+    tm: Instant,
+}
+
+impl Resolved {
+    fn is_due(&self, now: Instant) -> bool {
+        self.location.is_none() && now.saturating_duration_since(self.tm) >= RESOLVE_RETRY
+    }
+}
+
+#[derive(Default)]
+struct Geo {
+    reader: Option<Reader<Vec<u8>>>,
+    // relay metadata, by host without port
+    meta: HashMap<String, RelayMeta>,
+    // relay positions, by relay server as configured
+    relays: HashMap<String, Resolved>,
+    // bumped on reload, so that resolutions started before are dropped
+    generation: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref GEO: RwLock<Geo> = Default::default();
+}
+
+/// (Re)load the GeoIP database and the relay metadata file.
+/// Missing files disable the corresponding part; a malformed file is an error.
+pub(crate) fn reload(db_file: &str, meta_file: &str) -> ResultType<String> {
+    let reader = if !db_file.is_empty() && std::path::Path::new(db_file).exists() {
+        match Reader::open_readfile(db_file) {
+            Ok(reader) => Some(reader),
+            Err(err) => bail!("Failed to open geo database {}: {}", db_file, err),
+        }
+    } else {
+        None
+    };
+    let mut meta = HashMap::new();
+    if !meta_file.is_empty() && std::path::Path::new(meta_file).exists() {
+        let content = std::fs::read_to_string(meta_file)?;
+        let file: RelayMetaFile = serde_json::from_str(&content)?;
+        for m in file.relays {
+            meta.insert(host_of(&m.host).to_owned(), m);
+        }
+    }
+    let res = format!(
+        "geo database: {}, relay metadata: {}",
+        if reader.is_some() { db_file } else { "none" },
+        meta.len()
+    );
+    log::info!("{}", res);
+    let mut geo = GEO.write().unwrap();
+    geo.reader = reader;
+    geo.meta = meta;
+    geo.relays.clear();
+    geo.generation += 1;
+    Ok(res)
+}
+
+#[inline]
+pub(crate) fn is_enabled() -> bool {
+    GEO.read().unwrap().reader.is_some()
+}
+
+// host part of "host", "host:port", "[v6]:port" or "v6"
+fn host_of(server: &str) -> &str {
+    if let Some(rest) = server.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match server.rsplit_once(':') {
+        Some((host, _)) if !host.contains(':') => host,
+        _ => server,
+    }
+}
+
+fn lookup(reader: &Reader<Vec<u8>>, ip: IpAddr) -> Option<Location> {
+    let city: geoip2::City = reader.lookup(ip).ok()?;
+    let location = city.location?;
+    Some(Location {
+        lat: location.latitude?,
+        lon: location.longitude?,
+        continent: city
+            .continent
+            .and_then(|x| x.code)
+            .unwrap_or_default()
+            .to_owned(),
+        country: city
+            .country
+            .and_then(|x| x.iso_code)
+            .unwrap_or_default()
+            .to_owned(),
+    })
+}
+
+// the position given in the relay metadata
+fn fixed_location(geo: &Geo, relay: &str) -> Option<Location> {
+    match geo.meta.get(host_of(relay)) {
+        Some(RelayMeta {
+            lat: Some(lat),
+            lon: Some(lon),
+            ..
+        }) => Some(Location {
+            lat: *lat,
+            lon: *lon,
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+// blocking DNS lookup, call without holding GEO
+fn resolve_host(relay: &str) -> Option<IpAddr> {
+    let host = host_of(relay);
+    let addr = if host.contains(':') {
+        format!("[{host}]:0")
+    } else {
+        format!("{host}:0")
+    };
+    Some(addr.to_socket_addrs().ok()?.next()?.ip())
+}
+
+// Locate `relays` one by one, on a thread of its own.
+fn resolve(relays: Vec<String>, generation: u64) {
+    for relay in relays {
+        let fixed = fixed_location(&GEO.read().unwrap(), &relay);
+        let location = fixed.or_else(|| {
+            let ip = resolve_host(&relay)?;
+            lookup(GEO.read().unwrap().reader.as_ref()?, ip)
+        });
+        if location.is_none() {
+            log::warn!(
+                "Relay {} not located, retry in {}s",
+                relay,
+                RESOLVE_RETRY.as_secs()
+            );
+        }
+        let mut geo = GEO.write().unwrap();
+        if geo.generation != generation {
+            return;
+        }
+        geo.relays.insert(
+            relay,
+            Resolved {
+                location,
+                tm: Instant::now(),
+            },
+        );
+    }
+}
+
+// Start resolving the relays not located yet, or due for a retry.
+fn resolve_missing(relays: &[String]) {
+    let now = Instant::now();
+    let due = |geo: &Geo, relay: &String| geo.relays.get(relay).map_or(true, |x| x.is_due(now));
+    {
+        let geo = GEO.read().unwrap();
+        if !relays.iter().any(|x| due(&geo, x)) {
+            return;
+        }
+    }
+    let mut geo = GEO.write().unwrap();
+    let missing: Vec<String> = relays.iter().filter(|x| due(&geo, x)).cloned().collect();
+    if missing.is_empty() {
+        return;
+    }
+    for relay in &missing {
+        geo.relays.insert(
+            relay.clone(),
+            Resolved {
+                location: None,
+                tm: now,
+            },
+        );
+    }
+    let generation = geo.generation;
+    if let Err(err) = std::thread::Builder::new()
+        .name("geo-resolve".to_owned())
+        .spawn(move || resolve(missing, generation))
+    {
+        log::error!("Failed to resolve relays: {}", err);
+    }
+}
+
+// great-circle distance in km
+fn distance(a: &Location, b: &Location) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.lon - a.lon).to_radians();
+    let h = (dlat / 2.).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.).sin().powi(2);
+    2. * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+fn in_region(region: &str, peer: &Location) -> bool {
+    !region.is_empty()
+        && (region.eq_ignore_ascii_case(&peer.continent)
+            || region.eq_ignore_ascii_case(&peer.country))
+}
+
+fn cost(relay: &Location, meta: Option<&RelayMeta>, a: &Location, b: &Location) -> f64 {
+    let capacity = meta.map(|m| m.capacity).unwrap_or(DEFAULT_CAPACITY).max(1) as f64;
+    let mut cost = (distance(a, relay) + distance(b, relay)) * DEFAULT_CAPACITY as f64 / capacity;
+    if let Some(meta) = meta {
+        for peer in [a, b] {
+            if in_region(&meta.region, peer) {
+                cost *= REGION_FACTOR;
+            }
+        }
+    }
+    cost
+}
+
+/// Pick the relay closest to both peers, weighted by capacity, region and
+/// the load reported by the relay (`loads`, aligned with `relays`).
+/// Returns the index into `relays` and, if `explain`, an explanation of the
+/// choice, or None if geo selection is not possible (no database, or
+/// peers/relays not located).
+pub(crate) fn select(
+    relays: &[String],
+    loads: &[Option<f64>],
+    a: IpAddr,
+    b: IpAddr,
+    explain: bool,
+) -> Option<(usize, String)> {
+    if !is_enabled() {
+        return None;
+    }
+    resolve_missing(relays);
+    let geo = GEO.read().unwrap();
+    let reader = geo.reader.as_ref()?;
+    let mut text = String::new();
+    // the arguments are only formatted if `explain`
+    macro_rules! note {
+        ($($arg:tt)*) => {
+            if explain {
+                let _ = writeln!(text, $($arg)*);
+            }
+        };
+    }
+    let la = lookup(reader, a);
+    let lb = lookup(reader, b);
+    note!("peer {}: {:?}", a, la);
+    note!("peer {}: {:?}", b, lb);
+    let (la, lb) = match (la, lb) {
+        (Some(la), Some(lb)) => (la, lb),
+        (Some(l), None) | (None, Some(l)) => (l.clone(), l),
+        (None, None) => return None,
+    };
+    let mut best: Option<(usize, f64)> = None;
+    for (i, relay) in relays.iter().enumerate() {
+        let Some(location) = geo.relays.get(relay).and_then(|x| x.location.as_ref()) else {
+            note!("relay {}: not located", relay);
+            continue;
+        };
+        let meta = geo.meta.get(host_of(relay));
+        let load = loads.get(i).copied().flatten();
+        let c = cost(location, meta, &la, &lb) * (1. + load.unwrap_or(0.));
+        note!(
+            "relay {}: {:.0}km + {:.0}km, region {:?}, capacity {}, load {}, cost {:.0}",
+            relay,
+            distance(&la, location),
+            distance(&lb, location),
+            meta.map(|m| m.region.as_str()).unwrap_or_default(),
+            meta.map(|m| m.capacity).unwrap_or(DEFAULT_CAPACITY),
+            load.map(|x| format!("{:.0}%", x * 100.))
+                .unwrap_or_else(|| "unknown".to_owned()),
+            c
+        );
+        if best.map(|x| c < x.1).unwrap_or(true) {
+            best = Some((i, c));
+        }
+    }
+    let (i, _) = best?;
+    note!("selected: {}", relays[i]);
+    Some((i, text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(lat: f64, lon: f64) -> Location {
+        Location {
+            lat,
+            lon,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_host_of() {
+        assert_eq!(host_of("relay.example.com"), "relay.example.com");
+        assert_eq!(host_of("relay.example.com:21117"), "relay.example.com");
+        assert_eq!(host_of("[2001:db8::1]:21117"), "2001:db8::1");
+        assert_eq!(host_of("2001:db8::1"), "2001:db8::1");
+    }
+
+    #[test]
+    fn test_cost() {
+        let paris = loc(48.86, 2.35);
+        let berlin = loc(52.52, 13.40);
+        let tokyo = loc(35.68, 139.69);
+        let d = distance(&paris, &berlin);
+        assert!((870. ..890.).contains(&d), "{}", d);
+        assert!(cost(&berlin, None, &paris, &paris) < cost(&tokyo, None, &paris, &paris));
+        // a much bigger relay wins over a slightly closer one
+        let big = RelayMeta {
+            host: "big".to_owned(),
+            region: "".to_owned(),
+            capacity: 1000,
+            lat: None,
+            lon: None,
+        };
+        assert!(cost(&berlin, Some(&big), &paris, &paris) < cost(&paris, None, &paris, &berlin));
+    }
+
+    #[test]
+    fn test_resolve_retry() {
+        let now = Instant::now();
+        let resolved = |location, tm| Resolved { location, tm };
+        assert!(!resolved(None, now).is_due(now));
+        assert!(resolved(None, now).is_due(now + RESOLVE_RETRY));
+        assert!(!resolved(Some(loc(0., 0.)), now).is_due(now + RESOLVE_RETRY));
+        assert_eq!(
+            resolve_host("127.0.0.1:21117"),
+            Some("127.0.0.1".parse().unwrap())
+        );
+        assert_eq!(resolve_host("[::1]:21117"), Some("::1".parse().unwrap()));
+    }
+}
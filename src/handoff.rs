@@ -0,0 +1,299 @@
+// Restart of hbbr without dropping relays: the new process takes over the
+// listening sockets of the running one.
+//
+// With `handoff-socket` set, hbbr listens on that unix socket. `hbbr --takeover`
+// connects to it and receives the listeners (file descriptors passed with
+// SCM_RIGHTS). The old process pauses accepting before it sends them, so the
+// two never accept at the same time; connections wait in the backlog until
+// the new process accepts. Once the new process confirms, the old one exits
+// when its relays have finished, or on a signal. If the new process does not
+// confirm, the old one resumes accepting.
+//
+// The halves of a relay are paired in the memory of one process. A pair whose
+// first half reached the old process just before the pause, and whose second
+// half reaches the new one, is not paired: both halves time out and the
+// clients connect again, to the new process.
+//
+//   HANDOFF_SOCKET=/run/rustdesk/hbbr.sock hbbr             (running)
+//   HANDOFF_SOCKET=/run/rustdesk/hbbr.sock hbbr --takeover  (upgrade)
+use hbb_common::{bail, log, tokio::sync::Notify, ResultType};
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    sync::atomic::{AtomicBool, Ordering},
+};
+#[cfg(unix)]
+use std::{
+    io::{Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    time::Duration,
+};
+
+#[cfg(unix)]
+const TAKEOVER: &[u8] = b"takeover\n";
+#[cfg(unix)]
+const READY: &[u8] = b"ready\n";
+#[cfg(unix)]
+const MAX_FDS: usize = 8;
+// for each step of the handoff
+#[cfg(unix)]
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref NOTIFY: Notify = Notify::new();
+}
+
+/// The handoff socket, empty if not set.
+pub fn path() -> String {
+    crate::settings::get()
+        .handoff_socket
+        .clone()
+        .unwrap_or_default()
+}
+
+/// Resolves when the listeners have been handed over to a new process.
+pub async fn handed_over() {
+    loop {
+        let notified = NOTIFY.notified();
+        if HANDED_OVER.load(Ordering::SeqCst) {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Whether accepting is paused while the listeners are handed over.
+#[inline]
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Resolves when `is_paused` is no longer `paused`.
+pub async fn pause_changed(paused: bool) {
+    loop {
+        let notified = NOTIFY.notified();
+        if is_paused() != paused {
+            return;
+        }
+        notified.await;
+    }
+}
+
+#[cfg(unix)]
+fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::SeqCst);
+    NOTIFY.notify_waiters();
+}
+
+/// Listeners taken over from the running server, by role.
+pub struct Inherited {
+    listeners: HashMap<String, TcpListener>,
+    #[cfg(unix)]
+    conn: UnixStream,
+}
+
+impl Inherited {
+    pub fn take(&mut self, role: &str) -> Option<TcpListener> {
+        self.listeners.remove(role)
+    }
+
+    /// Tell the old server that the listeners are in use, it stops accepting.
+    pub fn confirm(self) -> ResultType<()> {
+        for role in self.listeners.keys() {
+            log::warn!("Inherited {} listener is not used", role);
+        }
+        #[cfg(unix)]
+        {
+            let mut conn = self.conn;
+            conn.write_all(READY)?;
+        }
+        Ok(())
+    }
+}
+
+/// Take over the listeners of the server running on the handoff socket `path`.
+#[cfg(unix)]
+pub fn take_over(path: &str) -> ResultType<Inherited> {
+    use nix::sys::{
+        socket::{recvmsg, ControlMessageOwned, MsgFlags},
+        uio::IoVec,
+    };
+
+    if path.is_empty() {
+        bail!("handoff-socket is not set");
+    }
+    let mut conn = UnixStream::connect(path)?;
+    conn.set_read_timeout(Some(TIMEOUT))?;
+    conn.write_all(TAKEOVER)?;
+    let mut buf = [0u8; 256];
+    let mut cmsg = nix::cmsg_space!([RawFd; MAX_FDS]);
+    let msg = recvmsg(
+        conn.as_raw_fd(),
+        &[IoVec::from_mut_slice(&mut buf)],
+        Some(&mut cmsg),
+        MsgFlags::empty(),
+    )?;
+    let mut fds = Vec::new();
+    for x in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(v) = x {
+            fds.extend(v);
+        }
+    }
+    let n = msg.bytes;
+    // SAFETY: the descriptors were just received and are owned by nobody else
+    let sockets: Vec<TcpListener> = fds
+        .into_iter()
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect();
+    let roles: Vec<&str> = std::str::from_utf8(&buf[..n])?
+        .trim()
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .collect();
+    if roles.len() != sockets.len() {
+        bail!(
+            "Handoff of {} listeners with {} sockets",
+            roles.len(),
+            sockets.len()
+        );
+    }
+    let listeners = roles
+        .into_iter()
+        .map(|x| x.to_owned())
+        .zip(sockets)
+        .collect();
+    Ok(Inherited { listeners, conn })
+}
+
+#[cfg(not(unix))]
+pub fn take_over(_path: &str) -> ResultType<Inherited> {
+    bail!("Takeover is only supported on unix");
+}
+
+/// Hand the listeners over to a new process connecting on `path`.
+#[cfg(unix)]
+pub fn serve(path: &str, listeners: &[(&str, RawFd)]) -> ResultType<()> {
+    // own copies, the listeners of the caller may be replaced
+    let mut copies = Vec::new();
+    for (role, fd) in listeners {
+        let fd = nix::unistd::dup(*fd)?;
+        // SAFETY: fd is a new descriptor owned by the copy
+        copies.push((role.to_string(), unsafe { TcpListener::from_raw_fd(fd) }));
+    }
+    // the socket of the process we took over from, or a stale one
+    std::fs::remove_file(path).ok();
+    let server = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    log::info!("Listening on {} for handoff", path);
+    std::thread::spawn(move || {
+        for conn in server.incoming() {
+            match conn
+                .map_err(|e| e.into())
+                .and_then(|x| hand_over(x, &copies))
+            {
+                Ok(()) => {
+                    log::info!("Listeners handed over");
+                    HANDED_OVER.store(true, Ordering::SeqCst);
+                    NOTIFY.notify_waiters();
+                    break;
+                }
+                Err(err) => log::error!("Handoff failed: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+fn hand_over(mut conn: UnixStream, listeners: &[(String, TcpListener)]) -> ResultType<()> {
+    conn.set_read_timeout(Some(TIMEOUT))?;
+    let mut buf = [0u8; 64];
+    let n = conn.read(&mut buf)?;
+    if &buf[..n] != TAKEOVER {
+        bail!("Unexpected handoff request");
+    }
+    set_paused(true);
+    let res = send_listeners(conn, listeners);
+    if res.is_err() {
+        log::info!("Resume accepting");
+        set_paused(false);
+    }
+    res
+}
+
+#[cfg(unix)]
+fn send_listeners(mut conn: UnixStream, listeners: &[(String, TcpListener)]) -> ResultType<()> {
+    use nix::sys::{
+        socket::{sendmsg, ControlMessage, MsgFlags},
+        uio::IoVec,
+    };
+
+    let roles: Vec<&str> = listeners.iter().map(|x| x.0.as_str()).collect();
+    let roles = format!("{}\n", roles.join(","));
+    let fds: Vec<RawFd> = listeners.iter().map(|x| x.1.as_raw_fd()).collect();
+    sendmsg(
+        conn.as_raw_fd(),
+        &[IoVec::from_slice(roles.as_bytes())],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        None,
+    )?;
+    // the new process confirms once it accepts on them
+    let mut buf = [0u8; 64];
+    let n = conn.read(&mut buf)?;
+    if &buf[..n] != READY {
+        bail!("The new process did not take over");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn wait(f: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while !f() {
+            if start.elapsed() > Duration::from_secs(5) {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    #[test]
+    fn test_handoff() {
+        let path = std::env::temp_dir().join(format!("hbbr_handoff_{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve(path, &[("relay", listener.as_raw_fd())]).unwrap();
+        // no confirmation, the old process resumes
+        let mut inherited = take_over(path).unwrap();
+        assert!(is_paused());
+        assert_eq!(inherited.take("relay").unwrap().local_addr().unwrap(), addr);
+        drop(inherited);
+        assert!(wait(|| !is_paused()));
+        assert!(!HANDED_OVER.load(Ordering::SeqCst));
+        // the same listener again, confirmed
+        let mut inherited = take_over(path).unwrap();
+        let relay = inherited.take("relay").unwrap();
+        assert_eq!(relay.local_addr().unwrap(), addr);
+        assert!(inherited.take("ws").is_none());
+        inherited.confirm().unwrap();
+        assert!(wait(|| HANDED_OVER.load(Ordering::SeqCst)));
+        // the inherited listener accepts
+        std::net::TcpStream::connect(addr).unwrap();
+        relay.accept().unwrap();
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -2,8 +2,11 @@ use clap::App;
 mod common;
 mod custom_keys;
 mod relay_server;
+mod server_key;
 use flexi_logger::*;
 use hbb_common::{config::RELAY_PORT, ResultType};
+use hbbs::key_format;
+use hbbs::settings::{self, Server};
 use relay_server::*;
 mod version;
 
@@ -14,8 +17,12 @@ fn main() -> ResultType<()> {
         .write_mode(WriteMode::Async)
         .start()?;
     let args = format!(
-        "-p, --port=[NUMBER(default={RELAY_PORT})] 'Sets the listening port'
+        "-c, --config=[FILE] 'Sets a TOML config file with [common] and [relay] sections. Its options override .env, the environment and command line override it'
+        -p, --port=[NUMBER(default={RELAY_PORT})] 'Sets the listening port'
         -k, --key=[KEY] 'Only allow the client with the same key'
+        , --key-stdin 'Reads the private key from the first line of stdin, see also KEY_FILE'
+        -d, --data-dir=[DIR] 'Sets the directory of the key, database and block lists (default: the working directory)'
+        -t, --takeover 'Takes over the listeners of the running hbbr through HANDOFF_SOCKET, its relays go on until they finish'
         ",
     );
     let matches = App::new("hbbr")
@@ -24,23 +31,32 @@ fn main() -> ResultType<()> {
         .about("RustDesk Relay Server")
         .args_from_usage(&args)
         .get_matches();
-    if let Ok(v) = ini::Ini::load_from_file(".env") {
-        if let Some(section) = v.section(None::<String>) {
-            section.iter().for_each(|(k, v)| std::env::set_var(k, v));
-        }
+    // .env does not override the environment nor the config file
+    settings::load_dotenv();
+    if let Some(v) = matches.value_of("data-dir") {
+        std::env::set_var("DATA_DIR", v);
     }
-    let mut port = RELAY_PORT;
-    if let Ok(v) = std::env::var("PORT") {
-        let v: i32 = v.parse().unwrap_or_default();
-        if v > 0 {
-            port = v + 1;
+    let config = matches
+        .value_of("config")
+        .map(|x| x.to_owned())
+        .unwrap_or_else(|| std::env::var("CONFIG").unwrap_or_default());
+    let settings = settings::init(Server::Hbbr, &config)?;
+    // PORT is the port of hbbs, see .env
+    let mut port = settings.relay_port.map(|x| x as i32).unwrap_or(RELAY_PORT);
+    if settings.relay_port.is_none() {
+        if let Ok(v) = std::env::var("PORT") {
+            let v: i32 = v.parse().unwrap_or_default();
+            if v > 0 {
+                port = v + 1;
+            }
         }
     }
     start(
         matches.value_of("port").unwrap_or(&port.to_string()),
         matches
             .value_of("key")
-            .unwrap_or(&std::env::var("KEY").unwrap_or_default()),
+            .unwrap_or(settings.relay_key.as_deref().unwrap_or_default()),
+        matches.is_present("takeover"),
     )?;
     Ok(())
 }
@@ -0,0 +1,627 @@
+// IP lists kept in a text file, one entry per line:
+//
+// <ip> [expires=<RFC 3339 time>] [by=<creator>] [reason]
+//
+// <ip> is an address or a network (IPv4 CIDR or IPv6 prefix), e.g. 1.2.3.4,
+// 10.1.0.0/16 or 2001:db8:1:2::/64.
+//
+// Lines starting with '#' are comments. Runtime changes are written back to
+// the file, entries lift on their own once expired, and edits to the file are
+// picked up while running. The creator and the reason of an entry cannot hold
+// control characters, a line break would add entries.
+use chrono::{DateTime, Duration, Utc};
+use hbb_common::{
+    bail, log,
+    tokio::{self, sync::RwLock},
+    ResultType,
+};
+use ipnetwork::IpNetwork;
+use notify::{RecursiveMode, Watcher};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IpEntry {
+    pub ip: String,
+    pub expires: Option<DateTime<Utc>>,
+    pub by: String,
+    pub reason: String,
+}
+
+impl IpEntry {
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let mut tokens = line.split_whitespace().peekable();
+        let mut entry = Self {
+            ip: tokens.next()?.to_owned(),
+            ..Default::default()
+        };
+        while let Some(token) = tokens.peek() {
+            if let Some(v) = token.strip_prefix("expires=") {
+                match DateTime::parse_from_rfc3339(v) {
+                    Ok(v) => entry.expires = Some(v.with_timezone(&Utc)),
+                    Err(err) => log::error!("Invalid expiry of {}: {} - {}", entry.ip, v, err),
+                }
+            } else if let Some(v) = token.strip_prefix("by=") {
+                entry.by = v.to_owned();
+            } else {
+                break;
+            }
+            tokens.next();
+        }
+        entry.reason = tokens.collect::<Vec<_>>().join(" ");
+        Some(entry)
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = self.ip.clone();
+        if let Some(expires) = self.expires {
+            line += &format!(" expires={}", expires.to_rfc3339());
+        }
+        if !self.by.is_empty() {
+            line += &format!(" by={}", clean(&self.by).replace(char::is_whitespace, "_"));
+        }
+        let reason = clean(&self.reason);
+        if !reason.trim().is_empty() {
+            line += " ";
+            line += reason.trim();
+        }
+        line
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "ip": self.ip,
+            "expires": self.expires.map(|x| x.to_rfc3339()),
+            "by": self.by,
+            "reason": self.reason,
+        })
+    }
+
+    #[inline]
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.map(|x| x <= now).unwrap_or(false)
+    }
+}
+
+// `s` with control characters as spaces, so that it stays on its line
+fn clean(s: &str) -> String {
+    s.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn check_text(name: &str, s: &str) -> ResultType<()> {
+    if s.chars().any(|c| c.is_control()) {
+        bail!("Invalid {}: control characters are not allowed", name);
+    }
+    Ok(())
+}
+
+/// Parse a duration such as 90s, 30m, 12h, 7d or 2w.
+pub fn parse_duration(v: &str) -> Option<Duration> {
+    let v = v.trim();
+    if !v.is_ascii() {
+        return None;
+    }
+    let (n, unit) = v.split_at(v.len().checked_sub(1)?);
+    let n: i64 = n.parse().ok().filter(|n| *n > 0)?;
+    match unit {
+        "s" => Some(Duration::seconds(n)),
+        "m" => Some(Duration::minutes(n)),
+        "h" => Some(Duration::hours(n)),
+        "d" => Some(Duration::days(n)),
+        "w" => Some(Duration::weeks(n)),
+        _ => None,
+    }
+}
+
+// Entries by their text, plus an index of the networks they cover by prefix
+// length, so that a lookup costs one hash lookup per prefix length in use.
+// Entries written differently may cover the same network, e.g. 1.2.3.4 and
+// 1.2.3.4/32, so a network has every entry covering it.
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, IpEntry>,
+    v4: BTreeMap<u8, HashMap<u32, Vec<String>>>,
+    v6: BTreeMap<u8, HashMap<u128, Vec<String>>>,
+}
+
+#[inline]
+fn mask_v4(ip: u32, prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    } else {
+        ip & (u32::MAX << (32 - prefix as u32))
+    }
+}
+
+#[inline]
+fn mask_v6(ip: u128, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        ip & (u128::MAX << (128 - prefix as u32))
+    }
+}
+
+impl Entries {
+    fn new(map: HashMap<String, IpEntry>) -> Self {
+        let mut me = Self {
+            map,
+            ..Default::default()
+        };
+        me.reindex();
+        me
+    }
+
+    fn reindex(&mut self) {
+        self.v4.clear();
+        self.v6.clear();
+        for key in self.map.keys() {
+            match key.parse::<IpNetwork>() {
+                Ok(IpNetwork::V4(net)) => {
+                    let prefix = net.prefix();
+                    self.v4
+                        .entry(prefix)
+                        .or_default()
+                        .entry(mask_v4(net.ip().into(), prefix))
+                        .or_default()
+                        .push(key.clone());
+                }
+                Ok(IpNetwork::V6(net)) => {
+                    let prefix = net.prefix();
+                    self.v6
+                        .entry(prefix)
+                        .or_default()
+                        .entry(mask_v6(net.ip().into(), prefix))
+                        .or_default()
+                        .push(key.clone());
+                }
+                Err(_) => log::warn!("{} is not an ip or network, matched as is", key),
+            }
+        }
+    }
+
+    // the most specific entry covering `ip` that has not expired
+    fn lookup(&self, ip: IpAddr, now: DateTime<Utc>) -> Option<&IpEntry> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        let keys: Box<dyn Iterator<Item = Option<&Vec<String>>> + '_> = match ip {
+            IpAddr::V4(ip) => {
+                let ip = u32::from(ip);
+                Box::new(
+                    self.v4
+                        .iter()
+                        .rev()
+                        .map(move |(p, m)| m.get(&mask_v4(ip, *p))),
+                )
+            }
+            IpAddr::V6(ip) => {
+                let ip = u128::from(ip);
+                Box::new(
+                    self.v6
+                        .iter()
+                        .rev()
+                        .map(move |(p, m)| m.get(&mask_v6(ip, *p))),
+                )
+            }
+        };
+        keys.flatten()
+            .flatten()
+            .filter_map(|key| self.map.get(key))
+            .find(|x| !x.is_expired(now))
+    }
+
+    // exact entry, or the entry covering `ip` if it is an address
+    fn get(&self, ip: &str, now: DateTime<Utc>) -> Option<&IpEntry> {
+        match self.map.get(ip) {
+            Some(x) if !x.is_expired(now) => Some(x),
+            _ => self.lookup(ip.parse().ok()?, now),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IpList {
+    path: String,
+    entries: Arc<RwLock<Entries>>,
+    // generation of the last snapshot taken, and of the last one written
+    generation: Arc<AtomicU64>,
+    written: Arc<Mutex<u64>>,
+}
+
+impl IpList {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_owned(),
+            entries: Default::default(),
+            generation: Default::default(),
+            written: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// (Re)read the file; a missing file is an empty list.
+    pub async fn load(&self) {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    log::error!("Failed to read {}: {}", self.path, err);
+                }
+                String::new()
+            }
+        };
+        let entries: HashMap<String, IpEntry> = content
+            .lines()
+            .filter_map(IpEntry::parse)
+            .map(|x| (x.ip.clone(), x))
+            .collect();
+        log::info!("#{}: {}", self.path, entries.len());
+        *self.entries.write().await = Entries::new(entries);
+    }
+
+    // The file content of `entries` and its generation, taken under the write
+    // lock so that the generations follow the order of the changes.
+    fn snapshot(&self, entries: &HashMap<String, IpEntry>) -> (u64, String) {
+        let mut entries: Vec<&IpEntry> = entries.values().collect();
+        entries.sort_by(|a, b| a.ip.cmp(&b.ip));
+        let mut content = "# <ip> [expires=<RFC 3339 time>] [by=<creator>] [reason]\n".to_owned();
+        for x in entries {
+            content += &x.to_line();
+            content.push('\n');
+        }
+        (self.generation.fetch_add(1, Ordering::SeqCst) + 1, content)
+    }
+
+    // Write a snapshot outside of the list lock, unless a newer one is written.
+    async fn save(&self, (generation, content): (u64, String)) -> ResultType<()> {
+        let path = self.path.clone();
+        let written = self.written.clone();
+        tokio::task::spawn_blocking(move || -> ResultType<()> {
+            let mut written = written.lock().unwrap();
+            if *written >= generation {
+                return Ok(());
+            }
+            // replace the file at once so that the watcher never reads half of it
+            let tmp = format!("{}.tmp", path);
+            std::fs::write(&tmp, content)?;
+            std::fs::rename(&tmp, &path)?;
+            *written = generation;
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Whether an address, or an entry given as is, is on the list.
+    pub async fn contains(&self, ip: &str) -> bool {
+        self.entries.read().await.get(ip, Utc::now()).is_some()
+    }
+
+    /// Whether an address is on the list, by itself or within a network.
+    pub async fn contains_ip(&self, ip: IpAddr) -> bool {
+        self.entries.read().await.lookup(ip, Utc::now()).is_some()
+    }
+
+    /// The entry matching `ip` (an entry as is, or an address within a network).
+    pub async fn get(&self, ip: &str) -> Option<IpEntry> {
+        self.entries.read().await.get(ip, Utc::now()).cloned()
+    }
+
+    pub async fn list(&self) -> Vec<IpEntry> {
+        let now = Utc::now();
+        let mut res: Vec<IpEntry> = self
+            .entries
+            .read()
+            .await
+            .map
+            .values()
+            .filter(|x| !x.is_expired(now))
+            .cloned()
+            .collect();
+        res.sort_by(|a, b| a.ip.cmp(&b.ip));
+        res
+    }
+
+    pub async fn count(&self) -> usize {
+        self.entries.read().await.map.len()
+    }
+
+    /// Add or replace entries, `ips` separated by '|'.
+    /// An entry is an address or a network, e.g. 10.1.0.0/16 or 2001:db8::/64.
+    pub async fn add(
+        &self,
+        ips: &str,
+        expires: Option<DateTime<Utc>>,
+        by: &str,
+        reason: &str,
+    ) -> ResultType<usize> {
+        let ips: Vec<&str> = ips
+            .split('|')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .collect();
+        for ip in ips.iter() {
+            if ip.parse::<IpNetwork>().is_err() {
+                bail!("Invalid ip or network: {}", ip);
+            }
+        }
+        check_text("creator", by)?;
+        check_text("reason", reason)?;
+        let mut lock = self.entries.write().await;
+        for ip in ips.iter() {
+            lock.map.insert(
+                ip.to_string(),
+                IpEntry {
+                    ip: ip.to_string(),
+                    expires,
+                    by: by.to_owned(),
+                    reason: reason.to_owned(),
+                },
+            );
+        }
+        lock.reindex();
+        let snapshot = self.snapshot(&lock.map);
+        drop(lock);
+        self.save(snapshot).await?;
+        Ok(ips.len())
+    }
+
+    /// Add entries from command arguments: <ip|ip...> [duration] [reason...]
+    pub async fn add_from_args<S: AsRef<str>>(&self, args: &[S], by: &str) -> ResultType<usize> {
+        let Some(ips) = args.first() else {
+            bail!("Missing ip");
+        };
+        let mut rest: Vec<&str> = args[1..].iter().map(|x| x.as_ref()).collect();
+        let expires = rest.first().and_then(|x| parse_duration(x));
+        if expires.is_some() {
+            rest.remove(0);
+        }
+        let expires = expires.map(|x| Utc::now() + x);
+        self.add(ips.as_ref(), expires, by, &rest.join(" ")).await
+    }
+
+    /// Remove entries, `ips` separated by '|', or "all".
+    pub async fn remove(&self, ips: &str) -> ResultType<usize> {
+        let mut lock = self.entries.write().await;
+        let n = lock.map.len();
+        if ips == "all" {
+            lock.map.clear();
+        } else {
+            for ip in ips.split('|') {
+                lock.map.remove(ip.trim());
+            }
+        }
+        let n = n - lock.map.len();
+        if n > 0 {
+            lock.reindex();
+            let snapshot = self.snapshot(&lock.map);
+            drop(lock);
+            self.save(snapshot).await?;
+        }
+        Ok(n)
+    }
+
+    /// Drop expired entries from memory and from the file.
+    pub async fn purge(&self) {
+        let now = Utc::now();
+        let mut lock = self.entries.write().await;
+        let n = lock.map.len();
+        lock.map.retain(|_, x| !x.is_expired(now));
+        if lock.map.len() < n {
+            log::info!(
+                "{}: {} expired entries lifted",
+                self.path,
+                n - lock.map.len()
+            );
+            lock.reindex();
+            let snapshot = self.snapshot(&lock.map);
+            drop(lock);
+            if let Err(err) = self.save(snapshot).await {
+                log::error!("Failed to write {}: {}", self.path, err);
+            }
+        }
+    }
+
+    /// Reload the list whenever its file changes, and purge expired entries
+    /// every `purge_interval` seconds.
+    pub fn watch(&self, purge_interval: u64) {
+        let me = self.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(std::time::Duration::from_secs(purge_interval));
+            loop {
+                timer.tick().await;
+                me.purge().await;
+            }
+        });
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = match notify::recommended_watcher(tx) {
+            Ok(w) => w,
+            Err(e) => {
+                log::error!("Failed to create file watcher: {}", e);
+                return;
+            }
+        };
+        // watch the directory: saving replaces the file
+        let path = Path::new(&self.path);
+        let dir = path
+            .parent()
+            .filter(|x| !x.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            log::error!("Failed to watch {}: {}", self.path, e);
+            return;
+        }
+        let file_name = path.file_name().map(|x| x.to_owned());
+        let me = self.clone();
+        let handle = tokio::runtime::Handle::current();
+        std::thread::spawn(move || {
+            let _watcher = watcher;
+            while let Ok(event) = rx.recv() {
+                match event {
+                    Ok(notify::Event {
+                        kind: notify::EventKind::Modify(_) | notify::EventKind::Create(_),
+                        paths,
+                        ..
+                    }) if paths.iter().any(|x| x.file_name() == file_name.as_deref()) => {
+                        log::info!("{} modified, reloading...", me.path);
+                        handle.block_on(me.load());
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("File watcher error: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_entry() {
+        assert_eq!(IpEntry::parse("# comment"), None);
+        let x = IpEntry::parse("1.2.3.4").unwrap();
+        assert_eq!(x.ip, "1.2.3.4");
+        assert_eq!(x.expires, None);
+        let x = IpEntry::parse("1.2.3.4 expires=2030-01-01T00:00:00+00:00 by=ops too many relays")
+            .unwrap();
+        assert_eq!(x.by, "ops");
+        assert_eq!(x.reason, "too many relays");
+        assert!(!x.is_expired(Utc::now()));
+        assert_eq!(IpEntry::parse(&x.to_line()), Some(x));
+        let x = IpEntry {
+            ip: "1.2.3.4".to_owned(),
+            by: "a\nb".to_owned(),
+            reason: "spam\n5.6.7.8 injected\r".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(x.to_line(), "1.2.3.4 by=a_b spam 5.6.7.8 injected");
+    }
+
+    #[test]
+    fn test_add() {
+        add();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn add() {
+        let path = std::env::temp_dir().join(format!("ip_list_test_{}.txt", std::process::id()));
+        let list = IpList::new(path.to_str().unwrap());
+        assert!(list
+            .add("1.2.3.4", None, "ops", "a\n5.6.7.8")
+            .await
+            .is_err());
+        assert!(list.add("1.2.3.4", None, "o\nps", "").await.is_err());
+        assert_eq!(list.count().await, 0);
+        assert_eq!(
+            list.add("1.2.3.4|10.0.0.0/8", None, "ops", "spam")
+                .await
+                .unwrap(),
+            2
+        );
+        assert_eq!(list.remove("10.0.0.0/8").await.unwrap(), 1);
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(content.lines().filter_map(IpEntry::parse).count(), 1);
+        assert!(content.contains("1.2.3.4 by=ops spam\n"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30m"), Some(Duration::minutes(30)));
+        assert_eq!(parse_duration("2d"), Some(Duration::days(2)));
+        assert_eq!(parse_duration("0h"), None);
+        assert_eq!(parse_duration("abc"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let now = Utc::now();
+        let entries = Entries::new(
+            [
+                "1.2.3.4",
+                "10.1.0.0/16",
+                "10.1.2.0/24",
+                "2001:db8:1:2::/64",
+                "0.0.0.0/0 x",
+            ]
+            .iter()
+            .filter_map(|x| IpEntry::parse(x))
+            .map(|x| (x.ip.clone(), x))
+            .collect(),
+        );
+        let ip = |x: &str| x.parse::<IpAddr>().unwrap();
+        assert_eq!(entries.lookup(ip("1.2.3.4"), now).unwrap().ip, "1.2.3.4");
+        assert_eq!(
+            entries.lookup(ip("10.1.2.3"), now).unwrap().ip,
+            "10.1.2.0/24"
+        );
+        assert_eq!(
+            entries.lookup(ip("10.1.3.3"), now).unwrap().ip,
+            "10.1.0.0/16"
+        );
+        assert_eq!(
+            entries.lookup(ip("::ffff:10.1.3.3"), now).unwrap().ip,
+            "10.1.0.0/16"
+        );
+        assert_eq!(entries.lookup(ip("9.9.9.9"), now).unwrap().ip, "0.0.0.0/0");
+        assert_eq!(
+            entries.lookup(ip("2001:db8:1:2::99"), now).unwrap().ip,
+            "2001:db8:1:2::/64"
+        );
+        assert!(entries.lookup(ip("2001:db8:1:3::1"), now).is_none());
+        assert_eq!(entries.get("10.1.0.0/16", now).unwrap().ip, "10.1.0.0/16");
+    }
+
+    #[test]
+    fn test_lookup_same_network() {
+        let now = Utc::now();
+        let entry = |ip: &str, expires: Option<DateTime<Utc>>| IpEntry {
+            ip: ip.to_owned(),
+            expires,
+            by: String::new(),
+            reason: String::new(),
+        };
+        let expired = Some(now - Duration::hours(1));
+        let later = Some(now + Duration::hours(1));
+        for (x, y, ip) in [
+            ("1.2.3.4", "1.2.3.4/32", "1.2.3.4"),
+            ("10.1.0.5/16", "10.1.0.0/16", "10.1.2.3"),
+        ] {
+            // whichever of the two is indexed first, the live one is found
+            for (a, b) in [(x, y), (y, x)] {
+                let entries = Entries::new(
+                    [entry(a, expired), entry(b, later)]
+                        .into_iter()
+                        .map(|x| (x.ip.clone(), x))
+                        .collect(),
+                );
+                let found = entries.lookup(ip.parse().unwrap(), now);
+                assert_eq!(found.unwrap().ip, b);
+            }
+        }
+    }
+}
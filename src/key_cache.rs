@@ -0,0 +1,366 @@
+use crate::{
+    database::{Database, LicenceKey},
+    key_format,
+};
+use hbb_common::{log, tokio::sync::RwLock, ResultType};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+// How long a key that is not in the database is remembered as missing,
+// so that clients sending garbage keys do not hit the database every time.
+const MISSING_TTL: Duration = Duration::from_secs(60);
+// Missing keys remembered at most, further garbage keys hit the database until
+// expired ones are swept.
+const MAX_MISSING: usize = 10_000;
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyState {
+    pub active: bool,
+    pub expired_at: i64,
+    pub max_bind_ids: i64,
+    pub bound: HashSet<String>,
+}
+
+impl KeyState {
+    fn from_record(rec: &LicenceKey) -> Self {
+        Self {
+            active: rec.active != 0,
+            expired_at: rec.expired_at,
+            max_bind_ids: rec.max_bind_ids,
+            bound: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn is_valid(&self, now: i64) -> bool {
+        self.active && self.expired_at > now
+    }
+
+    // Same tri-state as Database::check_binding_state:
+    // (exists_and_valid, already_bound, overuse)
+    pub fn check(&self, peer_id: &str, now: i64) -> (bool, bool, bool) {
+        let already = self.bound.contains(peer_id);
+        if !self.is_valid(now) {
+            return (false, already, false);
+        }
+        let overuse = !already && self.bound.len() as i64 >= self.max_bind_ids;
+        (true, already, overuse)
+    }
+}
+
+enum Entry {
+    Known(KeyState),
+    Missing(Instant),
+}
+
+#[derive(Default)]
+struct Keys {
+    entries: HashMap<String, Entry>,
+    // peer_id -> the known keys it is bound to, the reverse of KeyState::bound
+    by_peer: HashMap<String, HashSet<String>>,
+    // number of Missing entries
+    missing: usize,
+    swept: Option<Instant>,
+}
+
+impl Keys {
+    fn insert(&mut self, key: &str, entry: Entry) {
+        match self.entries.remove(key) {
+            Some(Entry::Known(state)) => {
+                for peer_id in &state.bound {
+                    self.unindex(key, peer_id);
+                }
+            }
+            Some(Entry::Missing(_)) => self.missing -= 1,
+            None => {}
+        }
+        match &entry {
+            Entry::Known(state) => {
+                for peer_id in &state.bound {
+                    self.index(key, peer_id);
+                }
+            }
+            Entry::Missing(tm) => {
+                if self.swept.map_or(true, |x| tm.duration_since(x) >= MISSING_TTL)
+                    || self.missing >= MAX_MISSING
+                {
+                    self.sweep(*tm);
+                }
+                if self.missing >= MAX_MISSING {
+                    return;
+                }
+                self.missing += 1;
+            }
+        }
+        self.entries.insert(key.to_owned(), entry);
+    }
+
+    // drop the Missing entries older than MISSING_TTL
+    fn sweep(&mut self, now: Instant) {
+        self.entries.retain(|_, x| match x {
+            Entry::Missing(tm) => now.saturating_duration_since(*tm) < MISSING_TTL,
+            Entry::Known(_) => true,
+        });
+        self.missing = self
+            .entries
+            .values()
+            .filter(|x| matches!(x, Entry::Missing(_)))
+            .count();
+        self.swept = Some(now);
+    }
+
+    fn index(&mut self, key: &str, peer_id: &str) {
+        self.by_peer
+            .entry(peer_id.to_owned())
+            .or_default()
+            .insert(key.to_owned());
+    }
+
+    fn unindex(&mut self, key: &str, peer_id: &str) {
+        if let Some(keys) = self.by_peer.get_mut(peer_id) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.by_peer.remove(peer_id);
+            }
+        }
+    }
+
+    fn bind(&mut self, key: &str, peer_id: &str) {
+        if let Some(Entry::Known(state)) = self.entries.get_mut(key) {
+            state.bound.insert(peer_id.to_owned());
+            self.index(key, peer_id);
+        }
+    }
+
+    fn unbind(&mut self, key: &str, peer_id: &str) {
+        if let Some(Entry::Known(state)) = self.entries.get_mut(key) {
+            state.bound.remove(peer_id);
+            self.unindex(key, peer_id);
+        }
+    }
+
+    fn is_bound(&self, peer_id: &str, now: i64) -> bool {
+        self.by_peer.get(peer_id).map_or(false, |keys| {
+            keys.iter().any(|key| {
+                matches!(self.entries.get(key), Some(Entry::Known(state)) if state.is_valid(now))
+            })
+        })
+    }
+}
+
+/// In-memory view of `licence_keys` and `licence_key_bindings`.
+///
+/// Loaded once at startup, then kept coherent by the admin API calling
+/// `refresh` after each mutation. Keys unknown to the cache are looked up
+/// in the database once and remembered, so the hot path only reads the
+/// database for keys it has never seen.
+#[derive(Clone)]
+pub struct KeyCache {
+    db: Database,
+    keys: Arc<RwLock<Keys>>,
+}
+
+impl KeyCache {
+    pub async fn new(db: Database) -> ResultType<Self> {
+        let cache = Self {
+            db,
+            keys: Default::default(),
+        };
+        cache.reload().await?;
+        Ok(cache)
+    }
+
+    pub async fn reload(&self) -> ResultType<()> {
+        let mut keys = Keys::default();
+        for rec in self.db.list_all_keys().await? {
+            keys.insert(&rec.licence_key, Entry::Known(KeyState::from_record(&rec)));
+        }
+        for (key, peer_id) in self.db.list_bindings(None).await? {
+            keys.bind(&key, &peer_id);
+        }
+        log::info!("licence key cache loaded: {} keys", keys.entries.len());
+        *self.keys.write().await = keys;
+        Ok(())
+    }
+
+    /// Re-read one key and its bindings from the database.
+    pub async fn refresh(&self, key: &str) {
+        let rec = match self.db.get_key(key).await {
+            Ok(rec) => rec,
+            Err(err) => {
+                log::error!("Failed to refresh licence key {}: {}", key, err);
+                return;
+            }
+        };
+        let entry = match rec {
+            Some(rec) => {
+                let mut state = KeyState::from_record(&rec);
+                match self.db.list_bindings(Some(key)).await {
+                    Ok(bindings) => state.bound = bindings.into_iter().map(|x| x.1).collect(),
+                    Err(err) => {
+                        log::error!("Failed to load bindings of licence key {}: {}", key, err);
+                        return;
+                    }
+                }
+                Entry::Known(state)
+            }
+            None => Entry::Missing(Instant::now()),
+        };
+        self.keys.write().await.insert(key, entry);
+    }
+
+    async fn get(&self, key: &str) -> Option<KeyState> {
+        match self.keys.read().await.entries.get(key) {
+            Some(Entry::Known(state)) => return Some(state.clone()),
+            Some(Entry::Missing(tm)) if tm.elapsed() < MISSING_TTL => return None,
+            _ => {}
+        }
+        self.refresh(key).await;
+        match self.keys.read().await.entries.get(key) {
+            Some(Entry::Known(state)) => Some(state.clone()),
+            _ => None,
+        }
+    }
+
+    pub async fn is_key_valid(&self, key: &str) -> bool {
+        let Ok(key) = key_format::normalize(key) else {
+            return false;
+        };
+        let key = key.as_str();
+        let now = chrono::Utc::now().timestamp();
+        self.get(key)
+            .await
+            .map(|state| state.is_valid(now))
+            .unwrap_or(false)
+    }
+
+    /// Returns (exists_and_valid, already_bound, overuse) without touching the database.
+    /// Keys not in an accepted format are invalid, see key_format.
+    pub async fn check_binding_state(&self, key: &str, peer_id: &str) -> (bool, bool, bool) {
+        let Ok(key) = key_format::normalize(key) else {
+            return (false, false, false);
+        };
+        let key = key.as_str();
+        let now = chrono::Utc::now().timestamp();
+        match self.get(key).await {
+            Some(state) => state.check(peer_id, now),
+            None => (false, false, false),
+        }
+    }
+
+    /// Whether `peer_id` is bound to a valid key.
+    pub async fn is_bound(&self, peer_id: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.keys.read().await.is_bound(peer_id, now)
+    }
+
+    /// Bind `peer_id` to `key` if the key is valid and has room left.
+    /// The binding is recorded in memory first and then written through to the database.
+    pub async fn ensure_binding_allowed(&self, key: &str, peer_id: &str) -> bool {
+        let Ok(key) = key_format::normalize(key) else {
+            return false;
+        };
+        let key = key.as_str();
+        if self.get(key).await.is_none() {
+            return false;
+        }
+        let now = chrono::Utc::now().timestamp();
+        {
+            let mut lock = self.keys.write().await;
+            let Some(Entry::Known(state)) = lock.entries.get(key) else {
+                return false;
+            };
+            match state.check(peer_id, now) {
+                (true, true, _) => return true,
+                (true, false, false) => lock.bind(key, peer_id),
+                _ => return false,
+            }
+        }
+        if let Err(err) = self.db.insert_binding(key, peer_id).await {
+            log::error!("Failed to bind {} to licence key {}: {}", peer_id, key, err);
+            self.keys.write().await.unbind(key, peer_id);
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, KeyState, Keys, MAX_MISSING, MISSING_TTL};
+    use std::time::Instant;
+
+    #[test]
+    fn test_key_state_check() {
+        let mut state = KeyState {
+            active: true,
+            expired_at: 100,
+            max_bind_ids: 2,
+            bound: Default::default(),
+        };
+        assert_eq!(state.check("a", 50), (true, false, false));
+        state.bound.insert("a".to_owned());
+        state.bound.insert("b".to_owned());
+        assert_eq!(state.check("a", 50), (true, true, false));
+        assert_eq!(state.check("c", 50), (true, false, true));
+        // expiry is evaluated against the clock passed in
+        assert_eq!(state.check("a", 100), (false, true, false));
+        state.active = false;
+        assert_eq!(state.check("c", 50), (false, false, false));
+    }
+
+    #[test]
+    fn test_bound_index() {
+        let state = |active| KeyState {
+            active,
+            expired_at: 100,
+            max_bind_ids: 2,
+            bound: ["a".to_owned()].into_iter().collect(),
+        };
+        let mut keys = Keys::default();
+        keys.insert("k1", Entry::Known(state(true)));
+        keys.insert("k2", Entry::Known(state(false)));
+        assert!(keys.is_bound("a", 50));
+        assert!(!keys.is_bound("a", 100));
+        assert!(!keys.is_bound("b", 50));
+        keys.bind("k2", "b");
+        assert!(!keys.is_bound("b", 50));
+        keys.bind("k1", "b");
+        assert!(keys.is_bound("b", 50));
+        keys.unbind("k1", "b");
+        assert!(!keys.is_bound("b", 50));
+        assert_eq!(keys.by_peer["b"].len(), 1);
+        // a refresh replaces the bindings of the key
+        keys.insert("k1", Entry::Missing(Instant::now()));
+        assert!(!keys.is_bound("a", 50));
+        assert_eq!(keys.by_peer["a"].len(), 1);
+        keys.insert("k2", Entry::Known(state(true)));
+        assert!(!keys.by_peer.contains_key("b"));
+    }
+
+    #[test]
+    fn test_missing_eviction() {
+        let now = Instant::now();
+        let mut keys = Keys::default();
+        for i in 0..MAX_MISSING + 10 {
+            keys.insert(&i.to_string(), Entry::Missing(now));
+        }
+        // capped, the keys over the cap are not remembered
+        assert_eq!(keys.missing, MAX_MISSING);
+        assert_eq!(keys.entries.len(), MAX_MISSING);
+        // a key found later replaces its Missing entry
+        keys.insert("0", Entry::Known(KeyState::default()));
+        assert_eq!(keys.missing, MAX_MISSING - 1);
+        // expired entries are swept once MISSING_TTL has passed
+        keys.insert("new", Entry::Missing(now + MISSING_TTL));
+        assert_eq!(keys.missing, 1);
+        assert_eq!(keys.entries.len(), 2);
+        assert!(matches!(keys.entries.get("0"), Some(Entry::Known(_))));
+        keys.sweep(now + MISSING_TTL * 2);
+        assert_eq!(keys.entries.len(), 1);
+        assert_eq!(keys.missing, 0);
+    }
+}
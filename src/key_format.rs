@@ -0,0 +1,298 @@
+// Licence key formats.
+//
+// `key-format` is the format of generated keys:
+// - `hex`: 32 hex digits, a UUID without dashes, the default
+// - `base32`: XXXXX-XXXXX-XXXXX-XXXXX in Crockford base32, 19 random symbols
+//   and a check symbol, after one of `key-prefixes` if set, e.g. ACME-XXXXX-...
+//
+// `key-formats` are the formats accepted from clients and the admin API,
+// `hex,base32` by default. `any` accepts every non-empty key as before. Other
+// keys are rejected before the database is looked up. The custom keys file
+// accepts keys in any format, see custom_keys.rs.
+//
+// base32 keys are typed by people: they are read in any case, with or without
+// dashes, with I and L as 1 and O as 0, and stored in the canonical form above.
+// The check symbol catches a wrong symbol and most swapped neighbours.
+//
+// hex keys are stored in lower case whatever the accepted formats, keys stored
+// in upper case before are lowered when the database is opened.
+use hbb_common::{bail, ResultType};
+use sodiumoxide::randombytes::randombytes_uniform;
+use std::str::FromStr;
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+// the check symbol is mod 37, with 5 more symbols
+const CHECK_ALPHABET: &[u8; 37] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ*~$=U";
+const BASE32_SYMBOLS: usize = 19;
+const GROUP: usize = 5;
+const HEX_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Hex,
+    Base32,
+    Any,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "hex" | "uuid" => Ok(Format::Hex),
+            "base32" | "crockford" => Ok(Format::Base32),
+            "any" => Ok(Format::Any),
+            _ => Err(format!("unknown key format {}, use hex, base32 or any", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Format {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Format::Hex => "hex",
+            Format::Base32 => "base32",
+            Format::Any => "any",
+        })
+    }
+}
+
+/// Whether `prefix` can be put in front of base32 keys.
+pub fn check_prefix(prefix: &str) -> Result<(), String> {
+    if prefix.is_empty() || prefix.len() > 16 || !prefix.chars().all(|x| x.is_ascii_alphanumeric())
+    {
+        return Err(format!(
+            "invalid key prefix {:?}, use up to 16 letters and digits",
+            prefix
+        ));
+    }
+    Ok(())
+}
+
+struct Rules {
+    generate: Format,
+    accept: Vec<Format>,
+    // upper case
+    prefixes: Vec<String>,
+}
+
+impl Rules {
+    // invalid values are reported by Settings::validate, and ignored here
+    fn current() -> Self {
+        let settings = crate::settings::get();
+        let generate = settings
+            .key_format
+            .as_deref()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(Format::Hex);
+        let mut accept: Vec<Format> = settings
+            .key_formats
+            .iter()
+            .flatten()
+            .filter_map(|x| x.parse().ok())
+            .collect();
+        if accept.is_empty() {
+            accept = vec![Format::Hex, Format::Base32];
+        }
+        let prefixes = settings
+            .key_prefixes
+            .iter()
+            .flatten()
+            .filter(|x| check_prefix(x).is_ok())
+            .map(|x| x.to_uppercase())
+            .collect();
+        Self {
+            generate,
+            accept,
+            prefixes,
+        }
+    }
+
+    fn generate(&self, prefix: Option<&str>) -> ResultType<String> {
+        let prefix = match prefix.map(|x| x.trim().to_uppercase()) {
+            Some(p) if p.is_empty() => None,
+            Some(p) if self.prefixes.contains(&p) => Some(p),
+            Some(p) => bail!("Key prefix {} is not in key-prefixes", p),
+            None => self.prefixes.first().cloned(),
+        };
+        match self.generate {
+            Format::Base32 => Ok(base32(prefix.as_deref())),
+            _ if prefix.is_some() => bail!("Key prefixes need key-format base32"),
+            _ => Ok(uuid::Uuid::new_v4().simple().to_string()),
+        }
+    }
+
+    fn normalize(&self, key: &str) -> Result<String, String> {
+        let mut errors = Vec::new();
+        for format in &self.accept {
+            let res = match format {
+                Format::Hex => normalize_hex(key),
+                Format::Base32 => normalize_base32(key, &self.prefixes),
+                Format::Any if key.trim().is_empty() => Err("empty key".to_owned()),
+                Format::Any => Ok(normalize_hex(key).unwrap_or_else(|_| key.to_owned())),
+            };
+            match res {
+                Ok(key) => return Ok(key),
+                Err(err) => errors.push(format!("{}: {}", format, err)),
+            }
+        }
+        Err(errors.join("; "))
+    }
+}
+
+/// A new key in the `key-format` format, after `prefix` or the first of
+/// `key-prefixes`.
+pub fn generate(prefix: Option<&str>) -> ResultType<String> {
+    Rules::current().generate(prefix)
+}
+
+/// The canonical form of `key` if it is in one of the `key-formats`, else
+/// why not. Keys are stored and looked up in canonical form.
+pub fn normalize(key: &str) -> Result<String, String> {
+    Rules::current().normalize(key)
+}
+
+/// `key` as stored, canonical if in one of the `key-formats`, else as is but
+/// for hex keys, for looking up keys that may have been added in another
+/// format.
+pub fn stored(key: &str) -> String {
+    normalize(key).unwrap_or_else(|_| normalize_hex(key).unwrap_or_else(|_| key.to_owned()))
+}
+
+fn normalize_hex(key: &str) -> Result<String, String> {
+    if key.len() != HEX_LEN || !key.chars().all(|x| x.is_ascii_hexdigit()) {
+        return Err(format!("not {} hex digits", HEX_LEN));
+    }
+    Ok(key.to_lowercase())
+}
+
+fn base32(prefix: Option<&str>) -> String {
+    let symbols: Vec<u8> = (0..BASE32_SYMBOLS)
+        .map(|_| randombytes_uniform(ALPHABET.len() as u32) as u8)
+        .collect();
+    format_base32(prefix, &symbols)
+}
+
+fn check_symbol(symbols: &[u8]) -> u8 {
+    let rem = symbols.iter().fold(0u32, |acc, x| {
+        (acc * 32 + *x as u32) % CHECK_ALPHABET.len() as u32
+    });
+    CHECK_ALPHABET[rem as usize]
+}
+
+// symbols are the values of the base32 digits, without the check symbol
+fn format_base32(prefix: Option<&str>, symbols: &[u8]) -> String {
+    let mut chars: Vec<u8> = symbols.iter().map(|x| ALPHABET[*x as usize]).collect();
+    chars.push(check_symbol(symbols));
+    let mut groups: Vec<String> = prefix.into_iter().map(|x| x.to_owned()).collect();
+    groups.extend(
+        chars
+            .chunks(GROUP)
+            .map(|x| String::from_utf8_lossy(x).into_owned()),
+    );
+    groups.join("-")
+}
+
+fn normalize_base32(key: &str, prefixes: &[String]) -> Result<String, String> {
+    let key = key.trim().to_uppercase();
+    let mut prefix = None;
+    let mut body = key.as_str();
+    for p in prefixes {
+        if let Some(rest) = key
+            .strip_prefix(p.as_str())
+            .and_then(|x| x.strip_prefix('-'))
+        {
+            prefix = Some(p.as_str());
+            body = rest;
+            break;
+        }
+    }
+    let chars: Vec<char> = body.chars().filter(|x| *x != '-').collect();
+    if chars.len() != BASE32_SYMBOLS + 1 {
+        if prefix.is_none() && body.contains('-') && chars.len() > BASE32_SYMBOLS + 1 {
+            return Err("unknown prefix".to_owned());
+        }
+        return Err(format!("not {} symbols", BASE32_SYMBOLS + 1));
+    }
+    let mut symbols = Vec::with_capacity(BASE32_SYMBOLS);
+    for c in &chars[..BASE32_SYMBOLS] {
+        let c = match c {
+            'I' | 'L' => '1',
+            'O' => '0',
+            c => *c,
+        };
+        match ALPHABET.iter().position(|x| *x as char == c) {
+            Some(v) => symbols.push(v as u8),
+            None => return Err(format!("invalid symbol {}", c)),
+        }
+    }
+    let check = match chars[BASE32_SYMBOLS] {
+        'I' | 'L' => '1',
+        'O' => '0',
+        c => c,
+    };
+    if check_symbol(&symbols) as char != check {
+        return Err("check symbol does not match, mistyped?".to_owned());
+    }
+    Ok(format_base32(prefix, &symbols))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(accept: Vec<Format>, prefixes: &[&str]) -> Rules {
+        Rules {
+            generate: Format::Base32,
+            accept,
+            prefixes: prefixes.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_base32() {
+        let rules = rules(vec![Format::Hex, Format::Base32], &["ACME"]);
+        let key = rules.generate(None).unwrap();
+        assert_eq!(key.len(), "ACME-XXXXX-XXXXX-XXXXX-XXXXX".len());
+        assert!(key.starts_with("ACME-"));
+        assert_eq!(rules.normalize(&key).unwrap(), key);
+        // typed loosely
+        let loose = key
+            .to_lowercase()
+            .replace('-', "")
+            .replacen("acme", "acme-", 1);
+        assert_eq!(rules.normalize(&loose).unwrap(), key);
+        let key = rules.generate(Some("")).unwrap();
+        assert_eq!(key.len(), 23);
+        assert_eq!(rules.normalize(&key.replace('1', "l")).unwrap(), key);
+        // one symbol off
+        let mut typo = key.clone().into_bytes();
+        typo[0] = if typo[0] == b'7' { b'8' } else { b'7' };
+        assert!(rules.normalize(&String::from_utf8(typo).unwrap()).is_err());
+        assert!(rules.normalize(&format!("OTHER-{}", key)).is_err());
+        assert!(rules.generate(Some("OTHER")).is_err());
+        assert!(rules.normalize("123").is_err());
+        let hex = uuid::Uuid::new_v4().simple().to_string();
+        assert_eq!(rules.normalize(&hex).unwrap(), hex);
+        assert_eq!(rules.normalize(&hex.to_uppercase()).unwrap(), hex);
+    }
+
+    #[test]
+    fn test_check_symbol() {
+        // 1 * 32 + 4 = 36, the last check symbol
+        assert_eq!(format_base32(None, &[0; 19]), "00000-00000-00000-00000");
+        assert_eq!(check_symbol(&[1, 4]), b'U');
+        assert!(check_prefix("ACME2").is_ok());
+        assert!(check_prefix("AC-ME").is_err());
+        assert_eq!(
+            rules(vec![Format::Any], &[]).normalize("123").unwrap(),
+            "123"
+        );
+        assert!(rules(vec![Format::Any], &[]).normalize(" ").is_err());
+        let hex = "0123456789ABCDEF0123456789ABCDEF";
+        assert_eq!(
+            rules(vec![Format::Any], &[]).normalize(hex).unwrap(),
+            hex.to_lowercase()
+        );
+    }
+}
@@ -0,0 +1,566 @@
+// Licence keys in bulk: generation, import and export.
+//
+// Imports are CSV with a header row or a JSON array of objects, with the
+// columns (fields):
+// - key: required, in one of the accepted key formats, see key_format
+// - expired_at: unix time, RFC 3339, YYYY-MM-DD (the end of that day, UTC) or
+//   permanent, 30 days from now if empty
+// - active: 1/0, true/false or Y/N, active if empty
+// - note, a leading ' is dropped in CSV if a formula character follows, as
+//   exports add it against formula injection
+// - max_bind_ids: 1 to 1000, 3 if empty
+// - bindings: peer IDs bound to the key, separated by ';' in CSV, none of them
+//   bound to another key already or in an earlier row
+// Other columns are ignored, so an export can be imported elsewhere.
+//
+// Every row is checked first, keys that exist already included, and reported
+// with its error. The valid rows are then inserted in one transaction, unless
+// it is a dry run.
+use crate::database::{Database, NewKey};
+use crate::key_format;
+use hbb_common::{bail, ResultType};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The most keys generated at once.
+pub const MAX_BULK: usize = 1000;
+/// expired_at of permanent keys.
+pub const PERMANENT: i64 = i64::MAX / 2;
+pub const DEFAULT_TTL: i64 = 86400 * 30;
+pub const DEFAULT_MAX_BIND: i64 = 3;
+const MAX_BIND: i64 = 1000;
+const COLUMNS: &[&str] = &[
+    "key",
+    "registered_at",
+    "expired_at",
+    "active",
+    "note",
+    "max_bind_ids",
+    "bindings",
+];
+// spreadsheets evaluate cells starting with these
+const FORMULA_CHARS: &[char] = &['=', '+', '-', '@', '\t', '\r'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {}, use csv or json", s)),
+        }
+    }
+}
+
+impl Format {
+    /// JSON if `text` starts like JSON, else CSV.
+    pub fn detect(text: &str) -> Self {
+        match text.trim_start().chars().next() {
+            Some('[') | Some('{') => Format::Json,
+            _ => Format::Csv,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RowReport {
+    // 1 based, the header of CSV not counted
+    pub row: usize,
+    pub key: String,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total: usize,
+    pub valid: usize,
+    pub imported: usize,
+    pub rows: Vec<RowReport>,
+}
+
+impl ImportReport {
+    /// The keys inserted.
+    pub fn imported_keys(&self) -> impl Iterator<Item = &str> {
+        let imported = self.imported > 0;
+        self.rows
+            .iter()
+            .filter(move |x| imported && x.error.is_none())
+            .map(|x| x.key.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExportKey {
+    key: String,
+    registered_at: i64,
+    expired_at: i64,
+    active: bool,
+    note: Option<String>,
+    max_bind_ids: i64,
+    bindings: Vec<String>,
+}
+
+/// Generate and insert `count` keys, returns them.
+pub async fn bulk_create(
+    db: &Database,
+    count: usize,
+    expired_at: i64,
+    note: Option<&str>,
+    max_bind_ids: i64,
+    prefix: Option<&str>,
+) -> ResultType<Vec<String>> {
+    if count == 0 || count > MAX_BULK {
+        bail!("count must be 1 to {}", MAX_BULK);
+    }
+    if !(1..=MAX_BIND).contains(&max_bind_ids) {
+        bail!("max_bind_ids must be 1 to {}", MAX_BIND);
+    }
+    let mut seen = HashSet::new();
+    let mut keys = Vec::with_capacity(count);
+    while keys.len() < count {
+        let key = key_format::generate(prefix)?;
+        if !seen.insert(key.clone()) || db.key_exists(&key).await? {
+            continue;
+        }
+        keys.push(NewKey {
+            key,
+            expired_at,
+            active: true,
+            note: note.map(|x| x.to_owned()),
+            max_bind_ids,
+            bindings: Vec::new(),
+        });
+    }
+    db.insert_keys(&keys).await?;
+    Ok(keys.into_iter().map(|x| x.key).collect())
+}
+
+/// Check every row of `text` and insert the valid ones unless `dry_run`.
+pub async fn import(
+    db: &Database,
+    text: &str,
+    format: Format,
+    dry_run: bool,
+) -> ResultType<ImportReport> {
+    let now = chrono::Utc::now().timestamp();
+    let mut report = ImportReport {
+        dry_run,
+        ..Default::default()
+    };
+    let mut seen = HashSet::new();
+    let mut keys = Vec::new();
+    // peer ID -> the key it is bound to
+    let mut bound: HashMap<String, String> = db
+        .list_bindings(None)
+        .await?
+        .into_iter()
+        .map(|(key, peer_id)| (peer_id, key))
+        .collect();
+    for (i, row) in read_rows(text, format)?.into_iter().enumerate() {
+        let raw_key = row
+            .as_ref()
+            .ok()
+            .and_then(|x| x.get("key"))
+            .map(value_str)
+            .unwrap_or_default();
+        let mut res = row.and_then(|x| parse_row(&x, now));
+        if let Ok(k) = &res {
+            if !seen.insert(k.key.clone()) {
+                res = Err("duplicate key in the file".to_owned());
+            } else if db.key_exists(&k.key).await? {
+                res = Err("key exists".to_owned());
+            } else if let Err(err) = check_bindings(k, &bound) {
+                res = Err(err);
+            }
+        }
+        let (key, error) = match res {
+            Ok(k) => {
+                let key = k.key.clone();
+                for peer_id in k.bindings.iter() {
+                    bound.insert(peer_id.clone(), key.clone());
+                }
+                keys.push(k);
+                (key, None)
+            }
+            Err(err) => (raw_key, Some(err)),
+        };
+        report.rows.push(RowReport {
+            row: i + 1,
+            key,
+            error,
+        });
+    }
+    report.total = report.rows.len();
+    report.valid = keys.len();
+    if !dry_run && !keys.is_empty() {
+        db.insert_keys(&keys).await?;
+        report.imported = keys.len();
+    }
+    Ok(report)
+}
+
+/// Every key with its bindings.
+pub async fn export(db: &Database, format: Format) -> ResultType<String> {
+    let mut bindings: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (key, peer_id) in db.list_bindings(None).await? {
+        bindings.entry(key).or_default().push(peer_id);
+    }
+    let mut keys: Vec<ExportKey> = db
+        .list_all_keys()
+        .await?
+        .into_iter()
+        .map(|x| ExportKey {
+            bindings: bindings.remove(&x.licence_key).unwrap_or_default(),
+            key: x.licence_key,
+            registered_at: x.registered_at,
+            expired_at: x.expired_at,
+            active: x.active != 0,
+            note: x.note,
+            max_bind_ids: x.max_bind_ids,
+        })
+        .collect();
+    keys.sort_by(|a, b| (a.registered_at, &a.key).cmp(&(b.registered_at, &b.key)));
+    for x in keys.iter_mut() {
+        x.bindings.sort();
+    }
+    match format {
+        Format::Json => Ok(serde_json::to_string_pretty(&keys)?),
+        Format::Csv => {
+            let mut w = csv::Writer::from_writer(Vec::new());
+            w.write_record(COLUMNS)?;
+            for x in keys.iter() {
+                w.write_record([
+                    x.key.clone(),
+                    x.registered_at.to_string(),
+                    x.expired_at.to_string(),
+                    if x.active { "1" } else { "0" }.to_owned(),
+                    csv_cell(x.note.as_deref().unwrap_or_default()),
+                    x.max_bind_ids.to_string(),
+                    x.bindings.join(";"),
+                ])?;
+            }
+            Ok(String::from_utf8(
+                w.into_inner().map_err(|e| e.into_error())?,
+            )?)
+        }
+    }
+}
+
+// the rows as JSON objects, CSV cells as strings
+fn read_rows(text: &str, format: Format) -> ResultType<Vec<Result<Map<String, Value>, String>>> {
+    match format {
+        Format::Json => {
+            let rows: Vec<Value> = match serde_json::from_str(text)? {
+                Value::Array(rows) => rows,
+                _ => bail!("Expected a JSON array of keys"),
+            };
+            Ok(rows
+                .into_iter()
+                .map(|x| match x {
+                    Value::Object(x) => Ok(x),
+                    _ => Err("not an object".to_owned()),
+                })
+                .collect())
+        }
+        Format::Csv => {
+            let mut r = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(text.as_bytes());
+            let headers: Vec<String> = r.headers()?.iter().map(|x| x.to_lowercase()).collect();
+            if !headers.iter().any(|x| x == "key") {
+                bail!("The CSV header has no key column");
+            }
+            Ok(r.records()
+                .map(|x| match x {
+                    Ok(x) => Ok(headers
+                        .iter()
+                        .zip(x.iter())
+                        .map(|(k, v)| {
+                            let v = if k == "note" { csv_uncell(v) } else { v };
+                            (k.clone(), Value::String(v.to_owned()))
+                        })
+                        .collect()),
+                    Err(err) => Err(err.to_string()),
+                })
+                .collect())
+        }
+    }
+}
+
+// quote a cell a spreadsheet would take for a formula
+fn csv_cell(s: &str) -> String {
+    if s.starts_with(FORMULA_CHARS) {
+        format!("'{s}")
+    } else {
+        s.to_owned()
+    }
+}
+
+// undo csv_cell
+fn csv_uncell(s: &str) -> &str {
+    match s.strip_prefix('\'') {
+        Some(x) if x.starts_with(FORMULA_CHARS) => x,
+        _ => s,
+    }
+}
+
+// the first binding that belongs to another key in `bound` (peer ID -> key)
+fn check_bindings(k: &NewKey, bound: &HashMap<String, String>) -> Result<(), String> {
+    for peer_id in k.bindings.iter() {
+        match bound.get(peer_id) {
+            Some(other) if *other != k.key => {
+                return Err(format!("{peer_id} is bound to another key {other}"));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn value_str(v: &Value) -> String {
+    match v {
+        Value::String(x) => x.trim().to_owned(),
+        Value::Null => String::new(),
+        x => x.to_string(),
+    }
+}
+
+fn parse_row(row: &Map<String, Value>, now: i64) -> Result<NewKey, String> {
+    let get = |name: &str| row.get(name).map(value_str).unwrap_or_default();
+    let key = get("key");
+    if key.is_empty() {
+        return Err("no key".to_owned());
+    }
+    let key = key_format::normalize(&key)?;
+    let expired_at = match get("expired_at") {
+        x if x.is_empty() => now + DEFAULT_TTL,
+        x => parse_expiry(&x).ok_or_else(|| format!("invalid expired_at {}", x))?,
+    };
+    let active = match get("active").to_lowercase().as_str() {
+        "" | "1" | "true" | "y" | "yes" => true,
+        "0" | "false" | "n" | "no" => false,
+        x => return Err(format!("invalid active {}", x)),
+    };
+    let note = Some(get("note")).filter(|x| !x.is_empty());
+    let max_bind_ids = match get("max_bind_ids") {
+        x if x.is_empty() => DEFAULT_MAX_BIND,
+        x => match x.parse::<i64>() {
+            Ok(n) if (1..=MAX_BIND).contains(&n) => n,
+            _ => return Err(format!("max_bind_ids must be 1 to {}, got {}", MAX_BIND, x)),
+        },
+    };
+    let bindings: Vec<String> = match row.get("bindings") {
+        Some(Value::Array(x)) => x.iter().map(value_str).filter(|x| !x.is_empty()).collect(),
+        Some(x) => value_str(x)
+            .split(|c: char| c == ';' || c == ',' || c.is_whitespace())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect(),
+        None => Vec::new(),
+    };
+    if bindings.len() as i64 > max_bind_ids {
+        return Err(format!(
+            "{} bindings, more than max_bind_ids {}",
+            bindings.len(),
+            max_bind_ids
+        ));
+    }
+    Ok(NewKey {
+        key,
+        expired_at,
+        active,
+        note,
+        max_bind_ids,
+        bindings,
+    })
+}
+
+fn parse_expiry(s: &str) -> Option<i64> {
+    if s.eq_ignore_ascii_case("permanent") {
+        return Some(PERMANENT);
+    }
+    if let Ok(x) = s.parse::<i64>() {
+        return Some(x);
+    }
+    if let Ok(x) = chrono::DateTime::parse_from_rfc3339(s) {
+        return Some(x.timestamp());
+    }
+    let date = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(23, 59, 59)?.and_utc().timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hbb_common::tokio;
+
+    const HEX: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_parse_row() {
+        let rows = read_rows(
+            &format!(
+                "key,expired_at,active,note,max_bind_ids,bindings\n\
+                 {HEX},2026-12-31,N,acme,2,123456789;987654321\n\
+                 {HEX},,,,,\n\
+                 123,,,,,\n\
+                 {HEX},tomorrow,,,,\n\
+                 {HEX},,,,1,a b\n"
+            ),
+            Format::Csv,
+        )
+        .unwrap();
+        let rows: Vec<_> = rows
+            .into_iter()
+            .map(|x| x.and_then(|x| parse_row(&x, 0)))
+            .collect();
+        assert_eq!(
+            rows[0],
+            Ok(NewKey {
+                key: HEX.to_owned(),
+                expired_at: 1798761599,
+                active: false,
+                note: Some("acme".to_owned()),
+                max_bind_ids: 2,
+                bindings: vec!["123456789".to_owned(), "987654321".to_owned()],
+            })
+        );
+        let k = rows[1].as_ref().unwrap();
+        assert_eq!((k.expired_at, k.active), (DEFAULT_TTL, true));
+        assert_eq!(k.max_bind_ids, DEFAULT_MAX_BIND);
+        assert!(rows[2].is_err());
+        assert!(rows[3].is_err());
+        assert!(rows[4].is_err());
+    }
+
+    #[test]
+    fn test_read_json() {
+        let rows = read_rows(
+            &format!(
+                r#"[{{"key": "{HEX}", "expired_at": {PERMANENT}, "active": true,
+                     "max_bind_ids": 5, "bindings": ["123456789"]}}, 1]"#
+            ),
+            Format::Json,
+        )
+        .unwrap();
+        let k = parse_row(rows[0].as_ref().unwrap(), 0).unwrap();
+        assert_eq!(
+            (k.expired_at, k.active, k.max_bind_ids),
+            (PERMANENT, true, 5)
+        );
+        assert_eq!(k.bindings, vec!["123456789"]);
+        assert!(rows[1].is_err());
+        assert!(read_rows("{}", Format::Json).is_err());
+        assert!(read_rows("id,note\n", Format::Csv).is_err());
+        assert_eq!(Format::detect(" [ ]"), Format::Json);
+        assert_eq!(Format::detect("key\n"), Format::Csv);
+        assert_eq!(parse_expiry("1970-01-01T00:01:00Z"), Some(60));
+    }
+
+    #[test]
+    fn test_csv_cell() {
+        assert_eq!(csv_cell("=1+2"), "'=1+2");
+        assert_eq!(csv_cell("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_cell("-x"), "'-x");
+        assert_eq!(csv_cell("\t=1"), "'\t=1");
+        assert_eq!(csv_cell("\r=1"), "'\r=1");
+        assert_eq!(csv_cell("a=b"), "a=b");
+        assert_eq!(csv_cell(""), "");
+        assert_eq!(csv_uncell("'+x"), "+x");
+        assert_eq!(csv_uncell("'x"), "'x");
+        let rows = read_rows(
+            &format!("key,note\n{HEX},'=cmd\n{HEX},'quoted\n"),
+            Format::Csv,
+        )
+        .unwrap();
+        let notes: Vec<_> = rows
+            .iter()
+            .map(|x| parse_row(x.as_ref().unwrap(), 0).unwrap().note)
+            .collect();
+        assert_eq!(
+            notes,
+            vec![Some("=cmd".to_owned()), Some("'quoted".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_check_bindings() {
+        let bound: HashMap<String, String> = [("123456789".to_owned(), "other".to_owned())]
+            .into_iter()
+            .collect();
+        let mut k = NewKey {
+            key: HEX.to_owned(),
+            bindings: vec!["987654321".to_owned()],
+            ..Default::default()
+        };
+        assert_eq!(check_bindings(&k, &bound), Ok(()));
+        k.bindings.push("123456789".to_owned());
+        assert_eq!(
+            check_bindings(&k, &bound),
+            Err("123456789 is bound to another key other".to_owned())
+        );
+        k.key = "other".to_owned();
+        assert_eq!(check_bindings(&k, &bound), Ok(()));
+    }
+
+    #[test]
+    fn test_import() {
+        import_export();
+    }
+
+    #[tokio::main(flavor = "current_thread")]
+    async fn import_export() {
+        let path = std::env::temp_dir().join(format!("key_io_{}.sqlite3", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let db = Database::new(path.to_str().unwrap()).await.unwrap();
+        let key = |i: usize| format!("{}{i}", &HEX[1..]);
+        db.insert_keys(&[NewKey {
+            key: key(0),
+            note: Some("=HYPERLINK(\"x\")".to_owned()),
+            max_bind_ids: 3,
+            bindings: vec!["111111111".to_owned()],
+            ..Default::default()
+        }])
+        .await
+        .unwrap();
+        let text = format!(
+            "key,bindings\n{},111111111\n{},222222222\n{},222222222\n",
+            key(1),
+            key(2),
+            key(3)
+        );
+        let report = import(&db, &text, Format::Csv, true).await.unwrap();
+        let errors: Vec<_> = report.rows.iter().map(|x| x.error.clone()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                Some(format!("111111111 is bound to another key {}", key(0))),
+                None,
+                Some(format!("222222222 is bound to another key {}", key(2))),
+            ]
+        );
+        assert_eq!((report.valid, report.imported), (1, 0));
+        let report = import(&db, &text, Format::Csv, false).await.unwrap();
+        assert_eq!(report.imported_keys().collect::<Vec<_>>(), vec![key(2)]);
+        let csv = export(&db, Format::Csv).await.unwrap();
+        assert!(csv.contains(r#","'=HYPERLINK(""x"")",3,111111111"#));
+        std::fs::remove_file(&path).ok();
+    }
+}
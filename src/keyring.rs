@@ -0,0 +1,325 @@
+// Server key rotation: a primary key and staged keys, all of them active.
+//
+// The primary key is the one of `--key` or `id_ed25519` as before. Staged keys
+// are `id_ed25519.<name>` (and `.pub`) in the data directory, created with
+// `rustdesk-utils serverkey stage`. hbbr accepts the public key of any active
+// key as licence key, hbbs only licence keys of its database. hbbs signs the
+// public keys of peers (IdPk) with the key named by `sign-key`, the primary key
+// by default.
+//
+// Rotation: stage a new key, hand its public key to clients, switch
+// `sign-key` to it once they have it, then promote it to primary and unset
+// `sign-key`. SIGHUP reads the keys and `sign-key` again.
+use crate::common::{check_key_file, data_path, write_key_file};
+use hbb_common::{bail, log, ResultType};
+use sodiumoxide::crypto::sign;
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+pub const PRIMARY: &str = "primary";
+const KEY_FILE: &str = "id_ed25519";
+
+#[derive(Clone)]
+pub struct Key {
+    pub name: String,
+    pub pk: String,
+    sk: Option<sign::SecretKey>,
+}
+
+#[derive(Clone, Default)]
+pub struct Keyring {
+    // the primary key first
+    keys: Vec<Key>,
+    // index of the key IdPk is signed with
+    signing: usize,
+    // the primary key is the one of id_ed25519, read again on reload
+    primary_file: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref KEYRING: RwLock<Arc<Keyring>> = Default::default();
+}
+
+impl Keyring {
+    fn load(primary: Key, primary_file: bool, sign_key: &str) -> ResultType<Self> {
+        let mut keys = vec![primary];
+        for (name, path) in staged()? {
+            let (pk, sk) = read_key(&path)?;
+            keys.push(Key {
+                name,
+                pk,
+                sk: Some(sk),
+            });
+        }
+        let signing = if sign_key.is_empty() {
+            0
+        } else {
+            match keys.iter().position(|x| x.name == sign_key) {
+                Some(i) if keys[i].sk.is_some() => i,
+                Some(_) => bail!("sign-key: {} has no private key", sign_key),
+                None => bail!("sign-key: no key {}", sign_key),
+            }
+        };
+        Ok(Self {
+            keys,
+            signing,
+            primary_file,
+        })
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    /// Whether `pk` is the public key of an active key.
+    pub fn contains(&self, pk: &str) -> bool {
+        !pk.is_empty() && self.keys.iter().any(|x| x.pk == pk)
+    }
+
+    /// The key IdPk is signed with.
+    pub fn signing_key(&self) -> Option<&sign::SecretKey> {
+        self.keys.get(self.signing).and_then(|x| x.sk.as_ref())
+    }
+
+    fn log(&self) {
+        for (i, key) in self.keys.iter().enumerate() {
+            if key.pk.is_empty() {
+                continue;
+            }
+            let signing = if i == self.signing { ", signing" } else { "" };
+            log::info!("Active key {}: {}{}", key.name, key.pk, signing);
+        }
+    }
+}
+
+/// Load the staged keys next to the primary key, `pk` or the one of `sk`.
+pub fn init(pk: &str, sk: Option<sign::SecretKey>) -> ResultType<Arc<Keyring>> {
+    let pk = match &sk {
+        Some(sk) => base64::encode(&sk.0[sign::SECRETKEYBYTES / 2..]),
+        None => pk.to_owned(),
+    };
+    let primary = Key {
+        name: PRIMARY.to_owned(),
+        pk: pk.clone(),
+        sk,
+    };
+    let primary_file = read_primary().map_or(false, |x| x.pk == pk);
+    let keyring = Arc::new(Keyring::load(primary, primary_file, &sign_key())?);
+    keyring.log();
+    *KEYRING.write().unwrap() = keyring.clone();
+    Ok(keyring)
+}
+
+#[inline]
+pub fn get() -> Arc<Keyring> {
+    KEYRING.read().unwrap().clone()
+}
+
+/// Read the staged keys and `sign-key` again, keeping the keyring on errors.
+pub fn reload() {
+    let old = get();
+    let Some(mut primary) = old.keys.first().cloned() else {
+        return;
+    };
+    // promoted in the meantime
+    if old.primary_file {
+        if let Some(key) = read_primary() {
+            primary = key;
+        }
+    }
+    match Keyring::load(primary, old.primary_file, &sign_key()) {
+        Ok(keyring) => {
+            keyring.log();
+            *KEYRING.write().unwrap() = Arc::new(keyring);
+        }
+        Err(err) => log::error!("Keys not reloaded: {}", err),
+    }
+}
+
+fn read_primary() -> Option<Key> {
+    let (pk, sk) = read_key(&key_path(PRIMARY)).ok()?;
+    Some(Key {
+        name: PRIMARY.to_owned(),
+        pk,
+        sk: Some(sk),
+    })
+}
+
+fn sign_key() -> String {
+    crate::settings::get().sign_key.clone().unwrap_or_default()
+}
+
+fn check_name(name: &str) -> ResultType<()> {
+    if name.is_empty()
+        || name == PRIMARY
+        || name == "pub"
+        || !name
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+    {
+        bail!("Invalid key name {:?}, use letters, digits, - and _", name);
+    }
+    Ok(())
+}
+
+fn key_path(name: &str) -> String {
+    if name == PRIMARY {
+        data_path(KEY_FILE)
+    } else {
+        data_path(&format!("{}.{}", KEY_FILE, name))
+    }
+}
+
+fn parse_sk(s: &str) -> Option<(String, sign::SecretKey)> {
+    let sk = crate::server_key::parse(s).ok()?;
+    let pk = base64::encode(&sk.0[sign::SECRETKEYBYTES / 2..]);
+    Some((pk, sk))
+}
+
+fn read_key(path: &str) -> ResultType<(String, sign::SecretKey)> {
+    check_key_file(path)?;
+    match parse_sk(&std::fs::read_to_string(path)?) {
+        Some(v) => Ok(v),
+        None => bail!("Malformed private key in {}", path),
+    }
+}
+
+// (name, path) of the staged keys, by name
+fn staged() -> ResultType<Vec<(String, String)>> {
+    let prefix = format!("{}.", KEY_FILE);
+    let mut res = Vec::new();
+    for entry in std::fs::read_dir(data_path("."))? {
+        let file = entry?.file_name().to_string_lossy().to_string();
+        let Some(name) = file.strip_prefix(&prefix) else {
+            continue;
+        };
+        if name.ends_with(".pub") || check_name(name).is_err() {
+            continue;
+        }
+        res.push((name.to_owned(), key_path(name)));
+    }
+    res.sort();
+    Ok(res)
+}
+
+fn write_key(name: &str, sk: &sign::SecretKey) -> ResultType<String> {
+    let path = key_path(name);
+    let pk = base64::encode(&sk.0[sign::SECRETKEYBYTES / 2..]);
+    write_key_file(&path, base64::encode(sk).as_bytes())?;
+    std::fs::write(format!("{}.pub", path), &pk)?;
+    Ok(pk)
+}
+
+/// Generate the staged key `name`, returns its public key.
+pub fn stage(name: &str) -> ResultType<String> {
+    check_name(name)?;
+    let path = key_path(name);
+    if Path::new(&path).exists() {
+        bail!("{} exists", path);
+    }
+    // like gen_sk, public keys without '/' and ':' are easier to pass around
+    let mut sk = sign::gen_keypair().1;
+    for _ in 0..300 {
+        let pk = base64::encode(&sk.0[sign::SECRETKEYBYTES / 2..]);
+        if !pk.contains('/') && !pk.contains(':') {
+            break;
+        }
+        sk = sign::gen_keypair().1;
+    }
+    write_key(name, &sk)
+}
+
+/// Make the staged key `name` the primary key, the primary key is kept as
+/// the staged key `retire_as`. The staged key is removed only once the
+/// primary key is replaced, see write_key_file.
+pub fn promote(name: &str, retire_as: &str) -> ResultType<()> {
+    check_name(name)?;
+    check_name(retire_as)?;
+    let (_, sk) = read_key(&key_path(name))?;
+    let primary = key_path(PRIMARY);
+    if Path::new(&primary).exists() {
+        if Path::new(&key_path(retire_as)).exists() {
+            bail!("{} exists", key_path(retire_as));
+        }
+        let (_, old) = read_key(&primary)?;
+        write_key(retire_as, &old)?;
+    }
+    write_key(PRIMARY, &sk)?;
+    std::fs::remove_file(key_path(name))?;
+    std::fs::remove_file(format!("{}.pub", key_path(name))).ok();
+    Ok(())
+}
+
+/// (name, public key) of the keys on disk, the primary key first.
+pub fn list() -> ResultType<Vec<(String, String)>> {
+    let mut res = Vec::new();
+    let primary = key_path(PRIMARY);
+    if Path::new(&primary).exists() {
+        res.push((PRIMARY.to_owned(), read_key(&primary)?.0));
+    }
+    for (name, path) in staged()? {
+        res.push((name, read_key(&path)?.0));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("2026-10_next").is_ok());
+        for name in ["", "primary", "pub", "a.b", "a/b", "../x"] {
+            assert!(check_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_keyring() {
+        let (pk0, sk) = sign::gen_keypair();
+        let (pk, sk) = parse_sk(&base64::encode(sk)).unwrap();
+        assert_eq!(pk, base64::encode(pk0));
+        let keyring = Keyring {
+            keys: vec![
+                Key {
+                    name: PRIMARY.to_owned(),
+                    pk: "old".to_owned(),
+                    sk: None,
+                },
+                Key {
+                    name: "next".to_owned(),
+                    pk: pk.clone(),
+                    sk: Some(sk),
+                },
+            ],
+            signing: 1,
+            primary_file: false,
+        };
+        assert!(keyring.contains("old"));
+        assert!(keyring.contains(&pk));
+        assert!(!keyring.contains(""));
+        assert!(keyring.signing_key().is_some());
+        assert!(parse_sk("garbage").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_key_file() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = std::env::temp_dir().join(format!("id_ed25519_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "old").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(check_key_file(path).is_err());
+        write_key_file(path, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "new");
+        assert!(check_key_file(path).is_ok());
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!Path::new(&format!("{}.tmp~", path)).exists());
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -3,9 +3,28 @@ pub use rendezvous_server::*;
 pub mod common;
 mod database;
 pub use database::*;
+mod key_cache;
+pub use key_cache::*;
 mod peer;
 mod version;
 mod custom_keys;
 pub use custom_keys::*;
+pub mod control;
+pub mod ip_list;
 mod admin;
 pub use admin::*;
+mod cluster;
+mod geo;
+mod policy;
+pub mod proxy_protocol;
+pub mod tls;
+pub mod settings;
+pub mod shutdown;
+pub mod handoff;
+pub mod systemd;
+pub mod keyring;
+pub mod key_format;
+pub mod key_io;
+mod server_key;
+mod relay_status;
+pub use relay_status::{RelayStatus, STATUS_PORT_OFFSET};
@@ -14,18 +14,54 @@ fn main() -> ResultType<()> {
         .write_mode(WriteMode::Async)
         .start()?;
     let args = format!(
-        "-c --config=[FILE] +takes_value 'Sets a custom config file'
+        "-c --config=[FILE] +takes_value 'Sets a config file, TOML (.toml) with [common] and [rendezvous] sections, otherwise INI with the option names. Its options override .env, the environment and command line override it'
         -p, --port=[NUMBER(default={RENDEZVOUS_PORT})] 'Sets the listening port'
+        -d, --data-dir=[DIR] 'Sets the directory of the key, database and other files with relative paths (default: the working directory)'
         -s, --serial=[NUMBER(default=0)] 'Sets configure update serial number'
         -R, --rendezvous-servers=[HOSTS] 'Sets rendezvous servers, separated by comma'
         -u, --software-url=[URL] 'Sets download url of RustDesk software of newest version'
         -r, --relay-servers=[HOST] 'Sets the default relay servers, separated by comma'
         -M, --rmem=[NUMBER(default={RMEM})] 'Sets UDP recv buffer size, set system rmem_max first, e.g., sudo sysctl -w net.core.rmem_max=52428800. vi /etc/sysctl.conf, net.core.rmem_max=52428800, sudo sysctl –p'
-        , --mask=[MASK] 'Determine if the connection comes from LAN, e.g. 192.168.0.0/16'
+        , --udp-workers=[NUMBER] 'Sets the number of UDP worker tasks (default: number of CPUs)'
+        , --udp-sockets=[NUMBER(default=1)] 'Sets the number of UDP sockets bound to the port with SO_REUSEPORT'
+        , --cluster-nodes=[HOSTS] 'Sets the cluster addresses of the other hbbs nodes sharing peers, separated by comma'
+        , --cluster-port=[NUMBER] 'Sets the udp port for cluster messages (default: port + 4)'
+        , --cluster-secret=[SECRET] 'Sets the secret shared by the cluster nodes to authenticate cluster messages, required with --cluster-nodes'
+        , --geo-db=[FILE] 'Sets the GeoIP database (MaxMind MMDB) used to select relay servers (default: GeoLite2-City.mmdb)'
+        , --relay-meta=[FILE] 'Sets the relay metadata file with region and capacity of relay servers (default: relay_meta.json)'
+        , --relay-saturation=[RATIO] 'Sets the load reported by a relay server above which it is not used (default: 0.95)'
+        , --allow-list=[FILE] 'Sets the file of IPs and networks that are never rate limited (default: allowlist.txt)'
+        , --deny-list=[FILE] 'Sets the file of IPs and networks that are refused (default: denylist.txt)'
+        , --rate-limits=[LIMITS] 'Sets per IP rate limits as <kind>=<per second>/<burst>, separated by comma, kinds: connection, register-peer, register-pk, punch-hole, request-relay, online (default: none)'
+        , --max-connections=[NUMBER] 'Sets the maximum of concurrent TCP/WebSocket connections (default: 0, unlimited)'
+        , --block-strikes=[NUMBER] 'Sets how often an IP may exceed its limits within a minute before it is blocked (default: 100)'
+        , --block-duration=[SECONDS] 'Sets the first block of an IP, doubled for each repeat up to a day (default: 60)'
+        , --lookup-limit=[NUMBER] 'Sets how many distinct IDs an IP may look up per hour with punch hole and online requests (default: 0, unlimited)'
+        , --online-max-ids=[NUMBER] 'Sets how many IDs of an online request are answered (default: 0, unlimited)'
+        , --online-auth=[Y|N] 'Only answers online requests of IDs currently registered from the same IP (default: N)'
+        , --uniform-failure=[Y|N] 'Reports unknown IDs as offline to punch hole requests (default: N)'
+        , --proxy-protocol=[Y|N] 'Accepts a PROXY protocol (v1/v2) header on TCP connections from the trusted proxies, which must be set (default: N)'
+        , --trusted-proxies=[CIDRS] 'Sets the proxies whose PROXY protocol and X-Real-IP/X-Forwarded-For headers are trusted, separated by comma'
+        , --tls-cert=[FILE] 'Sets the TLS certificate chain (PEM) for WebSocket connections (wss://), reloaded when it changes'
+        , --tls-key=[FILE] 'Sets the TLS private key (PEM) for WebSocket connections'
+        , --wss-port=[NUMBER] 'Sets a separate port for TLS WebSocket connections (default: the WebSocket port is TLS only)'
+        , --drain-timeout=[SECONDS] 'Sets how long pending replies may take on shutdown before exiting (default: 30)'
+        , --mask=[MASK] 'Determine if the connection comes from LAN, IPv4 and IPv6 networks separated by comma, e.g. 192.168.0.0/16,fd00::/8'
+        , --lan-sites=[SITES] 'Sets LAN sites with their own relay as <network>=<relay>, separated by comma, e.g. 10.1.0.0/16=10.1.0.5,10.2.0.0/16=10.2.0.5. Each LAN peer is given the relay of its own site'
+        , --local-ip=[IP] 'Sets the relay address given to IPv4 LAN peers (default: local IPv4 address)'
+        , --local-ip6=[IP] 'Sets the relay address given to IPv6 LAN peers (default: local IPv6 address)'
         -k, --key=[KEY] 'Only allow the client with the same key'
-        , --custom-keys-file=[FILE] 'Sets custom keys file path (default: custom_keys.json)'",
+        , --key-file=[FILE] 'Reads the private key from a file, base64, PKCS#8 PEM or OpenSSH (instead of --key, which the process list shows)'
+        , --key-stdin 'Reads the private key from the first line of stdin'
+        , --sign-key=[NAME] 'Sets the key peer public keys are signed with, the name of a key staged with rustdesk-utils serverkey (default: primary)'
+        , --custom-keys-file=[FILE] 'Sets custom keys file path (default: custom_keys.json)'
+        , --key-format=[FORMAT] 'Sets the format of generated licence keys: hex or base32, XXXXX-XXXXX-XXXXX-XXXXX with a check symbol (default: hex)'
+        , --key-formats=[FORMATS] 'Sets the licence key formats accepted, separated by comma, any accepts every key (default: hex,base32)'
+        , --key-prefixes=[PREFIXES] 'Sets the reseller prefixes of base32 licence keys, separated by comma, the first is the default'",
     );
     init_args(&args, "hbbs", "RustDesk ID/Rendezvous Server");
+    settings::load_dotenv();
+    settings::init(settings::Server::Hbbs, &get_arg("config"))?;
     let port = get_arg_or("port", RENDEZVOUS_PORT.to_string()).parse::<i32>()?;
     if port < 3 {
         bail!("Invalid port");
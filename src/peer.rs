@@ -1,5 +1,6 @@
 use crate::common::*;
 use crate::database;
+use crate::key_cache::KeyCache;
 use hbb_common::{
     bytes::Bytes,
     log,
@@ -63,28 +64,18 @@ pub(crate) type LockPeer = Arc<RwLock<Peer>>;
 pub(crate) struct PeerMap {
     map: Arc<RwLock<HashMap<String, LockPeer>>>,
     pub(crate) db: database::Database,
+    pub(crate) keys: KeyCache,
 }
 
 impl PeerMap {
     pub(crate) async fn new() -> ResultType<Self> {
-        let db = std::env::var("DB_URL").unwrap_or({
-            let mut db = "db_v2.sqlite3".to_owned();
-            #[cfg(all(windows, not(debug_assertions)))]
-            {
-                if let Some(path) = hbb_common::config::Config::icon_path().parent() {
-                    db = format!("{}\\{}", path.to_str().unwrap_or("."), db);
-                }
-            }
-            #[cfg(not(windows))]
-            {
-                db = format!("./{db}");
-            }
-            db
-        });
+        let db = database::Database::default_url();
         log::info!("DB_URL={}", db);
+        let db = database::Database::new(&db).await?;
         let pm = Self {
             map: Default::default(),
-            db: database::Database::new(&db).await?,
+            keys: KeyCache::new(db.clone()).await?,
+            db,
         };
         Ok(pm)
     }
@@ -173,6 +164,20 @@ impl PeerMap {
         self.map.read().await.get(id).cloned()
     }
 
+    /// Peers registered within `timeout` ms, with their socket address
+    pub(crate) async fn online_peers(&self, timeout: i32) -> Vec<(String, SocketAddr)> {
+        let mut peers = Vec::new();
+        for (id, peer) in self.map.read().await.iter() {
+            let peer = peer.read().await;
+            if peer.socket_addr.port() != 0
+                && (peer.last_reg_time.elapsed().as_millis() as i32) < timeout
+            {
+                peers.push((id.clone(), peer.socket_addr));
+            }
+        }
+        peers
+    }
+
     #[inline]
     pub(crate) async fn is_in_memory(&self, id: &str) -> bool {
         self.map.read().await.contains_key(id)
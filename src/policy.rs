@@ -0,0 +1,721 @@
+// Admission policy of hbbs: static allow/deny lists, per IP rate limits and
+// a cap on concurrent connections.
+//
+// Addresses on the allow list are never limited. Addresses on the deny list
+// are dropped. With rate-limits set, every other address gets a token bucket
+// per limited message kind; an address that keeps exceeding its limits is
+// blocked, for a period that doubles with each repeat offence.
+//
+// UDP source addresses are easily spoofed, so the per address state is kept
+// in capped maps: a full map drops its least recently active entry. The maps
+// are sharded by address, each shard with its own short held lock.
+//
+// Peer ID enumeration is made harder by capping the number of distinct IDs
+// an address may look up (punch hole and online requests) per hour, by
+// answering online requests only for requesters registered from their IP,
+// and by reporting unknown IDs as offline.
+//
+// Settings:
+//   allow-list, deny-list   list files, see ip_list (default allowlist.txt, denylist.txt)
+//   rate-limits             <kind>=<per second>/<burst>,... (default none, 0 disables a limit),
+//                           e.g. connection=20/100,register-peer=50/500,register-pk=5/50,
+//                           punch-hole=20/100,request-relay=20/100,online=10/50
+//   max-connections         concurrent TCP/WebSocket connections (default 0: unlimited)
+//   block-strikes           exceeded limits within a minute before a block (default 100)
+//   block-duration          first block in seconds, doubled up to a day (default 60)
+//   lookup-limit            distinct IDs looked up per address and hour (default 0: unlimited)
+//   online-max-ids          IDs answered per online request (default 0: unlimited)
+//   online-auth             Y: online requests only from IDs registered from that IP
+//   uniform-failure         Y: answer punch holes to unknown IDs with OFFLINE
+use crate::common::{data_path, get_arg, get_arg_or};
+use crate::control::parse_flag;
+use crate::ip_list::IpList;
+use hbb_common::{
+    log,
+    rendezvous_proto::rendezvous_message,
+    tokio::{self, time::interval},
+};
+use serde_json::{json, Value};
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::{BuildHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+const MAX_BLOCK: Duration = Duration::from_secs(24 * 3600);
+// an offender without a block for this long starts again from the first level
+const FORGET_AFTER: Duration = MAX_BLOCK;
+// idle buckets and offenders are dropped after this
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+const PURGE_INTERVAL: u64 = 60;
+const LOOKUP_WINDOW: Duration = Duration::from_secs(3600);
+const SHARDS: usize = 16;
+// entries of each per address map
+const MAX_ENTRIES: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Kind {
+    Connection,
+    RegisterPeer,
+    RegisterPk,
+    PunchHole,
+    RequestRelay,
+    Online,
+}
+
+const KIND_COUNT: usize = 6;
+const KINDS: [Kind; KIND_COUNT] = [
+    Kind::Connection,
+    Kind::RegisterPeer,
+    Kind::RegisterPk,
+    Kind::PunchHole,
+    Kind::RequestRelay,
+    Kind::Online,
+];
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Connection => "connection",
+            Kind::RegisterPeer => "register-peer",
+            Kind::RegisterPk => "register-pk",
+            Kind::PunchHole => "punch-hole",
+            Kind::RequestRelay => "request-relay",
+            Kind::Online => "online",
+        }
+    }
+
+    /// The kind of a rendezvous message, None for messages that are not limited.
+    pub(crate) fn of(msg: &Option<rendezvous_message::Union>) -> Option<Self> {
+        use rendezvous_message::Union;
+        match msg {
+            Some(Union::RegisterPeer(_)) => Some(Kind::RegisterPeer),
+            Some(Union::RegisterPk(_)) => Some(Kind::RegisterPk),
+            Some(Union::PunchHoleRequest(_)) => Some(Kind::PunchHole),
+            Some(Union::RequestRelay(_)) => Some(Kind::RequestRelay),
+            Some(Union::OnlineRequest(_)) => Some(Kind::Online),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Limit {
+    rate: f64,
+    burst: f64,
+}
+
+fn parse_limits(s: &str) -> HashMap<Kind, Limit> {
+    let mut res = HashMap::new();
+    for x in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let parsed = x.split_once('=').and_then(|(name, v)| {
+            let kind = KINDS.iter().find(|k| k.name() == name.trim())?;
+            let (rate, burst) = v.split_once('/').unwrap_or((v, v));
+            let rate = rate.trim().parse::<f64>().ok()?;
+            let burst = burst.trim().parse::<f64>().ok()?;
+            Some((*kind, Limit { rate, burst }))
+        });
+        match parsed {
+            Some((kind, limit)) if limit.rate > 0. && limit.burst >= 1. => {
+                res.insert(kind, limit);
+            }
+            Some((kind, _)) => {
+                res.remove(&kind);
+            }
+            None => log::error!("Invalid rate limit: {}", x),
+        }
+    }
+    res
+}
+
+struct Bucket {
+    tokens: f64,
+    tm: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: &Limit, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.tm).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.tm = now;
+        if self.tokens >= 1. {
+            self.tokens -= 1.;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct Offender {
+    strikes: u32,
+    strike_tm: Option<Instant>,
+    level: u32,
+    blocked_until: Option<Instant>,
+}
+
+impl Offender {
+    fn is_blocked(&self, now: Instant) -> bool {
+        self.blocked_until.map(|x| x > now).unwrap_or(false)
+    }
+
+    // returns the block duration if this strike triggers a block
+    fn strike(&mut self, now: Instant, strikes: u32, base: Duration) -> Option<Duration> {
+        if self
+            .strike_tm
+            .map(|x| now.duration_since(x) > STRIKE_WINDOW)
+            .unwrap_or(true)
+        {
+            self.strikes = 0;
+            self.strike_tm = Some(now);
+        }
+        if let Some(until) = self.blocked_until {
+            if now > until && now.duration_since(until) > FORGET_AFTER {
+                self.level = 0;
+            }
+        }
+        self.strikes += 1;
+        if self.strikes < strikes {
+            return None;
+        }
+        self.strikes = 0;
+        let dur = base
+            .checked_mul(1 << self.level.min(16))
+            .unwrap_or(MAX_BLOCK)
+            .min(MAX_BLOCK);
+        self.level += 1;
+        self.blocked_until = Some(now + dur);
+        Some(dur)
+    }
+}
+
+// distinct IDs looked up by one address in the current window
+struct Lookups {
+    ids: HashSet<String>,
+    tm: Instant,
+}
+
+impl Lookups {
+    fn check(&mut self, id: &str, max: usize, now: Instant) -> bool {
+        if now.duration_since(self.tm) > LOOKUP_WINDOW {
+            self.ids.clear();
+            self.tm = now;
+        }
+        if self.ids.contains(id) {
+            return true;
+        }
+        if self.ids.len() >= max {
+            return false;
+        }
+        self.ids.insert(id.to_owned());
+        true
+    }
+}
+
+// the last activity of per address state, the oldest is dropped from a full map
+trait Aged {
+    fn tm(&self) -> Instant;
+}
+
+impl Aged for Bucket {
+    fn tm(&self) -> Instant {
+        self.tm
+    }
+}
+
+impl Aged for Offender {
+    // blocked offenders are kept over idle ones
+    fn tm(&self) -> Instant {
+        self.blocked_until
+            .into_iter()
+            .chain(self.strike_tm)
+            .max()
+            .unwrap_or_else(Instant::now)
+    }
+}
+
+impl Aged for Lookups {
+    fn tm(&self) -> Instant {
+        self.tm
+    }
+}
+
+// A capped map split into shards with a lock each. The locks are never held
+// across an await.
+struct Shards<K, V> {
+    shards: Vec<Mutex<HashMap<K, V>>>,
+    hasher: RandomState,
+    // entries per shard
+    cap: usize,
+}
+
+impl<K: Hash + Eq + Copy, V: Aged> Shards<K, V> {
+    fn new(shards: usize, max: usize) -> Self {
+        Self {
+            shards: (0..shards).map(|_| Default::default()).collect(),
+            hasher: Default::default(),
+            cap: (max / shards).max(1),
+        }
+    }
+
+    fn shard(&self, k: &K) -> &Mutex<HashMap<K, V>> {
+        let mut h = self.hasher.build_hasher();
+        k.hash(&mut h);
+        &self.shards[h.finish() as usize % self.shards.len()]
+    }
+
+    fn get<R>(&self, k: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
+        self.shard(k).lock().unwrap().get(k).map(f)
+    }
+
+    // `f` of the entry of `k`, created with `new` if missing
+    fn with<R>(&self, k: K, new: impl FnOnce() -> V, f: impl FnOnce(&mut V) -> R) -> R {
+        let mut lock = self.shard(&k).lock().unwrap();
+        if lock.len() >= self.cap && !lock.contains_key(&k) {
+            if let Some(old) = lock.iter().min_by_key(|(_, v)| v.tm()).map(|(k, _)| *k) {
+                lock.remove(&old);
+            }
+        }
+        f(lock.entry(k).or_insert_with(new))
+    }
+
+    fn remove(&self, k: &K) -> bool {
+        self.shard(k).lock().unwrap().remove(k).is_some()
+    }
+
+    fn retain(&self, mut f: impl FnMut(&V) -> bool) {
+        for shard in &self.shards {
+            shard.lock().unwrap().retain(|_, v| f(v));
+        }
+    }
+
+    fn collect<T>(&self, mut f: impl FnMut(&K, &V) -> Option<T>) -> Vec<T> {
+        let mut res = Vec::new();
+        for shard in &self.shards {
+            res.extend(shard.lock().unwrap().iter().filter_map(|(k, v)| f(k, v)));
+        }
+        res
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    denied: AtomicU64,
+    blocked: AtomicU64,
+    rejected_connections: AtomicU64,
+    limited_lookups: AtomicU64,
+    refused_online: AtomicU64,
+    dropped_udp: AtomicU64,
+    limited: [AtomicU64; KIND_COUNT],
+}
+
+pub(crate) struct Policy {
+    allow: IpList,
+    deny: IpList,
+    limits: HashMap<Kind, Limit>,
+    max_connections: usize,
+    strikes: u32,
+    block_duration: Duration,
+    lookup_limit: usize,
+    online_max_ids: usize,
+    online_auth: bool,
+    uniform_failure: bool,
+    connections: AtomicUsize,
+    buckets: Shards<(IpAddr, Kind), Bucket>,
+    offenders: Shards<IpAddr, Offender>,
+    lookups: Shards<IpAddr, Lookups>,
+    counters: Counters,
+}
+
+lazy_static::lazy_static! {
+    static ref POLICY: Policy = Policy::new();
+}
+
+fn arg_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    get_arg(name).parse().unwrap_or(default)
+}
+
+#[inline]
+fn flag(name: &str) -> bool {
+    parse_flag(&get_arg(name)).unwrap_or(false)
+}
+
+#[inline]
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+impl Policy {
+    fn new() -> Self {
+        Self {
+            allow: IpList::new(&data_path(&get_arg_or("allow-list", "allowlist.txt".to_owned()))),
+            deny: IpList::new(&data_path(&get_arg_or("deny-list", "denylist.txt".to_owned()))),
+            limits: parse_limits(&get_arg("rate-limits")),
+            max_connections: arg_or("max-connections", 0),
+            strikes: arg_or("block-strikes", 100u32).max(1),
+            block_duration: Duration::from_secs(arg_or("block-duration", 60u64).max(1)),
+            lookup_limit: arg_or("lookup-limit", 0),
+            online_max_ids: arg_or("online-max-ids", 0),
+            online_auth: flag("online-auth"),
+            uniform_failure: flag("uniform-failure"),
+            connections: Default::default(),
+            buckets: Shards::new(SHARDS, MAX_ENTRIES),
+            offenders: Shards::new(SHARDS, MAX_ENTRIES),
+            lookups: Shards::new(SHARDS, MAX_ENTRIES),
+            counters: Default::default(),
+        }
+    }
+
+    // true if `ip` may send a message of `kind` now
+    fn check_rate(&self, ip: IpAddr, kind: Kind) -> bool {
+        let now = Instant::now();
+        if self.offenders.get(&ip, |x| x.is_blocked(now)) == Some(true) {
+            self.counters.blocked.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let Some(limit) = self.limits.get(&kind) else {
+            return true;
+        };
+        let new = || Bucket {
+            tokens: limit.burst,
+            tm: now,
+        };
+        let ok = self.buckets.with((ip, kind), new, |x| x.take(limit, now));
+        if !ok {
+            self.counters.limited[kind as usize].fetch_add(1, Ordering::Relaxed);
+            self.offenders.with(ip, Offender::default, |offender| {
+                if let Some(dur) = offender.strike(now, self.strikes, self.block_duration) {
+                    log::warn!(
+                        "{} blocked for {}s, level {}, exceeded {} limit",
+                        ip,
+                        dur.as_secs(),
+                        offender.level,
+                        kind.name()
+                    );
+                }
+            });
+        }
+        ok
+    }
+
+    fn purge(&self) {
+        let now = Instant::now();
+        self.buckets
+            .retain(|x| now.duration_since(x.tm) < IDLE_TIMEOUT);
+        self.offenders.retain(|x| {
+            x.is_blocked(now)
+                || x.strike_tm
+                    .map(|tm| now.duration_since(tm) < IDLE_TIMEOUT)
+                    .unwrap_or(false)
+                || (x.level > 0
+                    && x.blocked_until
+                        .map(|tm| now.duration_since(tm) < FORGET_AFTER)
+                        .unwrap_or(false))
+        });
+        self.lookups
+            .retain(|x| now.duration_since(x.tm) <= LOOKUP_WINDOW);
+    }
+}
+
+/// Load the lists and start the housekeeping; call once at startup.
+pub(crate) async fn init() {
+    let p = &*POLICY;
+    for list in [&p.allow, &p.deny] {
+        list.load().await;
+        list.watch(PURGE_INTERVAL);
+    }
+    log::info!(
+        "rate-limits: {:?}, max-connections: {}, block-strikes: {}, block-duration: {}s",
+        KINDS
+            .iter()
+            .filter_map(|k| p
+                .limits
+                .get(k)
+                .map(|l| format!("{}={}/{}", k.name(), l.rate, l.burst)))
+            .collect::<Vec<_>>(),
+        p.max_connections,
+        p.strikes,
+        p.block_duration.as_secs()
+    );
+    log::info!(
+        "lookup-limit: {}, online-max-ids: {}, online-auth: {}, uniform-failure: {}",
+        p.lookup_limit,
+        p.online_max_ids,
+        p.online_auth,
+        p.uniform_failure
+    );
+    tokio::spawn(async move {
+        let mut timer = interval(Duration::from_secs(PURGE_INTERVAL));
+        loop {
+            timer.tick().await;
+            POLICY.purge();
+        }
+    });
+}
+
+/// Whether a message of `kind` from `ip` is handled.
+pub(crate) async fn check(ip: IpAddr, kind: Option<Kind>) -> bool {
+    let ip = canonical(ip);
+    let p = &*POLICY;
+    if ip.is_loopback() || p.allow.contains_ip(ip).await {
+        return true;
+    }
+    if p.deny.contains_ip(ip).await {
+        p.counters.denied.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    match kind {
+        Some(kind) => p.check_rate(ip, kind),
+        None => true,
+    }
+}
+
+/// Accounts for one open connection while alive.
+pub(crate) struct Connection;
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        POLICY.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Admit a new TCP/WebSocket connection from `ip`.
+pub(crate) async fn accept(ip: IpAddr) -> Option<Connection> {
+    if !check(ip, Some(Kind::Connection)).await {
+        return None;
+    }
+    let p = &*POLICY;
+    let n = p.connections.fetch_add(1, Ordering::SeqCst);
+    let conn = Connection;
+    if p.max_connections > 0 && n >= p.max_connections {
+        p.counters
+            .rejected_connections
+            .fetch_add(1, Ordering::Relaxed);
+        log::debug!("Connection from {} rejected, {} connections", ip, n);
+        return None;
+    }
+    Some(conn)
+}
+
+/// For each of `ids`, whether `ip` may look it up within its distinct ID limit.
+pub(crate) async fn check_lookups(ip: IpAddr, ids: &[String]) -> Vec<bool> {
+    let ip = canonical(ip);
+    let p = &*POLICY;
+    if p.lookup_limit == 0 || ip.is_loopback() || p.allow.contains_ip(ip).await {
+        return vec![true; ids.len()];
+    }
+    let now = Instant::now();
+    let new = || Lookups {
+        ids: Default::default(),
+        tm: now,
+    };
+    let res: Vec<bool> = p.lookups.with(ip, new, |lookups| {
+        ids.iter()
+            .map(|id| lookups.check(id, p.lookup_limit, now))
+            .collect()
+    });
+    let n = res.iter().filter(|x| !**x).count();
+    if n > 0 {
+        p.counters
+            .limited_lookups
+            .fetch_add(n as _, Ordering::Relaxed);
+        log::debug!("{} exceeded the lookup limit, {} IDs refused", ip, n);
+    }
+    res
+}
+
+/// IDs answered per online request, 0 for no limit.
+#[inline]
+pub(crate) fn online_max_ids() -> usize {
+    POLICY.online_max_ids
+}
+
+/// Whether online requests are only answered for requesters registered from their IP.
+#[inline]
+pub(crate) fn online_auth() -> bool {
+    POLICY.online_auth
+}
+
+#[inline]
+pub(crate) fn online_refused() {
+    POLICY
+        .counters
+        .refused_online
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a datagram dropped as its UDP worker was busy, returns the count.
+#[inline]
+pub(crate) fn udp_dropped() -> u64 {
+    POLICY.counters.dropped_udp.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Whether unknown IDs are reported as offline.
+#[inline]
+pub(crate) fn uniform_failure() -> bool {
+    POLICY.uniform_failure
+}
+
+#[inline]
+pub(crate) fn allow_list() -> &'static IpList {
+    &POLICY.allow
+}
+
+#[inline]
+pub(crate) fn deny_list() -> &'static IpList {
+    &POLICY.deny
+}
+
+/// Lift the block of an offender, returns whether it was known.
+pub(crate) fn unblock(ip: IpAddr) -> bool {
+    POLICY.offenders.remove(&canonical(ip))
+}
+
+/// Counters and currently blocked addresses.
+pub(crate) fn stats() -> Value {
+    let p = &*POLICY;
+    let now = Instant::now();
+    let limited: serde_json::Map<String, Value> = KINDS
+        .iter()
+        .map(|k| {
+            (
+                k.name().to_owned(),
+                p.counters.limited[*k as usize]
+                    .load(Ordering::Relaxed)
+                    .into(),
+            )
+        })
+        .collect();
+    let blocked: Vec<Value> = p.offenders.collect(|ip, x| {
+        x.is_blocked(now).then(|| {
+            json!({
+                "ip": ip.to_string(),
+                "level": x.level,
+                "secs_left": x.blocked_until.map(|t| t.duration_since(now).as_secs()),
+            })
+        })
+    });
+    json!({
+        "connections": p.connections.load(Ordering::SeqCst),
+        "max_connections": p.max_connections,
+        "denied": p.counters.denied.load(Ordering::Relaxed),
+        "blocked_hits": p.counters.blocked.load(Ordering::Relaxed),
+        "rejected_connections": p.counters.rejected_connections.load(Ordering::Relaxed),
+        "limited_lookups": p.counters.limited_lookups.load(Ordering::Relaxed),
+        "refused_online": p.counters.refused_online.load(Ordering::Relaxed),
+        "dropped_udp": p.counters.dropped_udp.load(Ordering::Relaxed),
+        "limited": limited,
+        "blocked": blocked,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limits() {
+        let limits = parse_limits("register-pk=2/10, online=0/5, punch-hole=3, bogus=1/1");
+        assert_eq!(
+            limits.get(&Kind::RegisterPk),
+            Some(&Limit {
+                rate: 2.,
+                burst: 10.
+            })
+        );
+        assert_eq!(
+            limits.get(&Kind::PunchHole),
+            Some(&Limit {
+                rate: 3.,
+                burst: 3.
+            })
+        );
+        assert_eq!(limits.get(&Kind::Online), None);
+        assert_eq!(limits.len(), 2);
+    }
+
+    #[test]
+    fn test_bucket() {
+        let limit = Limit {
+            rate: 1.,
+            burst: 2.,
+        };
+        let now = Instant::now();
+        let mut b = Bucket {
+            tokens: 2.,
+            tm: now,
+        };
+        assert!(b.take(&limit, now));
+        assert!(b.take(&limit, now));
+        assert!(!b.take(&limit, now));
+        assert!(b.take(&limit, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_escalation() {
+        let base = Duration::from_secs(60);
+        let now = Instant::now();
+        let mut x = Offender::default();
+        assert_eq!(x.strike(now, 2, base), None);
+        assert_eq!(x.strike(now, 2, base), Some(base));
+        assert!(x.is_blocked(now));
+        assert_eq!(x.strike(now, 2, base), None);
+        assert_eq!(x.strike(now, 2, base), Some(base * 2));
+        x.level = 30;
+        assert_eq!(x.strike(now, 1, base), Some(MAX_BLOCK));
+    }
+
+    #[test]
+    fn test_lookups() {
+        let now = Instant::now();
+        let mut x = Lookups {
+            ids: Default::default(),
+            tm: now,
+        };
+        assert!(x.check("1", 2, now));
+        assert!(x.check("2", 2, now));
+        assert!(x.check("1", 2, now));
+        assert!(!x.check("3", 2, now));
+        assert!(x.check("3", 2, now + LOOKUP_WINDOW * 2));
+    }
+
+    #[test]
+    fn test_shards() {
+        let now = Instant::now();
+        let bucket = |secs| Bucket {
+            tokens: 1.,
+            tm: now + Duration::from_secs(secs),
+        };
+        // one shard of 2 entries
+        let map: Shards<u32, Bucket> = Shards::new(1, 2);
+        map.with(1, || bucket(1), |_| {});
+        map.with(2, || bucket(0), |_| {});
+        map.with(3, || bucket(2), |_| {});
+        // the oldest is dropped
+        assert_eq!(map.get(&2, |_| ()), None);
+        assert_eq!(map.get(&1, |x| x.tm), Some(now + Duration::from_secs(1)));
+        map.with(1, || bucket(9), |x| x.tm = now + Duration::from_secs(5));
+        map.with(4, || bucket(3), |_| {});
+        assert_eq!(map.get(&3, |_| ()), None);
+        assert_eq!(map.collect(|k, _| Some(*k)).len(), 2);
+        assert!(map.remove(&4));
+        map.retain(|x| x.tm > now + Duration::from_secs(5));
+        assert_eq!(map.collect(|k, _| Some(*k)).len(), 0);
+        // spread over the shards, capped in total
+        let map: Shards<u32, Bucket> = Shards::new(SHARDS, 64);
+        for i in 0..10_000 {
+            map.with(i, || bucket(i as _), |_| {});
+        }
+        assert!(map.collect(|k, _| Some(*k)).len() <= 64);
+    }
+}
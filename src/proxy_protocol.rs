@@ -0,0 +1,310 @@
+// Real client addresses behind load balancers and reverse proxies.
+//
+// With `proxy-protocol` enabled, TCP listeners of hbbs and hbbr accept an
+// HAProxy PROXY protocol header (v1 text or v2 binary) in front of the
+// stream. Connections without a header are handled as before.
+//
+// The address of a PROXY header, and the X-Real-IP / X-Forwarded-For headers
+// of WebSocket handshakes, are only used if the connection comes from one of
+// `trusted-proxies` (comma separated IPs or CIDRs), which `proxy-protocol`
+// requires. Unparseable or untrusted headers are ignored.
+//
+// hbbs takes both settings as options, hbbr from PROXY_PROTOCOL and
+// TRUSTED_PROXIES in the environment or .env.
+use crate::common::get_shared_arg;
+use hbb_common::{
+    log, sleep, timeout,
+    tokio::{io::AsyncReadExt, net::TcpStream},
+};
+use http::HeaderMap;
+use ipnetwork::IpNetwork;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Instant,
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+// header and the largest address block we read (two IPv6 addresses and ports)
+const PEEK_LEN: usize = V2_HEADER_LEN + 36;
+const HEADER_TIMEOUT: u64 = 3_000;
+
+lazy_static::lazy_static! {
+    static ref ENABLED: bool = matches!(
+        get_shared_arg("proxy-protocol").to_lowercase().as_str(),
+        "y" | "yes" | "true" | "on" | "1"
+    );
+    static ref TRUSTED: Vec<IpNetwork> = parse_networks(&get_shared_arg("trusted-proxies"));
+}
+
+fn parse_networks(s: &str) -> Vec<IpNetwork> {
+    s.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .filter_map(|x| match x.parse::<IpNetwork>() {
+            Ok(v) => Some(v),
+            Err(err) => {
+                log::error!("Invalid trusted proxy {}: {}", x, err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[inline]
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+fn is_trusted_in(nets: &[IpNetwork], ip: IpAddr) -> bool {
+    let ip = canonical(ip);
+    nets.iter().any(|x| x.contains(ip))
+}
+
+/// Whether `ip` is a configured trusted proxy.
+pub fn is_trusted(ip: IpAddr) -> bool {
+    is_trusted_in(&TRUSTED, ip)
+}
+
+pub fn log_settings() {
+    log::info!(
+        "proxy-protocol: {}, trusted-proxies: {:?}",
+        *ENABLED,
+        *TRUSTED
+    );
+}
+
+#[derive(Debug, PartialEq)]
+enum Parsed {
+    // no PROXY header, the stream starts with client data
+    None,
+    // need more bytes to decide
+    Incomplete,
+    // header of this many bytes, with the source address if it carries one
+    Header(usize, Option<SocketAddr>),
+}
+
+fn parse(buf: &[u8]) -> Parsed {
+    let n = buf.len().min(V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        return parse_v2(buf);
+    }
+    let n = buf.len().min(V1_PREFIX.len());
+    if buf[..n] == V1_PREFIX[..n] {
+        return parse_v1(buf);
+    }
+    Parsed::None
+}
+
+// PROXY TCP4 <src> <dst> <srcport> <dstport>\r\n, or PROXY UNKNOWN ...\r\n
+fn parse_v1(buf: &[u8]) -> Parsed {
+    let Some(end) = buf.windows(2).position(|x| x == b"\r\n") else {
+        return if buf.len() < V1_MAX_LEN {
+            Parsed::Incomplete
+        } else {
+            Parsed::None
+        };
+    };
+    let len = end + 2;
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end]).unwrap_or_default();
+    let fds: Vec<&str> = line.split(' ').collect();
+    let addr = match fds[..] {
+        ["TCP4" | "TCP6", src, _, port, _] => {
+            let ip = src.parse::<IpAddr>().ok();
+            let port = port.parse::<u16>().ok();
+            ip.zip(port).map(|(ip, port)| SocketAddr::new(ip, port))
+        }
+        _ => None,
+    };
+    Parsed::Header(len, addr)
+}
+
+fn parse_v2(buf: &[u8]) -> Parsed {
+    if buf.len() < V2_HEADER_LEN {
+        return Parsed::Incomplete;
+    }
+    let ver_cmd = buf[12];
+    let fam = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let total = V2_HEADER_LEN + len;
+    if ver_cmd >> 4 != 2 {
+        return Parsed::None;
+    }
+    // LOCAL (health checks of the proxy itself) carries no client
+    if ver_cmd & 0xF != 1 {
+        return Parsed::Header(total, None);
+    }
+    let need = match fam >> 4 {
+        1 => 12,
+        2 => 36,
+        _ => return Parsed::Header(total, None),
+    };
+    if len < need {
+        return Parsed::Header(total, None);
+    }
+    if buf.len() < V2_HEADER_LEN + need {
+        return Parsed::Incomplete;
+    }
+    let a = &buf[V2_HEADER_LEN..];
+    let addr = if need == 12 {
+        let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+        SocketAddr::new(ip.into(), u16::from_be_bytes([a[8], a[9]]))
+    } else {
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&a[..16]);
+        SocketAddr::new(
+            Ipv6Addr::from(ip).into(),
+            u16::from_be_bytes([a[32], a[33]]),
+        )
+    };
+    Parsed::Header(total, Some(addr))
+}
+
+/// Consume a PROXY header at the start of `stream` if there is one, and
+/// return the client address: the one of the header if `addr` is trusted,
+/// otherwise `addr`.
+pub async fn accept(stream: &mut TcpStream, addr: SocketAddr) -> SocketAddr {
+    if !*ENABLED {
+        return addr;
+    }
+    let mut buf = [0u8; V1_MAX_LEN.max(PEEK_LEN)];
+    let start = Instant::now();
+    // peek until the first bytes tell whether there is a header
+    let parsed = loop {
+        let n = match timeout(HEADER_TIMEOUT, stream.peek(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => n,
+            _ => return addr,
+        };
+        match parse(&buf[..n]) {
+            Parsed::Incomplete if start.elapsed().as_millis() < HEADER_TIMEOUT as u128 => {
+                sleep(0.01).await;
+            }
+            Parsed::Incomplete => {
+                log::debug!("Incomplete PROXY header from {}", addr);
+                return addr;
+            }
+            parsed => break parsed,
+        }
+    };
+    let Parsed::Header(len, src) = parsed else {
+        return addr;
+    };
+    let mut header = vec![0u8; len];
+    if !matches!(
+        timeout(HEADER_TIMEOUT, stream.read_exact(&mut header)).await,
+        Ok(Ok(_))
+    ) {
+        log::debug!("Failed to read PROXY header from {}", addr);
+        return addr;
+    }
+    match src {
+        Some(src) if is_trusted(addr.ip()) => {
+            log::debug!("{} via proxy {}", src, addr);
+            src
+        }
+        Some(src) => {
+            log::warn!("Ignored PROXY header for {} from untrusted {}", src, addr);
+            addr
+        }
+        None => addr,
+    }
+}
+
+/// The client IP of a WebSocket handshake from `peer`, if `peer` is a trusted
+/// proxy and sets X-Real-IP or X-Forwarded-For.
+pub fn forwarded_ip(peer: IpAddr, headers: &HeaderMap) -> Option<IpAddr> {
+    forwarded_ip_in(&TRUSTED, peer, headers)
+}
+
+fn forwarded_ip_in(nets: &[IpNetwork], peer: IpAddr, headers: &HeaderMap) -> Option<IpAddr> {
+    if !is_trusted_in(nets, peer) {
+        return None;
+    }
+    let header = |name| headers.get(name).and_then(|x| x.to_str().ok());
+    if let Some(ip) = header("X-Real-IP").and_then(|x| x.trim().parse().ok()) {
+        return Some(ip);
+    }
+    // the rightmost address that is not one of our proxies, each proxy appends its peer
+    let mut res = None;
+    for x in header("X-Forwarded-For")?.rsplit(',') {
+        let ip = x.trim().parse::<IpAddr>().ok()?;
+        res = Some(ip);
+        if !is_trusted_in(nets, ip) {
+            break;
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_v1() {
+        let line = b"PROXY TCP4 1.2.3.4 5.6.7.8 1234 21116\r\nrest";
+        assert_eq!(
+            parse(line),
+            Parsed::Header(39, Some("1.2.3.4:1234".parse().unwrap()))
+        );
+        let line = b"PROXY TCP6 ::1 ::2 1234 21116\r\n";
+        assert_eq!(
+            parse(line),
+            Parsed::Header(31, Some("[::1]:1234".parse().unwrap()))
+        );
+        assert_eq!(parse(b"PROXY UNKNOWN\r\n"), Parsed::Header(15, None));
+        assert_eq!(parse(b"PROXY TCP4 bogus\r\n"), Parsed::Header(18, None));
+        assert_eq!(parse(b"PRO"), Parsed::Incomplete);
+        assert_eq!(parse(b"PROXY TCP4 1.2.3.4"), Parsed::Incomplete);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Parsed::None);
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend([0x21, 0x11, 0, 12]);
+        buf.extend([1, 2, 3, 4, 5, 6, 7, 8, 0x04, 0xD2, 0x52, 0x6C]);
+        assert_eq!(
+            parse(&buf),
+            Parsed::Header(28, Some("1.2.3.4:1234".parse().unwrap()))
+        );
+        assert_eq!(parse(&buf[..20]), Parsed::Incomplete);
+        // LOCAL
+        buf[12] = 0x20;
+        assert_eq!(parse(&buf), Parsed::Header(28, None));
+        // unknown version
+        buf[12] = 0x31;
+        assert_eq!(parse(&buf), Parsed::None);
+    }
+
+    #[test]
+    fn test_forwarded_ip() {
+        let nets = parse_networks("10.0.0.0/8, 192.168.1.1");
+        let proxy: IpAddr = "10.1.1.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Forwarded-For",
+            "6.6.6.6, 1.2.3.4, 192.168.1.1".parse().unwrap(),
+        );
+        assert_eq!(
+            forwarded_ip_in(&nets, proxy, &headers),
+            Some("1.2.3.4".parse().unwrap())
+        );
+        assert_eq!(
+            forwarded_ip_in(&nets, "1.1.1.1".parse().unwrap(), &headers),
+            None
+        );
+        headers.insert("X-Forwarded-For", "1.2.3.4, garbage".parse().unwrap());
+        assert_eq!(forwarded_ip_in(&nets, proxy, &headers), None);
+        headers.insert("X-Real-IP", "5.5.5.5".parse().unwrap());
+        assert_eq!(
+            forwarded_ip_in(&nets, proxy, &headers),
+            Some("5.5.5.5".parse().unwrap())
+        );
+    }
+}
@@ -1,5 +1,11 @@
 use async_speed_limit::Limiter;
 use async_trait::async_trait;
+use axum::{
+    extract::Path,
+    middleware,
+    routing::{delete, get, post},
+    Json, Router,
+};
 use hbb_common::{
     allow_err, bail,
     bytes::{Bytes, BytesMut},
@@ -21,30 +27,50 @@ use hbb_common::{
 };
 use sodiumoxide::crypto::sign;
 use std::{
-    collections::{HashMap, HashSet},
-    io::prelude::*,
+    collections::HashMap,
     io::Error,
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::atomic::{AtomicUsize, Ordering},
 };
+use crate::common::data_path;
 use crate::custom_keys::CustomKeyManager;
-use hbbs::Database;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use hbbs::{
+    control::{CtlRequest, CtlResponse},
+    handoff,
+    keyring,
+    key_format,
+    ip_list::{parse_duration, IpList},
+    proxy_protocol,
+    settings::{self, Settings},
+    systemd,
+    shutdown,
+    tls::{self, Tls},
+    Database, RelayStatus, STATUS_PORT_OFFSET,
+};
 
 type Usage = (usize, usize, usize, usize);
 
 lazy_static::lazy_static! {
     static ref PEERS: Mutex<HashMap<String, Box<dyn StreamTrait>>> = Default::default();
     static ref USAGE: RwLock<HashMap<String, Usage>> = Default::default();
-    static ref BLACKLIST: RwLock<HashSet<String>> = Default::default();
-    static ref BLOCKLIST: RwLock<HashSet<String>> = Default::default();
+    static ref BLACKLIST: IpList = IpList::new(&data_path(BLACKLIST_FILE));
+    static ref BLOCKLIST: IpList = IpList::new(&data_path(BLOCKLIST_FILE));
 }
 
 // Rendezvous/hbbr do not share state; for hbbr validation we will lazy-check via SQLite path
 // We reuse the same DB URL discovery logic from PeerMap::new by reading DB_URL or default file.
 async fn is_key_valid_db(key: &str) -> bool {
-    let db_url = std::env::var("DB_URL").unwrap_or_else(|_| "./db_v2.sqlite3".to_string());
+    let Ok(key) = key_format::normalize(key) else {
+        return false;
+    };
+    let db_url = settings::get()
+        .db_url
+        .clone()
+        .unwrap_or_else(|| data_path("db_v2.sqlite3"));
     if let Ok(db) = Database::new(&db_url).await {
-        return db.is_key_valid(key).await.unwrap_or(false);
+        return db.is_key_valid(&key).await.unwrap_or(false);
     }
     false
 }
@@ -56,104 +82,178 @@ static TOTAL_BANDWIDTH: AtomicUsize = AtomicUsize::new(1024 * 1024 * 1024); // i
 static SINGLE_BANDWIDTH: AtomicUsize = AtomicUsize::new(128 * 1024 * 1024); // in bit/s
 const BLACKLIST_FILE: &str = "blacklist.txt";
 const BLOCKLIST_FILE: &str = "blocklist.txt";
+// how often expired list entries are purged, in seconds
+const LIST_PURGE_INTERVAL: u64 = 60;
 
 #[tokio::main(flavor = "multi_thread")]
-pub async fn start(port: &str, key: &str) -> ResultType<()> {
-    let key = get_server_sk(key);
-    if let Ok(mut file) = std::fs::File::open(BLACKLIST_FILE) {
-        let mut contents = String::new();
-        if file.read_to_string(&mut contents).is_ok() {
-            for x in contents.split('\n') {
-                if let Some(ip) = x.trim().split(' ').next() {
-                    BLACKLIST.write().await.insert(ip.to_owned());
-                }
-            }
+pub async fn start(port: &str, key: &str, takeover: bool) -> ResultType<()> {
+    let key = get_server_sk(&crate::server_key::resolve(key)?);
+    keyring::init(&key, None)?;
+    for list in [&*BLACKLIST, &*BLOCKLIST] {
+        list.load().await;
+        list.watch(LIST_PURGE_INTERVAL);
+    }
+    let port: u16 = port.parse()?;
+    log::info!("Listening on tcp :{}", port);
+    let port2 = port + 2;
+    let tls = Tls::from_settings()?;
+    let wss_port = tls.as_ref().and_then(|_| tls::wss_port());
+    match (&tls, wss_port) {
+        (Some(_), Some(wss_port)) => {
+            log::info!("Listening on websocket :{}", port2);
+            log::info!("Listening on websocket (TLS) :{}", wss_port);
         }
+        (Some(_), None) => log::info!("Listening on websocket (TLS) :{}", port2),
+        (None, _) => log::info!("Listening on websocket :{}", port2),
     }
-    log::info!(
-        "#blacklist({}): {}",
-        BLACKLIST_FILE,
-        BLACKLIST.read().await.len()
-    );
-    if let Ok(mut file) = std::fs::File::open(BLOCKLIST_FILE) {
-        let mut contents = String::new();
-        if file.read_to_string(&mut contents).is_ok() {
-            for x in contents.split('\n') {
-                if let Some(ip) = x.trim().split(' ').next() {
-                    BLOCKLIST.write().await.insert(ip.to_owned());
-                }
+    if let Some(tls) = &tls {
+        tls.watch();
+    }
+    check_params(&settings::get());
+    proxy_protocol::log_settings();
+    let limiter = <Limiter>::new(TOTAL_BANDWIDTH.load(Ordering::SeqCst) as _);
+    {
+        let limiter = limiter.clone();
+        settings::on_reload(move |settings| {
+            check_params(settings);
+            limiter.set_speed_limit(TOTAL_BANDWIDTH.load(Ordering::SeqCst) as _);
+        });
+    }
+    // the listeners of the running hbbr, or new ones
+    let mut inherited = if takeover {
+        Some(handoff::take_over(&handoff::path())?)
+    } else {
+        None
+    };
+    let mut listener = listen(&mut inherited, "relay", port).await?;
+    let mut listener2 = listen(&mut inherited, "ws", port2).await?;
+    let mut listener3 = match wss_port {
+        Some(wss_port) => Some(listen(&mut inherited, "wss", wss_port).await?),
+        None => None,
+    };
+    let status_port = settings::get()
+        .status_port
+        .unwrap_or((port as i32 + STATUS_PORT_OFFSET) as u16);
+    // the status is not authenticated, loopback unless opened up on purpose
+    let status_addr: IpAddr = settings::get()
+        .status_addr
+        .as_deref()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let status = match inherited.as_mut().and_then(|x| x.take("status")) {
+        Some(listener) => Ok(listener),
+        None => match systemd::take_tcp(status_port) {
+            Some(listener) => Ok(listener),
+            None => std::net::TcpListener::bind((status_addr, status_port)),
+        },
+    };
+    #[cfg(unix)]
+    let status_fd = status.as_ref().ok().map(|x| x.as_raw_fd());
+    match status {
+        Ok(status) => spawn_status(status, limiter.clone()),
+        Err(err) => log::error!("Status server failed: {}", err),
+    }
+    if let Some(inherited) = inherited {
+        inherited.confirm()?;
+        log::info!("Took over the listeners of the running hbbr");
+    }
+    #[cfg(unix)]
+    {
+        let path = handoff::path();
+        if !path.is_empty() {
+            let mut fds = vec![("relay", listener.as_raw_fd()), ("ws", listener2.as_raw_fd())];
+            if let Some(listener3) = &listener3 {
+                fds.push(("wss", listener3.as_raw_fd()));
+            }
+            if let Some(status) = status_fd {
+                fds.push(("status", status));
             }
+            handoff::serve(&path, &fds)?;
         }
     }
-    log::info!(
-        "#blocklist({}): {}",
-        BLOCKLIST_FILE,
-        BLOCKLIST.read().await.len()
-    );
-    let port: u16 = port.parse()?;
-    log::info!("Listening on tcp :{}", port);
-    let port2 = port + 2;
-    log::info!("Listening on websocket :{}", port2);
+    systemd::ready();
     let main_task = async move {
         loop {
             log::info!("Start");
-            io_loop(listen_any(port).await?, listen_any(port2).await?, &key).await;
+            if io_loop(
+                &mut listener,
+                &mut listener2,
+                &mut listener3,
+                &key,
+                &limiter,
+                tls.as_ref(),
+            )
+            .await
+            {
+                return Ok(());
+            }
+            listener = listen_any(port).await?;
+            listener2 = listen_any(port2).await?;
+            if let Some(wss_port) = wss_port {
+                listener3 = Some(listen_any(wss_port).await?);
+            }
+        }
+    };
+    let listen_signal = crate::common::listen_signal(on_hangup);
+    // the main task only ends when the listeners are handed over
+    let handed_over = tokio::select!(
+        res = main_task => {
+            res?;
+            true
         }
+        res = listen_signal => {
+            res?;
+            false
+        }
+    );
+    // the listeners are closed, relays in progress go on
+    shutdown::start();
+    let pending = || async { shutdown::active_count() };
+    // after a handoff, relays may take as long as they need
+    let timeout = if handed_over {
+        None
+    } else {
+        Some(shutdown::timeout())
     };
-    let listen_signal = crate::common::listen_signal();
     tokio::select!(
-        res = main_task => res,
-        res = listen_signal => res,
-    )
+        _ = shutdown::drain(timeout, pending) => {}
+        res = crate::common::listen_signal(on_hangup) => return res,
+    );
+    Ok(())
 }
 
-fn check_params() {
-    let tmp = std::env::var("DOWNGRADE_THRESHOLD")
-        .map(|x| x.parse::<f64>().unwrap_or(0.))
-        .unwrap_or(0.);
-    if tmp > 0. {
-        DOWNGRADE_THRESHOLD_100.store((tmp * 100.) as _, Ordering::SeqCst);
+fn check_params(settings: &Settings) {
+    if let Some(v) = settings.downgrade_threshold.filter(|x| *x > 0.) {
+        DOWNGRADE_THRESHOLD_100.store((v * 100.) as _, Ordering::SeqCst);
     }
     log::info!(
         "DOWNGRADE_THRESHOLD: {}",
         DOWNGRADE_THRESHOLD_100.load(Ordering::SeqCst) as f64 / 100.
     );
-    let tmp = std::env::var("DOWNGRADE_START_CHECK")
-        .map(|x| x.parse::<usize>().unwrap_or(0))
-        .unwrap_or(0);
-    if tmp > 0 {
-        DOWNGRADE_START_CHECK.store(tmp * 1000, Ordering::SeqCst);
+    if let Some(v) = settings.downgrade_start_check.filter(|x| *x > 0) {
+        DOWNGRADE_START_CHECK.store(v * 1000, Ordering::SeqCst);
     }
     log::info!(
         "DOWNGRADE_START_CHECK: {}s",
         DOWNGRADE_START_CHECK.load(Ordering::SeqCst) / 1000
     );
-    let tmp = std::env::var("LIMIT_SPEED")
-        .map(|x| x.parse::<f64>().unwrap_or(0.))
-        .unwrap_or(0.);
-    if tmp > 0. {
-        LIMIT_SPEED.store((tmp * 1024. * 1024.) as usize, Ordering::SeqCst);
+    if let Some(v) = settings.limit_speed.filter(|x| *x > 0.) {
+        LIMIT_SPEED.store((v * 1024. * 1024.) as usize, Ordering::SeqCst);
     }
     log::info!(
         "LIMIT_SPEED: {}Mb/s",
         LIMIT_SPEED.load(Ordering::SeqCst) as f64 / 1024. / 1024.
     );
-    let tmp = std::env::var("TOTAL_BANDWIDTH")
-        .map(|x| x.parse::<f64>().unwrap_or(0.))
-        .unwrap_or(0.);
-    if tmp > 0. {
-        TOTAL_BANDWIDTH.store((tmp * 1024. * 1024.) as usize, Ordering::SeqCst);
+    if let Some(v) = settings.total_bandwidth.filter(|x| *x > 0.) {
+        TOTAL_BANDWIDTH.store((v * 1024. * 1024.) as usize, Ordering::SeqCst);
     }
 
     log::info!(
         "TOTAL_BANDWIDTH: {}Mb/s",
         TOTAL_BANDWIDTH.load(Ordering::SeqCst) as f64 / 1024. / 1024.
     );
-    let tmp = std::env::var("SINGLE_BANDWIDTH")
-        .map(|x| x.parse::<f64>().unwrap_or(0.))
-        .unwrap_or(0.);
-    if tmp > 0. {
-        SINGLE_BANDWIDTH.store((tmp * 1024. * 1024.) as usize, Ordering::SeqCst);
+    if let Some(v) = settings.single_bandwidth.filter(|x| *x > 0.) {
+        SINGLE_BANDWIDTH.store((v * 1024. * 1024.) as usize, Ordering::SeqCst);
     }
     log::info!(
         "SINGLE_BANDWIDTH: {}Mb/s",
@@ -170,10 +270,10 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
         Some("h") => {
             res = format!(
                 "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
-                "blacklist-add(ba) <ip>",
+                "blacklist-add(ba) <ip|cidr> [duration(e.g. 30m,2h,7d)] [reason]",
                 "blacklist-remove(br) <ip>",
                 "blacklist(b) <ip>",
-                "blocklist-add(Ba) <ip>",
+                "blocklist-add(Ba) <ip|cidr> [duration(e.g. 30m,2h,7d)] [reason]",
                 "blocklist-remove(Br) <ip>",
                 "blocklist(B) <ip>",
                 "downgrade-threshold(dt) [value]",
@@ -184,57 +284,30 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
                 "usage(u)"
             )
         }
-        Some("blacklist-add" | "ba") => {
-            if let Some(ip) = fds.next() {
-                for ip in ip.split('|') {
-                    BLACKLIST.write().await.insert(ip.to_owned());
+        Some(cmd @ ("blacklist-add" | "ba" | "blocklist-add" | "Ba")) => {
+            let list = if cmd.starts_with('b') { &*BLACKLIST } else { &*BLOCKLIST };
+            let args: Vec<&str> = fds.collect();
+            if !args.is_empty() {
+                if let Err(err) = list.add_from_args(&args, "cmd").await {
+                    res = format!("{err}\n");
                 }
             }
         }
-        Some("blacklist-remove" | "br") => {
+        Some(cmd @ ("blacklist-remove" | "br" | "blocklist-remove" | "Br")) => {
+            let list = if cmd.starts_with('b') { &*BLACKLIST } else { &*BLOCKLIST };
             if let Some(ip) = fds.next() {
-                if ip == "all" {
-                    BLACKLIST.write().await.clear();
-                } else {
-                    for ip in ip.split('|') {
-                        BLACKLIST.write().await.remove(ip);
-                    }
+                if let Err(err) = list.remove(ip).await {
+                    res = format!("{err}\n");
                 }
             }
         }
-        Some("blacklist" | "b") => {
+        Some(cmd @ ("blacklist" | "b" | "blocklist" | "B")) => {
+            let list = if cmd.starts_with('b') { &*BLACKLIST } else { &*BLOCKLIST };
             if let Some(ip) = fds.next() {
-                res = format!("{}\n", BLACKLIST.read().await.get(ip).is_some());
+                res = format!("{}\n", list.contains(ip).await);
             } else {
-                for ip in BLACKLIST.read().await.clone().into_iter() {
-                    let _ = writeln!(res, "{ip}");
-                }
-            }
-        }
-        Some("blocklist-add" | "Ba") => {
-            if let Some(ip) = fds.next() {
-                for ip in ip.split('|') {
-                    BLOCKLIST.write().await.insert(ip.to_owned());
-                }
-            }
-        }
-        Some("blocklist-remove" | "Br") => {
-            if let Some(ip) = fds.next() {
-                if ip == "all" {
-                    BLOCKLIST.write().await.clear();
-                } else {
-                    for ip in ip.split('|') {
-                        BLOCKLIST.write().await.remove(ip);
-                    }
-                }
-            }
-        }
-        Some("blocklist" | "B") => {
-            if let Some(ip) = fds.next() {
-                res = format!("{}\n", BLOCKLIST.read().await.get(ip).is_some());
-            } else {
-                for ip in BLOCKLIST.read().await.clone().into_iter() {
-                    let _ = writeln!(res, "{ip}");
+                for x in list.list().await {
+                    let _ = writeln!(res, "{}", x.to_line());
                 }
             }
         }
@@ -335,16 +408,260 @@ async fn check_cmd(cmd: &str, limiter: Limiter) -> String {
     res
 }
 
-async fn io_loop(listener: TcpListener, listener2: TcpListener, key: &str) {
-    check_params();
-    let limiter = <Limiter>::new(TOTAL_BANDWIDTH.load(Ordering::SeqCst) as _);
+async fn get_status() -> RelayStatus {
+    let usage = USAGE.read().await;
+    // speed is in bit/ms
+    let bandwidth = usage.values().map(|x| x.3).sum::<usize>() * 1000;
+    let total_bandwidth = TOTAL_BANDWIDTH.load(Ordering::SeqCst);
+    RelayStatus {
+        relays: usage.len(),
+        bandwidth,
+        total_bandwidth,
+        saturation: bandwidth as f64 / total_bandwidth.max(1) as f64,
+        draining: shutdown::is_draining(),
+    }
+}
+
+// "Mb/s" argument of the bandwidth commands
+fn set_bandwidth(v: &str, value: &AtomicUsize) -> Result<(), String> {
+    match v.parse::<f64>() {
+        Ok(v) if v > 0. => {
+            value.store((v * 1024. * 1024.) as _, Ordering::SeqCst);
+            Ok(())
+        }
+        _ => Err(format!("invalid value: {v}")),
+    }
+}
+
+#[inline]
+fn get_bandwidth(value: &AtomicUsize) -> f64 {
+    value.load(Ordering::SeqCst) as f64 / 1024. / 1024.
+}
+
+async fn list_add(list: &IpList, args: &[String], by: &str) -> CtlResponse {
+    match list.add_from_args(args, by).await {
+        Ok(n) => CtlResponse::ok(n),
+        Err(err) => CtlResponse::err(err.to_string()),
+    }
+}
+
+async fn list_remove(list: &IpList, ips: Option<&str>) -> CtlResponse {
+    let Some(ips) = ips else {
+        return CtlResponse::err("missing ip");
+    };
+    match list.remove(ips).await {
+        Ok(n) => CtlResponse::ok(n),
+        Err(err) => CtlResponse::err(err.to_string()),
+    }
+}
+
+async fn list_get(list: &IpList, ip: Option<&str>) -> CtlResponse {
+    match ip {
+        Some(ip) => CtlResponse::ok(list.get(ip).await.map(|x| x.to_json())),
+        None => CtlResponse::ok(list.list().await.iter().map(|x| x.to_json()).collect::<Vec<_>>()),
+    }
+}
+
+/// JSON counterpart of `check_cmd`, for the authenticated control API.
+async fn control(req: CtlRequest, limiter: Limiter) -> CtlResponse {
+    use serde_json::json;
+
+    let arg = req.args.first().map(|x| x.trim()).filter(|x| !x.is_empty());
+    let bandwidth = |value: &AtomicUsize| match arg {
+        Some(v) => set_bandwidth(v, value)
+            .map(|_| CtlResponse::ok(get_bandwidth(value)))
+            .unwrap_or_else(CtlResponse::err),
+        None => CtlResponse::ok(get_bandwidth(value)),
+    };
+    match req.cmd.as_str() {
+        "blacklist-add" => list_add(&BLACKLIST, &req.args, "api").await,
+        "blacklist-remove" => list_remove(&BLACKLIST, arg).await,
+        "blacklist" => list_get(&BLACKLIST, arg).await,
+        "blocklist-add" => list_add(&BLOCKLIST, &req.args, "api").await,
+        "blocklist-remove" => list_remove(&BLOCKLIST, arg).await,
+        "blocklist" => list_get(&BLOCKLIST, arg).await,
+        "downgrade-threshold" => {
+            if let Some(v) = arg {
+                match v.parse::<f64>() {
+                    Ok(v) if v > 0. => {
+                        DOWNGRADE_THRESHOLD_100.store((v * 100.) as _, Ordering::SeqCst)
+                    }
+                    _ => return CtlResponse::err(format!("invalid value: {v}")),
+                }
+            }
+            CtlResponse::ok(DOWNGRADE_THRESHOLD_100.load(Ordering::SeqCst) as f64 / 100.)
+        }
+        "downgrade-start-check" => {
+            if let Some(v) = arg {
+                match v.parse::<usize>() {
+                    Ok(v) if v > 0 => DOWNGRADE_START_CHECK.store(v * 1000, Ordering::SeqCst),
+                    _ => return CtlResponse::err(format!("invalid value: {v}")),
+                }
+            }
+            CtlResponse::ok(DOWNGRADE_START_CHECK.load(Ordering::SeqCst) / 1000)
+        }
+        "limit-speed" => bandwidth(&LIMIT_SPEED),
+        "total-bandwidth" => {
+            let res = bandwidth(&TOTAL_BANDWIDTH);
+            limiter.set_speed_limit(TOTAL_BANDWIDTH.load(Ordering::SeqCst) as _);
+            res
+        }
+        "single-bandwidth" => bandwidth(&SINGLE_BANDWIDTH),
+        "usage" => {
+            let v: Vec<_> = USAGE
+                .read()
+                .await
+                .iter()
+                .filter(|x| (x.1).0 > 0)
+                .map(|(id, (elapsed, total, highest, speed))| {
+                    json!({
+                        "id": id,
+                        "elapsed_secs": elapsed / 1000,
+                        "total_bytes": total / 8,
+                        "highest_kbps": highest,
+                        "average_kbps": total / elapsed,
+                        "speed_kbps": speed,
+                    })
+                })
+                .collect();
+            CtlResponse::ok(v)
+        }
+        "status" => CtlResponse::ok(serde_json::to_value(get_status().await).unwrap_or_default()),
+        cmd => CtlResponse::unknown(cmd),
+    }
+}
+
+fn ip_list(name: &str) -> Option<&'static IpList> {
+    match name {
+        "blacklist" => Some(&*BLACKLIST),
+        "blocklist" => Some(&*BLOCKLIST),
+        _ => None,
+    }
+}
+
+#[derive(Debug, serde_derive::Deserialize)]
+struct IpListForm {
+    ip: String,
+    // e.g. 30m, 2h, 7d; empty for no expiry
+    #[serde(default)]
+    duration: String,
+    #[serde(default)]
+    by: String,
+    #[serde(default)]
+    reason: String,
+}
+
+async fn get_ip_list(Path(name): Path<String>) -> CtlResponse {
+    match ip_list(&name) {
+        Some(list) => list_get(list, None).await,
+        None => CtlResponse::err(format!("unknown list: {name}")),
+    }
+}
+
+async fn add_to_ip_list(Path(name): Path<String>, Json(form): Json<IpListForm>) -> CtlResponse {
+    let Some(list) = ip_list(&name) else {
+        return CtlResponse::err(format!("unknown list: {name}"));
+    };
+    let expires = if form.duration.is_empty() {
+        None
+    } else {
+        match parse_duration(&form.duration) {
+            Some(d) => Some(chrono::Utc::now() + d),
+            None => return CtlResponse::err(format!("invalid duration: {}", form.duration)),
+        }
+    };
+    let by = if form.by.is_empty() { "api" } else { &form.by };
+    match list.add(&form.ip, expires, by, &form.reason).await {
+        Ok(n) => CtlResponse::ok(n),
+        Err(err) => CtlResponse::err(err.to_string()),
+    }
+}
+
+async fn remove_from_ip_list(Path((name, ip)): Path<(String, String)>) -> CtlResponse {
+    match ip_list(&name) {
+        Some(list) => list_remove(list, Some(&ip)).await,
+        None => CtlResponse::err(format!("unknown list: {name}")),
+    }
+}
+
+// Load report polled by hbbs to pick the least loaded relay, and the
+// authenticated control API
+fn spawn_status(listener: std::net::TcpListener, limiter: Limiter) {
+    let app = Router::new()
+        .route("/status", get(|| async { Json(get_status().await) }))
+        .route("/readyz", get(shutdown::readyz));
+    if !hbbs::has_admin_credentials() {
+        log::warn!("Control API disabled, set admin-token or admin-user and admin-pass");
+    }
+    let ctl = Router::new()
+        .route(
+            "/api/ctl",
+            post(move |Json(req): Json<CtlRequest>| {
+                let limiter = limiter.clone();
+                async move { control(req, limiter).await }
+            }),
+        )
+        .route("/api/lists/:list", get(get_ip_list).post(add_to_ip_list))
+        .route("/api/lists/:list/:ip", delete(remove_from_ip_list))
+        .layer(middleware::from_fn(hbbs::auth_middleware));
+    let app = if hbbs::has_admin_credentials() {
+        app.merge(ctl)
+    } else {
+        app
+    };
+    let addr = listener
+        .local_addr()
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+    tokio::spawn(async move {
+        let server = match axum::Server::from_tcp(listener) {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("Status server failed: {}", e);
+                return;
+            }
+        };
+        // a new hbbr serves the status after a handoff
+        if let Err(e) = server
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(handoff::handed_over())
+            .await
+        {
+            log::error!("Status server failed: {}", e);
+        }
+    });
+    log::info!("Listening on http {}, status", addr);
+}
+
+// Returns true when the listeners were handed over to a new process.
+async fn io_loop(
+    listener: &mut TcpListener,
+    listener2: &mut TcpListener,
+    listener3: &mut Option<TcpListener>,
+    key: &str,
+    limiter: &Limiter,
+    tls: Option<&Tls>,
+) -> bool {
+    // the WebSocket port is TLS unless there is a separate wss port
+    let ws_tls = tls.filter(|_| listener3.is_none()).cloned();
     loop {
+        // not accepting while the listeners are handed over
+        let paused = handoff::is_paused();
         tokio::select! {
-            res = listener.accept() => {
+            _ = handoff::handed_over() => {
+                log::info!("Stop accepting connections");
+                return true;
+            }
+            _ = handoff::pause_changed(paused) => {
+                if !paused {
+                    log::info!("Pause accepting connections for the handoff");
+                }
+            }
+            res = listener.accept(), if !paused => {
                 match res {
                     Ok((stream, addr))  => {
                         stream.set_nodelay(true).ok();
-                        handle_connection(stream, addr, &limiter, key, false).await;
+                        handle_connection(stream, addr, limiter, key, false, None);
                     }
                     Err(err) => {
                        log::error!("listener.accept failed: {}", err);
@@ -352,11 +669,11 @@ async fn io_loop(listener: TcpListener, listener2: TcpListener, key: &str) {
                     }
                 }
             }
-            res = listener2.accept() => {
+            res = listener2.accept(), if !paused => {
                 match res {
                     Ok((stream, addr))  => {
                         stream.set_nodelay(true).ok();
-                        handle_connection(stream, addr, &limiter, key, true).await;
+                        handle_connection(stream, addr, limiter, key, true, ws_tls.clone());
                     }
                     Err(err) => {
                        log::error!("listener2.accept failed: {}", err);
@@ -364,22 +681,64 @@ async fn io_loop(listener: TcpListener, listener2: TcpListener, key: &str) {
                     }
                 }
             }
+            res = async { listener3.as_mut().unwrap().accept().await }, if !paused && listener3.is_some() => {
+                match res {
+                    Ok((stream, addr))  => {
+                        stream.set_nodelay(true).ok();
+                        handle_connection(stream, addr, limiter, key, true, tls.cloned());
+                    }
+                    Err(err) => {
+                       log::error!("listener3.accept failed: {}", err);
+                       break;
+                    }
+                }
+            }
+        }
+    }
+    false
+}
+
+// The listener of `role` taken over from the running hbbr, the one passed by
+// systemd for `port`, or a new one.
+async fn listen(
+    inherited: &mut Option<handoff::Inherited>,
+    role: &str,
+    port: u16,
+) -> ResultType<TcpListener> {
+    match inherited.as_mut().and_then(|x| x.take(role)) {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            log::info!("Took over {} listener {}", role, listener.local_addr()?);
+            Ok(TcpListener::from_std(listener)?)
         }
+        None => match systemd::take_tcp(port) {
+            Some(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(TcpListener::from_std(listener)?)
+            }
+            None => Ok(listen_any(port).await?),
+        },
     }
 }
 
-async fn handle_connection(
+fn handle_connection(
     stream: TcpStream,
     addr: SocketAddr,
     limiter: &Limiter,
     key: &str,
     ws: bool,
+    tls: Option<Tls>,
 ) {
-    let ip = hbb_common::try_into_v4(addr).ip();
-    if !ws && ip.is_loopback() {
-        let limiter = limiter.clone();
-        tokio::spawn(async move {
-            let mut stream = stream;
+    let key = key.to_owned();
+    let limiter = limiter.clone();
+    tokio::spawn(async move {
+        let mut stream = stream;
+        // commands only from the socket peer itself, never from a PROXY header
+        let peer = addr;
+        let local = hbb_common::try_into_v4(peer).ip().is_loopback();
+        let addr = proxy_protocol::accept(&mut stream, addr).await;
+        let ip = hbb_common::try_into_v4(addr).ip();
+        if !ws && local && addr == peer {
             let mut buffer = [0; 1024];
             if let Ok(Ok(n)) = timeout(1000, stream.read(&mut buffer[..])).await {
                 if let Ok(data) = std::str::from_utf8(&buffer[..n]) {
@@ -387,18 +746,13 @@ async fn handle_connection(
                     stream.write(res.as_bytes()).await.ok();
                 }
             }
-        });
-        return;
-    }
-    let ip = ip.to_string();
-    if BLOCKLIST.read().await.get(&ip).is_some() {
-        log::info!("{} blocked", ip);
-        return;
-    }
-    let key = key.to_owned();
-    let limiter = limiter.clone();
-    tokio::spawn(async move {
-        allow_err!(make_pair(stream, addr, &key, limiter, ws).await);
+            return;
+        }
+        if BLOCKLIST.contains_ip(ip).await {
+            log::info!("{} blocked", ip);
+            return;
+        }
+        allow_err!(make_pair(stream, addr, &key, limiter, ws, tls).await);
     });
 }
 
@@ -408,25 +762,26 @@ async fn make_pair(
     key: &str,
     limiter: Limiter,
     ws: bool,
+    tls: Option<Tls>,
 ) -> ResultType<()> {
     if ws {
         use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+        let stream = match tls {
+            Some(tls) => tls.accept(stream).await?,
+            None => tls::Stream::Plain(stream),
+        };
+        let peer = addr.ip();
         let callback = |req: &Request, response: Response| {
-            let headers = req.headers();
-            let real_ip = headers
-                .get("X-Real-IP")
-                .or_else(|| headers.get("X-Forwarded-For"))
-                .and_then(|header_value| header_value.to_str().ok());
-            if let Some(ip) = real_ip {
-                if ip.contains('.') {
-                    addr = format!("{ip}:0").parse().unwrap_or(addr);
-                } else {
-                    addr = format!("[{ip}]:0").parse().unwrap_or(addr);
-                }
+            if let Some(ip) = proxy_protocol::forwarded_ip(peer, req.headers()) {
+                addr = SocketAddr::new(ip, 0);
             }
             Ok(response)
         };
         let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+        if addr.ip() != peer && BLOCKLIST.contains_ip(addr.ip()).await {
+            log::info!("{} blocked", addr.ip());
+            return Ok(());
+        }
         make_pair_(ws_stream, addr, key, limiter).await;
     } else {
         make_pair_(FramedStream::from(stream, addr), addr, key, limiter).await;
@@ -439,8 +794,8 @@ async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limit
     if let Ok(Some(Ok(bytes))) = timeout(30_000, stream.recv()).await {
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
             if let Some(rendezvous_message::Union::RequestRelay(rf)) = msg_in.union {
-                // Check traditional key first
-                if !key.is_empty() && rf.licence_key != key {
+                // Check the server keys first, any active one during a key rotation
+                if !key.is_empty() && !keyring::get().contains(&rf.licence_key) {
                     // Check DB custom key if traditional key doesn't match
                     if !rf.custom_key.is_empty() {
                         // We can't get the binding id from relay request reliably; still enforce validity.
@@ -457,6 +812,7 @@ async fn make_pair_(stream: impl StreamTrait, addr: SocketAddr, key: &str, limit
                     let mut peer = PEERS.lock().await.remove(&rf.uuid);
                     if let Some(peer) = peer.as_mut() {
                         log::info!("Relayrequest {} from {} got paired", rf.uuid, addr);
+                        let _active = shutdown::active();
                         let id = format!("{}:{}", addr.ip(), addr.port());
                         USAGE.write().await.insert(id.clone(), Default::default());
                         if !stream.is_ws() && !peer.is_ws() {
@@ -490,7 +846,7 @@ async fn relay(
     total_limiter: Limiter,
     id: String,
 ) -> ResultType<()> {
-    let ip = addr.ip().to_string();
+    let ip = hbb_common::try_into_v4(addr).ip();
     let mut tm = std::time::Instant::now();
     let mut elapsed = 0;
     let mut total = 0;
@@ -554,11 +910,11 @@ async fn relay(
 
         let n = tm.elapsed().as_millis() as usize;
         if n >= 1_000 {
-            if BLOCKLIST.read().await.get(&ip).is_some() {
+            if BLOCKLIST.contains_ip(ip).await {
                 log::info!("{} blocked", ip);
                 break;
             }
-            blacked = BLACKLIST.read().await.get(&ip).is_some();
+            blacked = BLACKLIST.contains_ip(ip).await;
             tm = std::time::Instant::now();
             let speed = total_s / n;
             if speed > highest_s {
@@ -587,6 +943,12 @@ async fn relay(
     Ok(())
 }
 
+// SIGHUP
+fn on_hangup() {
+    settings::reload();
+    keyring::reload();
+}
+
 fn get_server_sk(key: &str) -> String {
     let mut key = key.to_owned();
     if let Ok(sk) = base64::decode(&key) {
@@ -636,7 +998,7 @@ impl StreamTrait for FramedStream {
 }
 
 #[async_trait]
-impl StreamTrait for tokio_tungstenite::WebSocketStream<TcpStream> {
+impl StreamTrait for tokio_tungstenite::WebSocketStream<tls::Stream> {
     async fn recv(&mut self) -> Option<Result<BytesMut, Error>> {
         if let Some(msg) = self.next().await {
             match msg {
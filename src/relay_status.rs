@@ -0,0 +1,126 @@
+// Load reported by hbbr on its status endpoint, and polled by hbbs to prefer
+// the least loaded relay servers. hbbr serves it on loopback only unless
+// `status-addr` is set, e.g. to 0.0.0.0 for hbbs on another host.
+use hbb_common::{config::RELAY_PORT, log, ResultType};
+use serde_derive::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::RwLock, time::Instant};
+
+/// The status endpoint listens on the relay port plus this offset.
+pub const STATUS_PORT_OFFSET: i32 = 100;
+// a status older than this (ms) is not used for selection
+const STATUS_TIMEOUT: u128 = 10_000;
+// relays whose saturation is within this of the least loaded one are used in turn
+const LOAD_TOLERANCE: f64 = 0.1;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RelayStatus {
+    /// number of active relayed sessions
+    pub relays: usize,
+    /// bandwidth currently in use, bit/s
+    pub bandwidth: usize,
+    /// total bandwidth limit, bit/s
+    pub total_bandwidth: usize,
+    /// bandwidth / total_bandwidth
+    pub saturation: f64,
+    /// shutting down, no new sessions
+    #[serde(default)]
+    pub draining: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref STATUS: RwLock<HashMap<String, (RelayStatus, Instant)>> = Default::default();
+}
+
+fn status_url(relay: &str) -> String {
+    let (host, port) = match relay.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().unwrap_or(RELAY_PORT))
+        }
+        _ => (relay, RELAY_PORT),
+    };
+    let host = if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]")
+    } else {
+        host.to_owned()
+    };
+    format!("http://{}:{}/status", host, port + STATUS_PORT_OFFSET)
+}
+
+async fn fetch_(relay: &str, timeout_ms: u64) -> ResultType<RelayStatus> {
+    Ok(reqwest::Client::builder()
+        .timeout(std::time::Duration::from_millis(timeout_ms))
+        .build()?
+        .get(status_url(relay))
+        .send()
+        .await?
+        .json::<RelayStatus>()
+        .await?)
+}
+
+/// Poll the status of a relay and remember it.
+/// Returns None if the relay has no status endpoint (e.g. an older hbbr).
+pub(crate) async fn fetch(relay: &str, timeout_ms: u64) -> Option<RelayStatus> {
+    match fetch_(relay, timeout_ms).await {
+        Ok(status) => {
+            log::debug!("relay {} status: {:?}", relay, status);
+            STATUS
+                .write()
+                .unwrap()
+                .insert(relay.to_owned(), (status.clone(), Instant::now()));
+            Some(status)
+        }
+        Err(err) => {
+            log::debug!("Failed to get status of relay {}: {}", relay, err);
+            STATUS.write().unwrap().remove(relay);
+            None
+        }
+    }
+}
+
+/// Saturation of each relay, None if unknown.
+pub(crate) fn saturations(relays: &[String]) -> Vec<Option<f64>> {
+    let lock = STATUS.read().unwrap();
+    relays
+        .iter()
+        .map(|x| match lock.get(x) {
+            Some((status, tm)) if tm.elapsed().as_millis() < STATUS_TIMEOUT => {
+                Some(status.saturation)
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Indexes of the relays that are about as loaded as the least loaded one.
+/// Relays without a known load count as idle.
+pub(crate) fn least_loaded(loads: &[Option<f64>]) -> Vec<usize> {
+    let min = loads
+        .iter()
+        .map(|x| x.unwrap_or(0.))
+        .fold(f64::MAX, f64::min);
+    loads
+        .iter()
+        .enumerate()
+        .filter(|(_, x)| x.unwrap_or(0.) <= min + LOAD_TOLERANCE)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_url() {
+        assert_eq!(status_url("relay.example.com"), "http://relay.example.com:21217/status");
+        assert_eq!(status_url("1.2.3.4:30000"), "http://1.2.3.4:30100/status");
+        assert_eq!(status_url("[2001:db8::1]:21117"), "http://[2001:db8::1]:21217/status");
+        assert_eq!(status_url("2001:db8::1"), "http://[2001:db8::1]:21217/status");
+    }
+
+    #[test]
+    fn test_least_loaded() {
+        assert_eq!(least_loaded(&[Some(0.5), Some(0.2), None]), vec![2]);
+        assert_eq!(least_loaded(&[Some(0.5), Some(0.45), Some(0.9)]), vec![0, 1]);
+    }
+}
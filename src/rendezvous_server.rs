@@ -1,7 +1,19 @@
 use crate::common::*;
 use crate::custom_keys::CustomKeyManager;
 use crate::admin::spawn_admin;
+use crate::cluster::{self, Cluster, ClusterMsg};
+use crate::control::{parse_flag, CtlRequest, CtlResponse};
+use crate::geo;
+use crate::relay_status;
+use crate::ip_list::IpList;
+use crate::keyring;
 use crate::peer::*;
+use crate::policy;
+use crate::proxy_protocol;
+use crate::settings::{self, Settings};
+use crate::shutdown;
+use crate::systemd;
+use crate::tls::{self, Tls};
 use hbb_common::{
     allow_err, bail,
     bytes::{Bytes, BytesMut},
@@ -23,35 +35,40 @@ use hbb_common::{
     tokio::{
         self,
         io::{AsyncReadExt, AsyncWriteExt},
-        net::{TcpListener, TcpStream},
-        sync::{mpsc, Mutex},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::{mpsc, oneshot, Mutex},
         time::{interval, Duration},
     },
-    tokio_util::codec::Framed,
+    tokio_util::{codec::Framed, udp::UdpFramed},
     try_into_v4,
     udp::FramedSocket,
-    AddrMangle, ResultType,
+    sleep, AddrMangle, ResultType,
 };
-use ipnetwork::Ipv4Network;
+use ipnetwork::IpNetwork;
 use sodiumoxide::crypto::sign;
 use std::{
-    collections::HashMap,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     sync::Arc,
     time::Instant,
 };
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 enum Data {
     Msg(Box<RendezvousMessage>, SocketAddr),
     RelayServers0(String),
     RelayServers(RelayServers),
+    ConfigureUpdate(ConfigUpdate),
+    Control(CtlRequest, oneshot::Sender<CtlResponse>),
+    // the settings were reloaded on SIGHUP
+    Reload,
 }
 
 const REG_TIMEOUT: i32 = 30_000;
 type TcpStreamSink = SplitSink<Framed<TcpStream, BytesCodec>, Bytes>;
-type WsSink = SplitSink<tokio_tungstenite::WebSocketStream<TcpStream>, tungstenite::Message>;
+type WsSink = SplitSink<tokio_tungstenite::WebSocketStream<tls::Stream>, tungstenite::Message>;
 enum Sink {
     TcpStream(TcpStreamSink),
     Ws(WsSink),
@@ -61,16 +78,22 @@ type Receiver = mpsc::UnboundedReceiver<Data>;
 static ROTATION_RELAY_SERVER: AtomicUsize = AtomicUsize::new(0);
 type RelayServers = Vec<String>;
 const CHECK_RELAY_TIMEOUT: u64 = 3_000;
+// how long to wait for database queries when exiting, in ms
+const FLUSH_TIMEOUT: u64 = 5_000;
 static ALWAYS_USE_RELAY: AtomicBool = AtomicBool::new(false);
+// relays reporting a saturation at or above this are not used
+static RELAY_SATURATION_100: AtomicUsize = AtomicUsize::new(95); // 0.95
 
 #[derive(Clone)]
 struct Inner {
     serial: i32,
     version: String,
     software_url: String,
-    mask: Option<Ipv4Network>,
+    // relay address given to LAN peers of --mask, per address family
     local_ip: String,
-    sk: Option<sign::SecretKey>,
+    local_ip6: String,
+    sites: Vec<LanSite>,
+    tls: Option<Tls>,
 }
 
 #[derive(Clone)]
@@ -83,49 +106,155 @@ pub struct RendezvousServer {
     rendezvous_servers: Arc<Vec<String>>,
     inner: Arc<Inner>,
     custom_key_manager: CustomKeyManager,
+    cluster: Option<Cluster>,
+}
+
+// UDP datagrams are handled off the main loop by a fixed pool of workers.
+// A datagram is dispatched by the hash of its source address, so all
+// messages of one peer are handled in order by the same worker. The queue of
+// a worker is bounded, datagrams are dropped while it is full, so a flood or a
+// slow database does not grow memory.
+#[derive(Clone)]
+struct UdpWorkers(Arc<Vec<UdpWorker>>);
+
+struct UdpWorker {
+    packets: mpsc::Sender<(BytesMut, SocketAddr)>,
+    // replaces the worker's copy of the server so that relay-server and
+    // configure updates made by the main loop are seen, never dropped
+    sync: mpsc::UnboundedSender<Box<RendezvousServer>>,
+}
+
+// datagrams queued per worker
+const UDP_QUEUE: usize = 1024;
+
+impl UdpWorkers {
+    fn spawn(rs: &RendezvousServer, n: usize, key: &str) -> Self {
+        let mut workers = Vec::with_capacity(n);
+        for _ in 0..n.max(1) {
+            let (packets, mut rx) = mpsc::channel::<(BytesMut, SocketAddr)>(UDP_QUEUE);
+            let (sync, mut sync_rx) = mpsc::unbounded_channel::<Box<RendezvousServer>>();
+            let mut rs = rs.clone();
+            let key = key.to_owned();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        biased;
+                        Some(new) = sync_rx.recv() => rs = *new,
+                        res = rx.recv() => {
+                            let Some((bytes, addr)) = res else {
+                                break;
+                            };
+                            if let Err(err) = rs.handle_udp(&bytes, addr, &key).await {
+                                log::error!("udp failure: {}", err);
+                            }
+                        }
+                    }
+                }
+            });
+            workers.push(UdpWorker { packets, sync });
+        }
+        log::info!("udp workers: {}, queue: {}", workers.len(), UDP_QUEUE);
+        Self(Arc::new(workers))
+    }
+
+    #[inline]
+    fn dispatch(&self, bytes: BytesMut, addr: SocketAddr) {
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        let i = (hasher.finish() % self.0.len() as u64) as usize;
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.0[i].packets.try_send((bytes, addr)) {
+            let n = policy::udp_dropped();
+            if n == 1 || n % 10_000 == 0 {
+                log::warn!("udp worker {} is busy, {} datagrams dropped so far", i, n);
+            }
+        }
+    }
+
+    fn sync(&self, rs: &RendezvousServer) {
+        for w in self.0.iter() {
+            w.sync.send(Box::new(rs.clone())).ok();
+        }
+    }
 }
 
 enum LoopFailure {
     UdpSocket,
+    Listener4,
     Listener3,
     Listener2,
     Listener,
 }
 
+// Where the answer to a punch hole request goes
+enum PunchTarget {
+    Requester,
+    Peer(SocketAddr),
+    // (node, peer address), the peer is registered on another cluster node
+    Node(SocketAddr, SocketAddr),
+}
+
 impl RendezvousServer {
     #[tokio::main(flavor = "multi_thread")]
     pub async fn start(port: i32, serial: i32, key: &str, rmem: usize) -> ResultType<()> {
-        let (key, sk) = Self::get_server_sk(key);
+        let (key, sk) = Self::get_server_sk(&crate::server_key::resolve(key)?);
+        keyring::init(&key, sk)?;
         let nat_port = port - 1;
         let ws_port = port + 2;
         let pm = PeerMap::new().await?;
         log::info!("serial={}", serial);
-        let rendezvous_servers = get_servers(&get_arg("rendezvous-servers"), "rendezvous-servers");
+        let config = settings::get();
+        let rendezvous_servers =
+            get_servers(&joined(&config.rendezvous_servers), "rendezvous-servers");
         log::info!("Listening on tcp/udp :{}", port);
         log::info!("Listening on tcp :{}, extra port for NAT test", nat_port);
-        log::info!("Listening on websocket :{}", ws_port);
+        let tls = Tls::from_settings()?;
+        let wss_port = tls.as_ref().and_then(|_| tls::wss_port());
+        match (&tls, wss_port) {
+            (Some(_), Some(wss_port)) => {
+                log::info!("Listening on websocket :{}", ws_port);
+                log::info!("Listening on websocket (TLS) :{}", wss_port);
+            }
+            (Some(_), None) => log::info!("Listening on websocket (TLS) :{}", ws_port),
+            (None, _) => log::info!("Listening on websocket :{}", ws_port),
+        }
+        if let Some(tls) = &tls {
+            tls.watch();
+        }
         let mut socket = create_udp_listener(port, rmem).await?;
         let (tx, mut rx) = mpsc::unbounded_channel::<Data>();
-        let software_url = get_arg("software-url");
+        let software_url = config.software_url.clone().unwrap_or_default();
         let version = hbb_common::get_version_from_url(&software_url);
         if !version.is_empty() {
             log::info!("software_url: {}, version: {}", software_url, version);
         }
-        let mask = get_arg("mask").parse().ok();
-        let local_ip = if mask.is_none() {
-            "".to_owned()
+        let (local_ip, local_ip6, sites) = lan_settings(&config);
+        let custom_keys_file =
+            data_path(&get_arg_or("custom-keys-file", "custom_keys.json".to_string()));
+        let mut custom_key_manager = CustomKeyManager::new(&custom_keys_file).await;
+        let cluster_nodes = get_servers(&get_arg("cluster-nodes"), "cluster-nodes");
+        let cluster = if cluster_nodes.is_empty() {
+            None
         } else {
-            get_arg_or(
-                "local-ip",
-                local_ip_address::local_ip()
-                    .map(|x| x.to_string())
-                    .unwrap_or_default(),
-            )
+            let cluster_port = get_arg("cluster-port")
+                .parse::<u16>()
+                .unwrap_or((port + 4) as _);
+            let secret = get_arg("cluster-secret");
+            Some(Cluster::new(cluster_port, &cluster_nodes, &secret).await?)
         };
-        let custom_keys_file = get_arg_or("custom-keys-file", "custom_keys.json".to_string());
-        let mut custom_key_manager = CustomKeyManager::new(&custom_keys_file).await;
         // Start admin UI (localhost) with same base port
-        spawn_admin(pm.db.clone(), port).await;
+        let (ctl_tx, mut ctl_rx) = mpsc::unbounded_channel();
+        spawn_admin(pm.db.clone(), pm.keys.clone(), ctl_tx, port).await;
+        {
+            // control requests are answered by the main loop
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while let Some((req, res)) = ctl_rx.recv().await {
+                    if tx.send(Data::Control(req, res)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
         let mut rs = Self {
             tcp_punch: Arc::new(Mutex::new(HashMap::new())),
             pm,
@@ -137,35 +266,61 @@ impl RendezvousServer {
                 serial,
                 version,
                 software_url,
-                sk,
-                mask,
+                sites,
                 local_ip,
+                local_ip6,
+                tls,
             }),
             custom_key_manager,
+            cluster,
         };
-        log::info!("mask: {:?}", rs.inner.mask);
         log::info!("local-ip: {:?}", rs.inner.local_ip);
+        log::info!("local-ip6: {:?}", rs.inner.local_ip6);
+        for site in rs.inner.sites.iter() {
+            log::info!("lan: {} relay: {:?}", site.network, site.relay);
+        }
         std::env::set_var("PORT_FOR_API", port.to_string());
-        rs.parse_relay_servers(&get_arg("relay-servers"));
+        rs.parse_relay_servers(&joined(&config.relay_servers));
+        allow_err!(Self::reload_geo());
+        policy::init().await;
+        proxy_protocol::log_settings();
+        apply_settings(&config);
+        {
+            let tx = tx.clone();
+            settings::on_reload(move |config| {
+                apply_settings(config);
+                tx.send(Data::Reload).ok();
+            });
+        }
+        let udp_workers = get_arg("udp-workers").parse::<usize>().unwrap_or_else(|_| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4)
+        });
+        let workers = UdpWorkers::spawn(&rs, udp_workers, &key);
+        if let Some(cluster) = rs.cluster.clone() {
+            let (tx, mut rx) = mpsc::unbounded_channel::<ClusterMsg>();
+            cluster.spawn(tx);
+            let mut me = rs.clone();
+            tokio::spawn(async move {
+                while let Some(msg) = rx.recv().await {
+                    me.handle_cluster(msg).await;
+                }
+            });
+        }
+        let udp_sockets = get_arg("udp-sockets").parse::<usize>().unwrap_or(1);
+        for _ in 1..udp_sockets {
+            tokio::spawn(udp_recv_loop(port, rmem, workers.clone()));
+        }
         let mut listener = create_tcp_listener(port).await?;
         let mut listener2 = create_tcp_listener(nat_port).await?;
         let mut listener3 = create_tcp_listener(ws_port).await?;
-        let test_addr = std::env::var("TEST_HBBS").unwrap_or_default();
-        if std::env::var("ALWAYS_USE_RELAY")
-            .unwrap_or_default()
-            .to_uppercase()
-            == "Y"
-        {
-            ALWAYS_USE_RELAY.store(true, Ordering::SeqCst);
-        }
-        log::info!(
-            "ALWAYS_USE_RELAY={}",
-            if ALWAYS_USE_RELAY.load(Ordering::SeqCst) {
-                "Y"
-            } else {
-                "N"
-            }
-        );
+        let mut listener4 = match wss_port {
+            Some(wss_port) => Some(create_tcp_listener(wss_port as _).await?),
+            None => None,
+        };
+        systemd::ready();
+        let test_addr = config.test_hbbs.clone().unwrap_or_default();
         if test_addr.to_lowercase() != "no" {
             let test_addr = if test_addr.is_empty() {
                 listener.local_addr()?
@@ -188,6 +343,8 @@ impl RendezvousServer {
                 }
             });
         };
+        let tcp_punch = rs.tcp_punch.clone();
+        let db = rs.pm.db.clone();
         let main_task = async move {
             loop {
                 log::info!("Start");
@@ -197,7 +354,9 @@ impl RendezvousServer {
                         &mut listener,
                         &mut listener2,
                         &mut listener3,
+                        &mut listener4,
                         &mut socket,
+                        &workers,
                         &key,
                     )
                     .await
@@ -218,14 +377,36 @@ impl RendezvousServer {
                         drop(listener3);
                         listener3 = create_tcp_listener(ws_port).await?;
                     }
+                    LoopFailure::Listener4 => {
+                        drop(listener4);
+                        listener4 = match wss_port {
+                            Some(wss_port) => Some(create_tcp_listener(wss_port as _).await?),
+                            None => None,
+                        };
+                    }
                 }
             }
         };
-        let listen_signal = listen_signal();
+        tokio::pin!(main_task);
         tokio::select!(
-            res = main_task => res,
-            res = listen_signal => res,
-        )
+            res = &mut main_task => return res,
+            res = listen_signal(on_hangup) => res?,
+        );
+        // the main loop keeps running, peers answer pending punch holes over UDP
+        shutdown::start();
+        let pending = || {
+            let tcp_punch = tcp_punch.clone();
+            async move { tcp_punch.lock().await.len() }
+        };
+        tokio::select!(
+            res = &mut main_task => return res,
+            _ = shutdown::drain(Some(shutdown::timeout()), pending) => {}
+            res = listen_signal(on_hangup) => return res,
+        );
+        if timeout(FLUSH_TIMEOUT, db.flush()).await.is_err() {
+            log::warn!("Database queries still in progress");
+        }
+        Ok(())
     }
 
     async fn io_loop(
@@ -234,12 +415,29 @@ impl RendezvousServer {
         listener: &mut TcpListener,
         listener2: &mut TcpListener,
         listener3: &mut TcpListener,
+        listener4: &mut Option<TcpListener>,
         socket: &mut FramedSocket,
+        workers: &UdpWorkers,
         key: &str,
     ) -> LoopFailure {
         let mut timer_check_relay = interval(Duration::from_millis(CHECK_RELAY_TIMEOUT));
+        let mut timer_cluster = interval(Duration::from_millis(cluster::ANNOUNCE_INTERVAL));
+        // no new connections while draining, the NAT test port still answers commands
+        let mut draining = shutdown::is_draining();
         loop {
             tokio::select! {
+                _ = shutdown::draining(), if !draining => {
+                    log::info!("Stop accepting connections");
+                    draining = true;
+                }
+                _ = timer_cluster.tick() => {
+                    if let Some(cluster) = self.cluster.clone() {
+                        let pm = self.pm.clone();
+                        tokio::spawn(async move {
+                            cluster.announce(pm.online_peers(REG_TIMEOUT).await).await;
+                        });
+                    }
+                }
                 _ = timer_check_relay.tick() => {
                     if self.relay_servers0.len() > 1 {
                         let rs = self.relay_servers0.clone();
@@ -252,17 +450,31 @@ impl RendezvousServer {
                 Some(data) = rx.recv() => {
                     match data {
                         Data::Msg(msg, addr) => { allow_err!(socket.send(msg.as_ref(), addr).await); }
-                        Data::RelayServers0(rs) => { self.parse_relay_servers(&rs); }
-                        Data::RelayServers(rs) => { self.relay_servers = Arc::new(rs); }
+                        Data::RelayServers0(rs) => {
+                            self.parse_relay_servers(&rs);
+                            workers.sync(self);
+                        }
+                        Data::RelayServers(rs) => {
+                            self.relay_servers = Arc::new(rs);
+                            workers.sync(self);
+                        }
+                        Data::ConfigureUpdate(cu) => {
+                            self.configure_update(cu);
+                            workers.sync(self);
+                        }
+                        Data::Control(req, res) => {
+                            res.send(self.control(req).await).ok();
+                        }
+                        Data::Reload => {
+                            self.reload();
+                            workers.sync(self);
+                        }
                     }
                 }
                 res = socket.next() => {
                     match res {
                         Some(Ok((bytes, addr))) => {
-                            if let Err(err) = self.handle_udp(&bytes, addr.into(), socket, key).await {
-                                log::error!("udp failure: {}", err);
-                                return LoopFailure::UdpSocket;
-                            }
+                            workers.dispatch(bytes, addr.into());
                         }
                         Some(Err(err)) => {
                             log::error!("udp failure: {}", err);
@@ -285,11 +497,13 @@ impl RendezvousServer {
                         }
                     }
                 }
-                res = listener3.accept() => {
+                res = listener3.accept(), if !draining => {
                     match res {
                         Ok((stream, addr))  => {
                             stream.set_nodelay(true).ok();
-                            self.handle_listener(stream, addr, key, true).await;
+                            // the WebSocket port is TLS unless there is a separate wss port
+                            let tls = listener4.is_none() && self.inner.tls.is_some();
+                            self.handle_listener(stream, addr, key, true, tls).await;
                         }
                         Err(err) => {
                            log::error!("listener3.accept failed: {}", err);
@@ -297,11 +511,23 @@ impl RendezvousServer {
                         }
                     }
                 }
-                res = listener.accept() => {
+                res = async { listener4.as_mut().unwrap().accept().await }, if listener4.is_some() && !draining => {
+                    match res {
+                        Ok((stream, addr))  => {
+                            stream.set_nodelay(true).ok();
+                            self.handle_listener(stream, addr, key, true, true).await;
+                        }
+                        Err(err) => {
+                           log::error!("listener4.accept failed: {}", err);
+                           return LoopFailure::Listener4;
+                        }
+                    }
+                }
+                res = listener.accept(), if !draining => {
                     match res {
                         Ok((stream, addr)) => {
                             stream.set_nodelay(true).ok();
-                            self.handle_listener(stream, addr, key, false).await;
+                            self.handle_listener(stream, addr, key, false, false).await;
                         }
                        Err(err) => {
                            log::error!("listener.accept failed: {}", err);
@@ -318,16 +544,18 @@ impl RendezvousServer {
         &mut self,
         bytes: &BytesMut,
         addr: SocketAddr,
-        socket: &mut FramedSocket,
         key: &str,
     ) -> ResultType<()> {
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
+            if !policy::check(addr.ip(), policy::Kind::of(&msg_in.union)).await {
+                return Ok(());
+            }
             match msg_in.union {
                 Some(rendezvous_message::Union::RegisterPeer(rp)) => {
                     // B registered
                     if !rp.id.is_empty() {
                         log::trace!("New peer registered: {:?} {:?}", &rp.id, &addr);
-                        self.update_addr(rp.id, addr, socket).await?;
+                        self.update_addr(rp.id, addr).await?;
                         if self.inner.serial > rp.serial {
                             let mut msg_out = RendezvousMessage::new();
                             msg_out.set_configure_update(ConfigUpdate {
@@ -335,7 +563,7 @@ impl RendezvousServer {
                                 rendezvous_servers: (*self.rendezvous_servers).clone(),
                                 ..Default::default()
                             });
-                            socket.send(&msg_out, addr).await?;
+                            self.send_udp(msg_out, addr)?;
                         }
                     }
                 }
@@ -346,27 +574,27 @@ impl RendezvousServer {
                     let id = rk.id;
                     let ip = addr.ip().to_string();
                     if id.len() < 6 {
-                        return send_rk_res(socket, addr, UUID_MISMATCH).await;
+                        return self.send_rk_res(addr, UUID_MISMATCH);
                     } else if !self.check_ip_blocker(&ip, &id).await {
-                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
+                        return self.send_rk_res(addr, TOO_FREQUENT);
                     }
                     
                     // Check custom key if provided
                     log::info!("Register request from {}: custom_key='{}'", addr, rk.custom_key);
                     if !rk.custom_key.is_empty() {
                         // Distinguish invalid vs overuse; allow if already bound
-                        let (valid, already, overuse) = self.pm.db.check_binding_state(&rk.custom_key, &id).await.unwrap_or((false,false,false));
+                        let (valid, already, overuse) = self.pm.keys.check_binding_state(&rk.custom_key, &id).await;
                         if !valid {
                             log::warn!("Invalid or expired custom key '{}' from client {}", rk.custom_key, addr);
-                            return send_rk_res(socket, addr, TOO_FREQUENT).await; // use mismatch for invalid
+                            return self.send_rk_res(addr, TOO_FREQUENT); // use mismatch for invalid
                         }
                         if overuse && !already {
                             log::warn!("Custom key '{}' overuse for id {} from {}", rk.custom_key, id, addr);
-                            return send_rk_res(socket, addr, TOO_FREQUENT).await; // registration path does not expose overuse code; keep as TOO_FREQUENT
+                            return self.send_rk_res(addr, TOO_FREQUENT); // registration path does not expose overuse code; keep as TOO_FREQUENT
                         }
                         // Bind if not already bound
                         if !already {
-                            let _ = self.pm.db.ensure_binding_allowed(&rk.custom_key, &id).await;
+                            self.pm.keys.ensure_binding_allowed(&rk.custom_key, &id).await;
                         }
                         log::info!("Valid custom key used for registration: {} (already_bound={})", rk.custom_key, already);
                     }
@@ -387,7 +615,7 @@ impl RendezvousServer {
                                         peer.pk,
                                     );
                                     drop(peer);
-                                    return send_rk_res(socket, addr, UUID_MISMATCH).await;
+                                    return self.send_rk_res(addr, UUID_MISMATCH);
                                 }
                             } else {
                                 log::warn!(
@@ -397,7 +625,7 @@ impl RendezvousServer {
                                     peer.uuid
                                 );
                                 drop(peer);
-                                return send_rk_res(socket, addr, UUID_MISMATCH).await;
+                                return self.send_rk_res(addr, UUID_MISMATCH);
                             }
                             let ip_changed = peer.info.ip != ip;
                             (
@@ -410,7 +638,7 @@ impl RendezvousServer {
                     if req_pk.1.elapsed().as_secs() > 6 {
                         req_pk.0 = 0;
                     } else if req_pk.0 > 2 {
-                        return send_rk_res(socket, addr, TOO_FREQUENT).await;
+                        return self.send_rk_res(addr, TOO_FREQUENT);
                     }
                     req_pk.0 += 1;
                     req_pk.1 = Instant::now();
@@ -442,7 +670,7 @@ impl RendezvousServer {
                         result: register_pk_response::Result::OK.into(),
                         ..Default::default()
                     });
-                    socket.send(&msg_out, addr).await?
+                    self.send_udp(msg_out, addr)?;
                 }
                 Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
                     if self.pm.is_in_memory(&ph.id).await {
@@ -457,30 +685,15 @@ impl RendezvousServer {
                     }
                 }
                 Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
-                    self.handle_hole_sent(phs, addr, Some(socket)).await?;
+                    self.handle_hole_sent(phs, addr, true).await?;
                 }
                 Some(rendezvous_message::Union::LocalAddr(la)) => {
-                    self.handle_local_addr(la, addr, Some(socket)).await?;
+                    self.handle_local_addr(la, addr, true).await?;
                 }
-                Some(rendezvous_message::Union::ConfigureUpdate(mut cu)) => {
+                Some(rendezvous_message::Union::ConfigureUpdate(cu)) => {
                     if try_into_v4(addr).ip().is_loopback() && cu.serial > self.inner.serial {
-                        let mut inner: Inner = (*self.inner).clone();
-                        inner.serial = cu.serial;
-                        self.inner = Arc::new(inner);
-                        self.rendezvous_servers = Arc::new(
-                            cu.rendezvous_servers
-                                .drain(..)
-                                .filter(|x| {
-                                    !x.is_empty()
-                                        && test_if_valid_server(x, "rendezvous-server").is_ok()
-                                })
-                                .collect(),
-                        );
-                        log::info!(
-                            "configure updated: serial={} rendezvous-servers={:?}",
-                            self.inner.serial,
-                            self.rendezvous_servers
-                        );
+                        // applied by the main loop, which then syncs all workers
+                        self.tx.send(Data::ConfigureUpdate(cu))?;
                     }
                 }
                 Some(rendezvous_message::Union::SoftwareUpdate(su)) => {
@@ -490,7 +703,7 @@ impl RendezvousServer {
                             url: self.inner.software_url.clone(),
                             ..Default::default()
                         });
-                        socket.send(&msg_out, addr).await?;
+                        self.send_udp(msg_out, addr)?;
                     }
                 }
                 _ => {}
@@ -510,6 +723,9 @@ impl RendezvousServer {
     ) -> bool {
         log::info!("Received {} bytes from {}: {:?}", bytes.len(), addr, &bytes[..std::cmp::min(50, bytes.len())]);
         if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(bytes) {
+            if !policy::check(addr.ip(), policy::Kind::of(&msg_in.union)).await {
+                return false;
+            }
             match msg_in.union {
                 Some(rendezvous_message::Union::PunchHoleRequest(ph)) => {
                     // there maybe several attempt, so sink can be none
@@ -530,6 +746,11 @@ impl RendezvousServer {
                         msg_out.set_request_relay(rf);
                         let peer_addr = peer.read().await.socket_addr;
                         self.tx.send(Data::Msg(msg_out.into(), peer_addr)).ok();
+                    } else if let Some((node, peer_addr)) = self.cluster_locate(&rf.id).await {
+                        let mut msg_out = RendezvousMessage::new();
+                        rf.socket_addr = AddrMangle::encode(addr).into();
+                        msg_out.set_request_relay(rf);
+                        allow_err!(self.deliver(node, peer_addr, addr, &msg_out).await);
                     }
                     return true;
                 }
@@ -543,21 +764,25 @@ impl RendezvousServer {
                     }
                     let mut msg_out = RendezvousMessage::new();
                     if !rr.relay_server.is_empty() {
-                        if self.is_lan(addr_b) {
+                        if let Some(relay) = self.lan_relay(addr_b) {
                             // https://github.com/rustdesk/rustdesk-server/issues/24
-                            rr.relay_server = self.inner.local_ip.clone();
-                        } else if rr.relay_server == self.inner.local_ip {
+                            rr.relay_server = relay;
+                        } else if self.is_local_relay(&rr.relay_server) {
                             rr.relay_server = self.get_relay_server(addr.ip(), addr_b.ip());
                         }
                     }
                     msg_out.set_relay_response(rr);
-                    allow_err!(self.send_to_tcp_sync(msg_out, addr_b).await);
+                    if let Some(node) = self.cluster_origin(addr_b).await {
+                        allow_err!(self.reply(node, addr_b, &msg_out).await);
+                    } else {
+                        allow_err!(self.send_to_tcp_sync(msg_out, addr_b).await);
+                    }
                 }
                 Some(rendezvous_message::Union::PunchHoleSent(phs)) => {
-                    allow_err!(self.handle_hole_sent(phs, addr, None).await);
+                    allow_err!(self.handle_hole_sent(phs, addr, false).await);
                 }
                 Some(rendezvous_message::Union::LocalAddr(la)) => {
-                    allow_err!(self.handle_local_addr(la, addr, None).await);
+                    allow_err!(self.handle_local_addr(la, addr, false).await);
                 }
                 Some(rendezvous_message::Union::TestNatRequest(tar)) => {
                     let mut msg_out = RendezvousMessage::new();
@@ -600,7 +825,7 @@ impl RendezvousServer {
                     } else {
                         // Check if this is a custom key registration
                         if !rk.custom_key.is_empty() {
-                            if self.pm.db.is_key_valid(&rk.custom_key).await.unwrap_or(false) {
+                            if self.pm.keys.is_key_valid(&rk.custom_key).await {
                                 register_pk_response::Result::OK
                             } else {
                                 register_pk_response::Result::TOO_FREQUENT // Use as invalid key response
@@ -629,7 +854,6 @@ impl RendezvousServer {
         &mut self,
         id: String,
         socket_addr: SocketAddr,
-        socket: &mut FramedSocket,
     ) -> ResultType<()> {
         let (request_pk, ip_change) = if let Some(old) = self.pm.get_in_memory(&id).await {
             let mut old = old.write().await;
@@ -665,21 +889,21 @@ impl RendezvousServer {
             request_pk,
             ..Default::default()
         });
-        socket.send(&msg_out, socket_addr).await
+        self.send_udp(msg_out, socket_addr)
     }
 
     #[inline]
-    async fn handle_hole_sent<'a>(
+    async fn handle_hole_sent(
         &mut self,
         phs: PunchHoleSent,
         addr: SocketAddr,
-        socket: Option<&'a mut FramedSocket>,
+        udp: bool,
     ) -> ResultType<()> {
         // punch hole sent from B, tell A that B is ready to be connected
         let addr_a = AddrMangle::decode(&phs.socket_addr);
         log::debug!(
             "{} punch hole response to {:?} from {:?}",
-            if socket.is_none() { "TCP" } else { "UDP" },
+            if udp { "UDP" } else { "TCP" },
             &addr_a,
             &addr
         );
@@ -694,8 +918,10 @@ impl RendezvousServer {
             p.set_nat_type(t);
         }
         msg_out.set_punch_hole_response(p);
-        if let Some(socket) = socket {
-            socket.send(&msg_out, addr_a).await?;
+        if let Some(node) = self.cluster_origin(addr_a).await {
+            self.reply(node, addr_a, &msg_out).await?;
+        } else if udp {
+            self.send_udp(msg_out, addr_a)?;
         } else {
             self.send_to_tcp(msg_out, addr_a).await;
         }
@@ -703,17 +929,17 @@ impl RendezvousServer {
     }
 
     #[inline]
-    async fn handle_local_addr<'a>(
+    async fn handle_local_addr(
         &mut self,
         la: LocalAddr,
         addr: SocketAddr,
-        socket: Option<&'a mut FramedSocket>,
+        udp: bool,
     ) -> ResultType<()> {
         // relay local addrs of B to A
         let addr_a = AddrMangle::decode(&la.socket_addr);
         log::debug!(
             "{} local addrs response to {:?} from {:?}",
-            if socket.is_none() { "TCP" } else { "UDP" },
+            if udp { "UDP" } else { "TCP" },
             &addr_a,
             &addr
         );
@@ -726,8 +952,10 @@ impl RendezvousServer {
         };
         p.set_is_local(true);
         msg_out.set_punch_hole_response(p);
-        if let Some(socket) = socket {
-            socket.send(&msg_out, addr_a).await?;
+        if let Some(node) = self.cluster_origin(addr_a).await {
+            self.reply(node, addr_a, &msg_out).await?;
+        } else if udp {
+            self.send_udp(msg_out, addr_a)?;
         } else {
             self.send_to_tcp(msg_out, addr_a).await;
         }
@@ -741,7 +969,7 @@ impl RendezvousServer {
         ph: PunchHoleRequest,
         key: &str,
         ws: bool,
-    ) -> ResultType<(RendezvousMessage, Option<SocketAddr>)> {
+    ) -> ResultType<(RendezvousMessage, PunchTarget)> {
         let mut ph = ph;
         
         // 首先检查自定义密钥
@@ -750,7 +978,7 @@ impl RendezvousServer {
         
         if !ph.licence_key.is_empty() {
             // Distinguish invalid vs overuse; do not impact already-bound ids
-            let (valid, already, overuse) = self.pm.db.check_binding_state(&ph.licence_key, &ph.id).await.unwrap_or((false,false,false));
+            let (valid, already, overuse) = self.pm.keys.check_binding_state(&ph.licence_key, &ph.id).await;
             if !valid {
                 log::warn!("Invalid custom key '{}' from client {}", ph.licence_key, addr);
                 let mut msg_out = RendezvousMessage::new();
@@ -758,7 +986,7 @@ impl RendezvousServer {
                     failure: punch_hole_response::Failure::LICENSE_MISMATCH.into(),
                     ..Default::default()
                 });
-                return Ok((msg_out, None));
+                return Ok((msg_out, PunchTarget::Requester));
             }
             if overuse && !already {
                 log::warn!("Custom key '{}' overuse for id {} from {}", ph.licence_key, ph.id, addr);
@@ -767,11 +995,11 @@ impl RendezvousServer {
                     failure: punch_hole_response::Failure::LICENSE_OVERUSE.into(),
                     ..Default::default()
                 });
-                return Ok((msg_out, None));
+                return Ok((msg_out, PunchTarget::Requester));
             }
             // Bind when valid and not yet bound
             if !already {
-                let _ = self.pm.db.ensure_binding_allowed(&ph.licence_key, &ph.id).await;
+                self.pm.keys.ensure_binding_allowed(&ph.licence_key, &ph.id).await;
             }
             log::info!("Client {} authenticated with custom key: {} (already_bound={})", addr, ph.licence_key, already);
         } else if !key.is_empty() {
@@ -782,7 +1010,15 @@ impl RendezvousServer {
                 failure: punch_hole_response::Failure::LICENSE_MISMATCH.into(),
                 ..Default::default()
             });
-            return Ok((msg_out, None));
+            return Ok((msg_out, PunchTarget::Requester));
+        }
+        if !policy::check_lookups(addr.ip(), std::slice::from_ref(&ph.id)).await[0] {
+            let mut msg_out = RendezvousMessage::new();
+            msg_out.set_punch_hole_response(PunchHoleResponse {
+                failure: punch_hole_response::Failure::OFFLINE.into(),
+                ..Default::default()
+            });
+            return Ok((msg_out, PunchTarget::Requester));
         }
         let id = ph.id;
         // punch hole request from A, relay to B,
@@ -790,92 +1026,116 @@ impl RendezvousServer {
         // fetch local addrs if in same intranet.
         // because punch hole won't work if in the same intranet,
         // all routers will drop such self-connections.
-        if let Some(peer) = self.pm.get(&id).await {
-            let (elapsed, peer_addr) = {
+        let local = match self.pm.get(&id).await {
+            Some(peer) => {
                 let r = peer.read().await;
-                (r.last_reg_time.elapsed().as_millis() as i32, r.socket_addr)
-            };
-            if elapsed >= REG_TIMEOUT {
-                let mut msg_out = RendezvousMessage::new();
-                msg_out.set_punch_hole_response(PunchHoleResponse {
-                    failure: punch_hole_response::Failure::OFFLINE.into(),
-                    ..Default::default()
-                });
-                return Ok((msg_out, None));
-            }
-            let mut msg_out = RendezvousMessage::new();
-            let peer_is_lan = self.is_lan(peer_addr);
-            let is_lan = self.is_lan(addr);
-            let mut relay_server = self.get_relay_server(addr.ip(), peer_addr.ip());
-            if ALWAYS_USE_RELAY.load(Ordering::SeqCst) || (peer_is_lan ^ is_lan) {
-                if peer_is_lan {
-                    // https://github.com/rustdesk/rustdesk-server/issues/24
-                    relay_server = self.inner.local_ip.clone()
-                }
-                ph.nat_type = NatType::SYMMETRIC.into(); // will force relay
+                Some((r.last_reg_time.elapsed().as_millis() as i32, r.socket_addr))
             }
-            let same_intranet: bool = !ws
-                && (peer_is_lan && is_lan || {
-                    match (peer_addr, addr) {
-                        (SocketAddr::V4(a), SocketAddr::V4(b)) => a.ip() == b.ip(),
-                        (SocketAddr::V6(a), SocketAddr::V6(b)) => a.ip() == b.ip(),
-                        _ => false,
-                    }
-                });
-            let socket_addr = AddrMangle::encode(addr).into();
-            if same_intranet {
-                log::debug!(
-                    "Fetch local addr {:?} {:?} request from {:?}",
-                    id,
-                    peer_addr,
-                    addr
-                );
-                msg_out.set_fetch_local_addr(FetchLocalAddr {
-                    socket_addr,
-                    relay_server,
-                    ..Default::default()
-                });
-            } else {
-                log::debug!(
-                    "Punch hole {:?} {:?} request from {:?}",
-                    id,
-                    peer_addr,
-                    addr
-                );
-                msg_out.set_punch_hole(PunchHole {
-                    socket_addr,
-                    nat_type: ph.nat_type,
-                    relay_server,
-                    ..Default::default()
-                });
+            None => None,
+        };
+        let (peer_addr, target) = match local {
+            Some((elapsed, peer_addr)) if elapsed < REG_TIMEOUT => {
+                (peer_addr, PunchTarget::Peer(peer_addr))
             }
-            Ok((msg_out, Some(peer_addr)))
+            _ => match self.cluster_locate(&id).await {
+                Some((node, peer_addr)) => (peer_addr, PunchTarget::Node(node, peer_addr)),
+                None => {
+                    let mut msg_out = RendezvousMessage::new();
+                    msg_out.set_punch_hole_response(PunchHoleResponse {
+                        failure: if local.is_some() || policy::uniform_failure() {
+                            punch_hole_response::Failure::OFFLINE
+                        } else {
+                            punch_hole_response::Failure::ID_NOT_EXIST
+                        }
+                        .into(),
+                        ..Default::default()
+                    });
+                    return Ok((msg_out, PunchTarget::Requester));
+                }
+            },
+        };
+        let mut msg_out = RendezvousMessage::new();
+        let site = self.lan_relay(addr);
+        let peer_site = self.lan_relay(peer_addr);
+        let same_site = site.is_some() && site == peer_site;
+        // the peer gets the relay of its own site, the requester likewise in
+        // RelayResponse, see relay_for
+        let relay_server = relay_for(&self.inner.sites, peer_addr, || {
+            self.get_relay_server(addr.ip(), peer_addr.ip())
+        });
+        // peers of different sites only reach each other through a relay
+        let force_relay = ALWAYS_USE_RELAY.load(Ordering::SeqCst)
+            || ((site.is_some() || peer_site.is_some()) && !same_site);
+        if force_relay {
+            ph.nat_type = NatType::SYMMETRIC.into(); // will force relay
+        }
+        let same_intranet: bool = !ws && (same_site || same_intranet(peer_addr.ip(), addr.ip()));
+        let socket_addr = AddrMangle::encode(addr).into();
+        if same_intranet {
+            log::debug!(
+                "Fetch local addr {:?} {:?} request from {:?}",
+                id,
+                peer_addr,
+                addr
+            );
+            msg_out.set_fetch_local_addr(FetchLocalAddr {
+                socket_addr,
+                relay_server,
+                ..Default::default()
+            });
         } else {
-            let mut msg_out = RendezvousMessage::new();
-            msg_out.set_punch_hole_response(PunchHoleResponse {
-                failure: punch_hole_response::Failure::ID_NOT_EXIST.into(),
+            log::debug!(
+                "Punch hole {:?} {:?} request from {:?}",
+                id,
+                peer_addr,
+                addr
+            );
+            msg_out.set_punch_hole(PunchHole {
+                socket_addr,
+                nat_type: ph.nat_type,
+                relay_server,
                 ..Default::default()
             });
-            Ok((msg_out, None))
         }
+        Ok((msg_out, target))
     }
 
     #[inline]
     async fn handle_online_request(
         &mut self,
         stream: &mut FramedStream,
-        peers: Vec<String>,
+        addr: SocketAddr,
+        or: OnlineRequest,
     ) -> ResultType<()> {
+        let peers = or.peers;
         let mut states = BytesMut::zeroed((peers.len() + 7) / 8);
-        for (i, peer_id) in peers.iter().enumerate() {
-            if let Some(peer) = self.pm.get_in_memory(peer_id).await {
-                let elapsed = peer.read().await.last_reg_time.elapsed().as_millis() as i32;
+        // refused or capped IDs are reported offline, so the answer has the usual shape
+        let mut n = peers.len();
+        if policy::online_max_ids() > 0 {
+            n = n.min(policy::online_max_ids());
+        }
+        if policy::online_auth() && !self.is_authenticated(&or.id, addr).await {
+            log::debug!("Online request of {:?} from {} refused", or.id, addr);
+            policy::online_refused();
+            n = 0;
+        }
+        let allowed = policy::check_lookups(addr.ip(), &peers[..n]).await;
+        for (i, peer_id) in peers[..n].iter().enumerate() {
+            if !allowed[i] {
+                continue;
+            }
+            let online = match self.pm.get_in_memory(peer_id).await {
+                Some(peer) => {
+                    let elapsed = peer.read().await.last_reg_time.elapsed().as_millis() as i32;
+                    elapsed < REG_TIMEOUT
+                }
+                None => false,
+            } || self.cluster_locate(peer_id).await.is_some();
+            if online {
                 // bytes index from left to right
                 let states_idx = i / 8;
                 let bit_idx = 7 - i % 8;
-                if elapsed < REG_TIMEOUT {
-                    states[states_idx] |= 0x01 << bit_idx;
-                }
+                states[states_idx] |= 0x01 << bit_idx;
             }
         }
 
@@ -889,6 +1149,37 @@ impl RendezvousServer {
         Ok(())
     }
 
+    // `id` has a live registration from the IP of `addr`. A licence key bound
+    // to `id` is not enough, anyone can claim the ID.
+    async fn is_authenticated(&self, id: &str, addr: SocketAddr) -> bool {
+        if id.is_empty() {
+            return false;
+        }
+        let Some(peer) = self.pm.get_in_memory(id).await else {
+            return false;
+        };
+        let r = peer.read().await;
+        (r.last_reg_time.elapsed().as_millis() as i32) < REG_TIMEOUT
+            && try_into_v4(r.socket_addr).ip() == try_into_v4(addr).ip()
+    }
+
+    // UDP replies are sent by the main loop, which owns the socket
+    #[inline]
+    fn send_udp(&self, msg: RendezvousMessage, addr: SocketAddr) -> ResultType<()> {
+        self.tx.send(Data::Msg(msg.into(), addr))?;
+        Ok(())
+    }
+
+    #[inline]
+    fn send_rk_res(&self, addr: SocketAddr, res: register_pk_response::Result) -> ResultType<()> {
+        let mut msg_out = RendezvousMessage::new();
+        msg_out.set_register_pk_response(RegisterPkResponse {
+            result: res.into(),
+            ..Default::default()
+        });
+        self.send_udp(msg_out, addr)
+    }
+
     #[inline]
     async fn send_to_tcp(&mut self, msg: RendezvousMessage, addr: SocketAddr) {
         let mut tcp = self.tcp_punch.lock().await.remove(&try_into_v4(addr));
@@ -932,11 +1223,11 @@ impl RendezvousServer {
         key: &str,
         ws: bool,
     ) -> ResultType<()> {
-        let (msg, to_addr) = self.handle_punch_hole_request(addr, ph, key, ws).await?;
-        if let Some(addr) = to_addr {
-            self.tx.send(Data::Msg(msg.into(), addr))?;
-        } else {
-            self.send_to_tcp_sync(msg, addr).await?;
+        let (msg, target) = self.handle_punch_hole_request(addr, ph, key, ws).await?;
+        match target {
+            PunchTarget::Peer(peer_addr) => self.tx.send(Data::Msg(msg.into(), peer_addr))?,
+            PunchTarget::Node(node, peer_addr) => self.deliver(node, peer_addr, addr, &msg).await?,
+            PunchTarget::Requester => self.send_to_tcp_sync(msg, addr).await?,
         }
         Ok(())
     }
@@ -948,14 +1239,12 @@ impl RendezvousServer {
         ph: PunchHoleRequest,
         key: &str,
     ) -> ResultType<()> {
-        let (msg, to_addr) = self.handle_punch_hole_request(addr, ph, key, false).await?;
-        self.tx.send(Data::Msg(
-            msg.into(),
-            match to_addr {
-                Some(addr) => addr,
-                None => addr,
-            },
-        ))?;
+        let (msg, target) = self.handle_punch_hole_request(addr, ph, key, false).await?;
+        match target {
+            PunchTarget::Peer(peer_addr) => self.tx.send(Data::Msg(msg.into(), peer_addr))?,
+            PunchTarget::Node(node, peer_addr) => self.deliver(node, peer_addr, addr, &msg).await?,
+            PunchTarget::Requester => self.tx.send(Data::Msg(msg.into(), addr))?,
+        }
         Ok(())
     }
 
@@ -989,20 +1278,182 @@ impl RendezvousServer {
         true
     }
 
+    #[inline]
+    async fn cluster_locate(&self, id: &str) -> Option<(SocketAddr, SocketAddr)> {
+        match &self.cluster {
+            Some(cluster) => cluster.locate(id).await,
+            None => None,
+        }
+    }
+
+    #[inline]
+    async fn cluster_origin(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        match &self.cluster {
+            Some(cluster) => cluster.origin(addr).await,
+            None => None,
+        }
+    }
+
+    async fn deliver(
+        &self,
+        node: SocketAddr,
+        to: SocketAddr,
+        from: SocketAddr,
+        msg: &RendezvousMessage,
+    ) -> ResultType<()> {
+        if let Some(cluster) = &self.cluster {
+            cluster.deliver(node, to, from, msg).await?;
+        }
+        Ok(())
+    }
+
+    async fn reply(
+        &self,
+        node: SocketAddr,
+        to: SocketAddr,
+        msg: &RendezvousMessage,
+    ) -> ResultType<()> {
+        if let Some(cluster) = &self.cluster {
+            cluster.reply(node, to, msg).await?;
+        }
+        Ok(())
+    }
+
+    // Deliveries and replies received from other cluster nodes
+    async fn handle_cluster(&mut self, msg: ClusterMsg) {
+        match msg {
+            ClusterMsg::Deliver { to, msg, .. } => {
+                if let Ok(msg) = RendezvousMessage::parse_from_bytes(&msg) {
+                    allow_err!(self.send_udp(msg, to));
+                }
+            }
+            ClusterMsg::Reply { to, msg } => {
+                if let Ok(msg) = RendezvousMessage::parse_from_bytes(&msg) {
+                    if self.tcp_punch.lock().await.contains_key(&try_into_v4(to)) {
+                        self.send_to_tcp(msg, to).await;
+                    } else {
+                        allow_err!(self.send_udp(msg, to));
+                    }
+                }
+            }
+            ClusterMsg::Peers(_) => {}
+        }
+    }
+
+    fn configure_update(&mut self, mut cu: ConfigUpdate) {
+        if cu.serial <= self.inner.serial {
+            return;
+        }
+        let mut inner: Inner = (*self.inner).clone();
+        inner.serial = cu.serial;
+        self.inner = Arc::new(inner);
+        self.rendezvous_servers = Arc::new(
+            cu.rendezvous_servers
+                .drain(..)
+                .filter(|x| !x.is_empty() && test_if_valid_server(x, "rendezvous-server").is_ok())
+                .collect(),
+        );
+        log::info!(
+            "configure updated: serial={} rendezvous-servers={:?}",
+            self.inner.serial,
+            self.rendezvous_servers
+        );
+    }
+
     fn parse_relay_servers(&mut self, relay_servers: &str) {
         let rs = get_servers(relay_servers, "relay-servers");
         self.relay_servers0 = Arc::new(rs);
         self.relay_servers = self.relay_servers0.clone();
     }
 
-    fn get_relay_server(&self, _pa: IpAddr, _pb: IpAddr) -> String {
+    // Apply reloaded relay/rendezvous servers, serial, software url and LAN
+    // settings at once, bumping the serial so clients fetch the new config.
+    fn reload(&mut self) {
+        let mut inner: Inner = (*self.inner).clone();
+        let mut changed = false;
+        let config = settings::get();
+        let software_url = config.software_url.clone().unwrap_or_default();
+        if software_url != inner.software_url {
+            log::info!("software-url: {:?} -> {:?}", inner.software_url, software_url);
+            inner.version = hbb_common::get_version_from_url(&software_url);
+            inner.software_url = software_url;
+            changed = true;
+        }
+        let (local_ip, local_ip6, sites) = lan_settings(&config);
+        if (&local_ip, &local_ip6, &sites) != (&inner.local_ip, &inner.local_ip6, &inner.sites) {
+            log::info!("local-ip: {:?} -> {:?}", inner.local_ip, local_ip);
+            log::info!("local-ip6: {:?} -> {:?}", inner.local_ip6, local_ip6);
+            for site in sites.iter() {
+                log::info!("lan: {} relay: {:?}", site.network, site.relay);
+            }
+            inner.local_ip = local_ip;
+            inner.local_ip6 = local_ip6;
+            inner.sites = sites;
+            changed = true;
+        }
+        let rendezvous_servers =
+            get_servers(&joined(&config.rendezvous_servers), "rendezvous-servers");
+        if rendezvous_servers != *self.rendezvous_servers {
+            log::info!(
+                "rendezvous-servers: {:?} -> {:?}",
+                self.rendezvous_servers,
+                rendezvous_servers
+            );
+            self.rendezvous_servers = Arc::new(rendezvous_servers);
+            changed = true;
+        }
+        let relay_servers = get_servers(&joined(&config.relay_servers), "relay-servers");
+        if relay_servers != *self.relay_servers0 {
+            log::info!(
+                "relay-servers: {:?} -> {:?}",
+                self.relay_servers0,
+                relay_servers
+            );
+            self.relay_servers0 = Arc::new(relay_servers);
+            self.relay_servers = self.relay_servers0.clone();
+            changed = true;
+        }
+        let serial = config.serial.unwrap_or(0);
+        let serial = if serial > inner.serial {
+            serial
+        } else if changed {
+            inner.serial + 1
+        } else {
+            inner.serial
+        };
+        if serial != inner.serial {
+            log::info!("serial: {} -> {}", inner.serial, serial);
+            inner.serial = serial;
+        } else {
+            log::info!("No server settings changed");
+        }
+        self.inner = Arc::new(inner);
+    }
+
+    fn reload_geo() -> ResultType<String> {
+        geo::reload(
+            &data_path(&get_arg_or("geo-db", "GeoLite2-City.mmdb".to_owned())),
+            &data_path(&get_arg_or("relay-meta", "relay_meta.json".to_owned())),
+        )
+    }
+
+    fn get_relay_server(&self, pa: IpAddr, pb: IpAddr) -> String {
         if self.relay_servers.is_empty() {
             return "".to_owned();
         } else if self.relay_servers.len() == 1 {
             return self.relay_servers[0].clone();
         }
-        let i = ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst) % self.relay_servers.len();
-        self.relay_servers[i].clone()
+        let loads = relay_status::saturations(&self.relay_servers);
+        let debug = log::log_enabled!(log::Level::Debug);
+        if let Some((i, explain)) = geo::select(&self.relay_servers, &loads, pa, pb, debug) {
+            if debug {
+                log::debug!("Relay for {} and {}:\n{}", pa, pb, explain.trim_end());
+            }
+            return self.relay_servers[i].clone();
+        }
+        let candidates = relay_status::least_loaded(&loads);
+        let i = ROTATION_RELAY_SERVER.fetch_add(1, Ordering::SeqCst) % candidates.len();
+        self.relay_servers[candidates[i]].clone()
     }
 
     async fn check_cmd(&self, cmd: &str) -> String {
@@ -1013,13 +1464,21 @@ impl RendezvousServer {
         match fds.next() {
             Some("h") => {
                 res = format!(
-                    "{}\n{}\n{}\n{}\n{}\n{}\n",
+                    "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
                     "relay-servers(rs) <separated by ,>",
+                    "relay-load(rl)",
                     "reload-geo(rg)",
                     "ip-blocker(ib) [<ip>|<number>] [-]",
                     "ip-changes(ic) [<id>|<number>] [-]",
                     "always-use-relay(aur)",
-                    "test-geo(tg) <ip1> <ip2>"
+                    "test-geo(tg) <ip1> <ip2>",
+                    "policy(p) [<ip> -]",
+                    "deny-add(da) <ip|cidr> [duration(e.g. 30m,2h,7d)] [reason]",
+                    "deny-remove(dr) <ip>",
+                    "deny(d) [ip]",
+                    "allow-add(aa) <ip|cidr> [duration(e.g. 30m,2h,7d)] [reason]",
+                    "allow-remove(ar) <ip>",
+                    "allow(a) [ip]"
                 )
             }
             Some("relay-servers" | "rs") => {
@@ -1133,16 +1592,76 @@ impl RendezvousServer {
                     );
                 }
             }
+            Some("relay-load" | "rl") => {
+                let loads = relay_status::saturations(&self.relay_servers0);
+                for (relay, load) in self.relay_servers0.iter().zip(loads) {
+                    let state = if !self.relay_servers.contains(relay) {
+                        "unavailable".to_owned()
+                    } else if let Some(load) = load {
+                        format!("{:.0}%", load * 100.)
+                    } else {
+                        "unknown".to_owned()
+                    };
+                    let _ = writeln!(res, "{relay}: {state}");
+                }
+            }
+            Some("policy" | "p") => match fds.next().map(|x| x.parse::<IpAddr>()) {
+                Some(Ok(ip)) => {
+                    if fds.next() == Some("-") {
+                        res = format!("{}\n", policy::unblock(ip));
+                    }
+                }
+                Some(Err(err)) => res = format!("{err}\n"),
+                None => {
+                    res = serde_json::to_string_pretty(&policy::stats()).unwrap_or_default();
+                    res.push('\n');
+                }
+            },
+            Some(cmd @ ("deny-add" | "da" | "allow-add" | "aa")) => {
+                let list = Self::policy_list(cmd);
+                let args: Vec<&str> = fds.collect();
+                if !args.is_empty() {
+                    if let Err(err) = list.add_from_args(&args, "cmd").await {
+                        res = format!("{err}\n");
+                    }
+                }
+            }
+            Some(cmd @ ("deny-remove" | "dr" | "allow-remove" | "ar")) => {
+                if let Some(ip) = fds.next() {
+                    if let Err(err) = Self::policy_list(cmd).remove(ip).await {
+                        res = format!("{err}\n");
+                    }
+                }
+            }
+            Some(cmd @ ("deny" | "d" | "allow" | "a")) => {
+                let list = Self::policy_list(cmd);
+                if let Some(ip) = fds.next() {
+                    res = format!("{}\n", list.contains(ip).await);
+                } else {
+                    for x in list.list().await {
+                        let _ = writeln!(res, "{}", x.to_line());
+                    }
+                }
+            }
+            Some("reload-geo" | "rg") => {
+                res = match Self::reload_geo() {
+                    Ok(v) => format!("{v}\n"),
+                    Err(err) => format!("{err}\n"),
+                };
+            }
             Some("test-geo" | "tg") => {
                 if let Some(rs) = fds.next() {
                     if let Ok(a) = rs.parse::<IpAddr>() {
-                        if let Some(rs) = fds.next() {
-                            if let Ok(b) = rs.parse::<IpAddr>() {
-                                res = format!("{:?}", self.get_relay_server(a, b));
-                            }
-                        } else {
-                            res = format!("{:?}", self.get_relay_server(a, a));
-                        }
+                        let b = fds.next().and_then(|x| x.parse::<IpAddr>().ok()).unwrap_or(a);
+                        let loads = relay_status::saturations(&self.relay_servers);
+                        res = match geo::select(&self.relay_servers, &loads, a, b, true) {
+                            Some((_, explain)) => explain,
+                            None => format!(
+                                "geo selection not available{}, least loaded: {:?}\n",
+                                if geo::is_enabled() { "" } else { " (no geo database)" },
+                                self.get_relay_server(a, b)
+                            ),
+                        };
                     }
                 }
             }
@@ -1151,12 +1670,170 @@ impl RendezvousServer {
         res
     }
 
+    #[inline]
+    fn policy_list(cmd: &str) -> &'static IpList {
+        if cmd.starts_with('d') {
+            policy::deny_list()
+        } else {
+            policy::allow_list()
+        }
+    }
+
+    /// JSON counterpart of `check_cmd`, for the authenticated control API.
+    async fn control(&self, req: CtlRequest) -> CtlResponse {
+        use serde_json::json;
+
+        let arg = |i: usize| req.args.get(i).map(|x| x.trim()).filter(|x| !x.is_empty());
+        match req.cmd.as_str() {
+            "relay-servers" => {
+                if let Some(rs) = arg(0) {
+                    self.tx.send(Data::RelayServers0(rs.to_owned())).ok();
+                    CtlResponse::ok(json!(null))
+                } else {
+                    CtlResponse::ok(json!({
+                        "configured": *self.relay_servers0,
+                        "available": *self.relay_servers,
+                    }))
+                }
+            }
+            "relay-load" => {
+                let loads = relay_status::saturations(&self.relay_servers0);
+                let v: Vec<_> = self
+                    .relay_servers0
+                    .iter()
+                    .zip(loads)
+                    .map(|(relay, load)| {
+                        json!({
+                            "relay": relay,
+                            "available": self.relay_servers.contains(relay),
+                            "saturation": load,
+                        })
+                    })
+                    .collect();
+                CtlResponse::ok(v)
+            }
+            "reload-geo" => match Self::reload_geo() {
+                Ok(v) => CtlResponse::ok(v),
+                Err(err) => CtlResponse::err(err.to_string()),
+            },
+            "test-geo" => {
+                let Some(a) = arg(0).and_then(|x| x.parse::<IpAddr>().ok()) else {
+                    return CtlResponse::err("usage: test-geo <ip1> [ip2]");
+                };
+                let b = arg(1).and_then(|x| x.parse::<IpAddr>().ok()).unwrap_or(a);
+                let loads = relay_status::saturations(&self.relay_servers);
+                let explain = geo::select(&self.relay_servers, &loads, a, b, true).map(|x| x.1);
+                CtlResponse::ok(json!({
+                    "relay": self.get_relay_server(a, b),
+                    "geo": explain,
+                }))
+            }
+            "ip-blocker" => {
+                let mut lock = IP_BLOCKER.lock().await;
+                lock.retain(|&_, (a, b)| {
+                    a.1.elapsed().as_secs() <= IP_BLOCK_DUR
+                        || b.1.elapsed().as_secs() <= DAY_SECONDS
+                });
+                let entry = |ip: &String, (a, b): &((u32, Instant), (HashSet<String>, Instant))| {
+                    json!({
+                        "ip": ip,
+                        "requests": a.0,
+                        "requests_secs": a.1.elapsed().as_secs(),
+                        "ids": b.0.len(),
+                        "ids_secs": b.1.elapsed().as_secs(),
+                    })
+                };
+                // a number is the page offset of the text command, the whole list is returned
+                match arg(0).filter(|x| x.parse::<i32>().is_err()) {
+                    Some(ip) => {
+                        let ip = ip.to_owned();
+                        let v = lock.get(&ip).map(|x| entry(&ip, x));
+                        if arg(1) == Some("-") {
+                            lock.remove(&ip);
+                        }
+                        CtlResponse::ok(v)
+                    }
+                    None => {
+                        CtlResponse::ok(lock.iter().map(|(k, v)| entry(k, v)).collect::<Vec<_>>())
+                    }
+                }
+            }
+            "ip-changes" => {
+                let mut lock = IP_CHANGES.lock().await;
+                lock.retain(|&_, v| v.0.elapsed().as_secs() < IP_CHANGE_DUR_X2 && v.1.len() > 1);
+                let entry = |id: &String, (tm, ips): &(Instant, HashMap<String, i32>)| {
+                    json!({ "id": id, "secs": tm.elapsed().as_secs(), "ips": ips })
+                };
+                match arg(0) {
+                    Some(id) => {
+                        let id = id.to_owned();
+                        let v = lock.get(&id).map(|x| entry(&id, x));
+                        if arg(1) == Some("-") {
+                            lock.remove(&id);
+                        }
+                        CtlResponse::ok(v)
+                    }
+                    None => {
+                        CtlResponse::ok(lock.iter().map(|(k, v)| entry(k, v)).collect::<Vec<_>>())
+                    }
+                }
+            }
+            "always-use-relay" => {
+                if let Some(v) = arg(0) {
+                    let Some(v) = parse_flag(v) else {
+                        return CtlResponse::err(format!("invalid value: {v}"));
+                    };
+                    ALWAYS_USE_RELAY.store(v, Ordering::SeqCst);
+                }
+                CtlResponse::ok(ALWAYS_USE_RELAY.load(Ordering::SeqCst))
+            }
+            "policy" => match arg(0) {
+                Some(ip) => {
+                    let (Ok(ip), Some("-")) = (ip.parse::<IpAddr>(), arg(1)) else {
+                        return CtlResponse::err("usage: policy [<ip> -]");
+                    };
+                    CtlResponse::ok(policy::unblock(ip))
+                }
+                None => CtlResponse::ok(policy::stats()),
+            },
+            cmd @ ("deny-add" | "allow-add") => {
+                match Self::policy_list(cmd).add_from_args(&req.args, "api").await {
+                    Ok(n) => CtlResponse::ok(n),
+                    Err(err) => CtlResponse::err(err.to_string()),
+                }
+            }
+            cmd @ ("deny-remove" | "allow-remove") => {
+                let Some(ip) = arg(0) else {
+                    return CtlResponse::err("missing ip");
+                };
+                match Self::policy_list(cmd).remove(ip).await {
+                    Ok(n) => CtlResponse::ok(n),
+                    Err(err) => CtlResponse::err(err.to_string()),
+                }
+            }
+            cmd @ ("deny" | "allow") => {
+                let list = Self::policy_list(cmd);
+                match arg(0) {
+                    Some(ip) => CtlResponse::ok(list.get(ip).await.map(|x| x.to_json())),
+                    None => CtlResponse::ok(
+                        list.list().await.iter().map(|x| x.to_json()).collect::<Vec<_>>(),
+                    ),
+                }
+            }
+            cmd => CtlResponse::unknown(cmd),
+        }
+    }
+
     async fn handle_listener2(&self, stream: TcpStream, addr: SocketAddr) {
         let mut rs = self.clone();
-        let ip = try_into_v4(addr).ip();
-        if ip.is_loopback() {
-            tokio::spawn(async move {
-                let mut stream = stream;
+        tokio::spawn(async move {
+            let mut stream = stream;
+            // commands only from the socket peer itself, never from a PROXY header
+            let peer = addr;
+            let local = try_into_v4(peer).ip().is_loopback();
+            let addr = proxy_protocol::accept(&mut stream, addr).await;
+            let ip = try_into_v4(addr).ip();
+            if local && addr == peer {
                 let mut buffer = [0; 1024];
                 if let Ok(Ok(n)) = timeout(1000, stream.read(&mut buffer[..])).await {
                     if let Ok(data) = std::str::from_utf8(&buffer[..n]) {
@@ -1164,14 +1841,17 @@ impl RendezvousServer {
                         stream.write(res.as_bytes()).await.ok();
                     }
                 }
-            });
-            return;
-        }
-        let stream = FramedStream::from(stream, addr);
-        tokio::spawn(async move {
-            let mut stream = stream;
+                return;
+            }
+            let Some(_conn) = policy::accept(ip).await else {
+                return;
+            };
+            let mut stream = FramedStream::from(stream, addr);
             if let Some(Ok(bytes)) = stream.next_timeout(30_000).await {
                 if let Ok(msg_in) = RendezvousMessage::parse_from_bytes(&bytes) {
+                    if !policy::check(ip, policy::Kind::of(&msg_in.union)).await {
+                        return;
+                    }
                     match msg_in.union {
                         Some(rendezvous_message::Union::TestNatRequest(_)) => {
                             let mut msg_out = RendezvousMessage::new();
@@ -1182,7 +1862,7 @@ impl RendezvousServer {
                             stream.send(&msg_out).await.ok();
                         }
                         Some(rendezvous_message::Union::OnlineRequest(or)) => {
-                            allow_err!(rs.handle_online_request(&mut stream, or.peers).await);
+                            allow_err!(rs.handle_online_request(&mut stream, addr, or).await);
                         }
                         _ => {}
                     }
@@ -1191,12 +1871,21 @@ impl RendezvousServer {
         });
     }
 
-    async fn handle_listener(&self, stream: TcpStream, addr: SocketAddr, key: &str, ws: bool) {
+    async fn handle_listener(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        key: &str,
+        ws: bool,
+        tls: bool,
+    ) {
         log::debug!("Tcp connection from {:?}, ws: {}", addr, ws);
         let mut rs = self.clone();
         let key = key.to_owned();
         tokio::spawn(async move {
-            allow_err!(rs.handle_listener_inner(stream, addr, &key, ws).await);
+            let mut stream = stream;
+            let addr = proxy_protocol::accept(&mut stream, addr).await;
+            allow_err!(rs.handle_listener_inner(stream, addr, &key, ws, tls).await);
         });
     }
 
@@ -1207,26 +1896,26 @@ impl RendezvousServer {
         mut addr: SocketAddr,
         key: &str,
         ws: bool,
+        tls: bool,
     ) -> ResultType<()> {
         let mut sink;
         if ws {
             use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+            let stream = match &self.inner.tls {
+                Some(x) if tls => x.accept(stream).await?,
+                _ => tls::Stream::Plain(stream),
+            };
+            let peer = addr.ip();
             let callback = |req: &Request, response: Response| {
-                let headers = req.headers();
-                let real_ip = headers
-                    .get("X-Real-IP")
-                    .or_else(|| headers.get("X-Forwarded-For"))
-                    .and_then(|header_value| header_value.to_str().ok());
-                if let Some(ip) = real_ip {
-                    if ip.contains('.') {
-                        addr = format!("{ip}:0").parse().unwrap_or(addr);
-                    } else {
-                        addr = format!("[{ip}]:0").parse().unwrap_or(addr);
-                    }
+                if let Some(ip) = proxy_protocol::forwarded_ip(peer, req.headers()) {
+                    addr = SocketAddr::new(ip, 0);
                 }
                 Ok(response)
             };
             let ws_stream = tokio_tungstenite::accept_hdr_async(stream, callback).await?;
+            let Some(_conn) = policy::accept(addr.ip()).await else {
+                return Ok(());
+            };
             let (a, mut b) = ws_stream.split();
             sink = Some(Sink::Ws(a));
             while let Ok(Some(Ok(msg))) = timeout(30_000, b.next()).await {
@@ -1237,6 +1926,9 @@ impl RendezvousServer {
                 }
             }
         } else {
+            let Some(_conn) = policy::accept(addr.ip()).await else {
+                return Ok(());
+            };
             let (a, mut b) = Framed::new(stream, BytesCodec::new()).split();
             sink = Some(Sink::TcpStream(a));
             while let Ok(Some(Ok(bytes))) = timeout(30_000, b.next()).await {
@@ -1254,7 +1946,11 @@ impl RendezvousServer {
 
     #[inline]
     async fn get_pk(&mut self, version: &str, id: String) -> Bytes {
-        if version.is_empty() || self.inner.sk.is_none() {
+        let keyring = keyring::get();
+        let Some(sk) = keyring.signing_key() else {
+            return Bytes::new();
+        };
+        if version.is_empty() {
             Bytes::new()
         } else {
             match self.pm.get(&id).await {
@@ -1268,7 +1964,7 @@ impl RendezvousServer {
                         }
                         .write_to_bytes()
                         .unwrap_or_default(),
-                        self.inner.sk.as_ref().unwrap(),
+                        sk,
                     )
                     .into()
                 }
@@ -1306,21 +2002,161 @@ impl RendezvousServer {
     }
 
     #[inline]
-    fn is_lan(&self, addr: SocketAddr) -> bool {
-        if let Some(network) = &self.inner.mask {
-            match addr {
-                SocketAddr::V4(v4_socket_addr) => {
-                    return network.contains(*v4_socket_addr.ip());
-                }
+    /// The relay of the LAN site of `addr`, None if it is not in a LAN.
+    fn lan_relay(&self, addr: SocketAddr) -> Option<String> {
+        site_relay(&self.inner.sites, addr)
+    }
 
-                SocketAddr::V6(v6_socket_addr) => {
-                    if let Some(v4_addr) = v6_socket_addr.ip().to_ipv4() {
-                        return network.contains(v4_addr);
-                    }
-                }
-            }
+    fn is_local_relay(&self, relay: &str) -> bool {
+        self.inner.sites.iter().any(|x| x.relay == relay)
+    }
+}
+
+// A LAN network and the relay address given to its peers,
+// networks with the same relay are one site.
+#[derive(Debug, PartialEq)]
+struct LanSite {
+    network: IpNetwork,
+    relay: String,
+}
+
+fn site_relay(sites: &[LanSite], addr: SocketAddr) -> Option<String> {
+    let ip = try_into_v4(addr).ip();
+    sites
+        .iter()
+        .find(|x| x.network.contains(ip))
+        .map(|x| x.relay.clone())
+}
+
+// The relay given to `addr`: the relay of its own LAN site whatever the other
+// peer is, as a LAN peer may not reach the public relay without hairpin NAT,
+// else `public`. The relay servers join the two sides of a pair by its UUID.
+// https://github.com/rustdesk/rustdesk-server/issues/24
+fn relay_for(sites: &[LanSite], addr: SocketAddr, public: impl FnOnce() -> String) -> String {
+    site_relay(sites, addr).unwrap_or_else(public)
+}
+
+// a list setting as the comma separated option
+fn joined(v: &Option<Vec<String>>) -> String {
+    v.as_deref().unwrap_or_default().join(",")
+}
+
+// Relay addresses of LAN peers from --mask, --lan-sites, --local-ip and --local-ip6
+fn lan_settings(config: &Settings) -> (String, String, Vec<LanSite>) {
+    let mask = parse_mask(&joined(&config.mask));
+    let local_ip = if !mask.iter().any(|x| x.is_ipv4()) {
+        "".to_owned()
+    } else {
+        config.local_ip.clone().unwrap_or_else(|| {
+            local_ip_address::local_ip()
+                .map(|x| x.to_string())
+                .unwrap_or_default()
+        })
+    };
+    let local_ip6 = if !mask.iter().any(|x| x.is_ipv6()) {
+        "".to_owned()
+    } else {
+        config.local_ip6.clone().unwrap_or_else(|| {
+            local_ip_address::local_ipv6()
+                .map(|x| x.to_string())
+                .unwrap_or_default()
+        })
+    };
+    let sites = parse_lan_sites(&joined(&config.lan_sites));
+    let sites = lan_sites(sites, &mask, &local_ip, &local_ip6);
+    (local_ip, local_ip6, sites)
+}
+
+// settings that may change on SIGHUP
+fn apply_settings(settings: &Settings) {
+    if let Some(v) = settings.relay_saturation.filter(|x| *x > 0.) {
+        RELAY_SATURATION_100.store((v * 100.) as _, Ordering::SeqCst);
+    }
+    log::info!(
+        "relay-saturation: {}",
+        RELAY_SATURATION_100.load(Ordering::SeqCst) as f64 / 100.
+    );
+    if let Some(v) = settings.always_use_relay {
+        ALWAYS_USE_RELAY.store(v, Ordering::SeqCst);
+    }
+    log::info!(
+        "ALWAYS_USE_RELAY={}",
+        if ALWAYS_USE_RELAY.load(Ordering::SeqCst) {
+            "Y"
+        } else {
+            "N"
         }
-        false
+    );
+}
+
+// <network>=<relay>,...
+fn parse_lan_sites(s: &str) -> Vec<LanSite> {
+    s.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .filter_map(|x| {
+            let site = x.split_once('=').and_then(|(network, relay)| {
+                Some(LanSite {
+                    network: network.trim().parse().ok()?,
+                    relay: relay.trim().to_owned(),
+                })
+            });
+            if site.is_none() {
+                log::error!("Invalid lan site {}, expected <network>=<relay>", x);
+            }
+            site
+        })
+        .collect()
+}
+
+// sites and --mask networks, which share the local relay of their address
+// family; the most specific network first
+fn lan_sites(
+    mut sites: Vec<LanSite>,
+    mask: &[IpNetwork],
+    local_ip: &str,
+    local_ip6: &str,
+) -> Vec<LanSite> {
+    for network in mask {
+        let (a, b) = if network.is_ipv4() {
+            (local_ip, local_ip6)
+        } else {
+            (local_ip6, local_ip)
+        };
+        sites.push(LanSite {
+            network: *network,
+            relay: if a.is_empty() { b } else { a }.to_owned(),
+        });
+    }
+    sites.sort_by(|a, b| b.network.prefix().cmp(&a.network.prefix()));
+    sites
+}
+
+fn parse_mask(s: &str) -> Vec<IpNetwork> {
+    s.split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .filter_map(|x| match x.parse::<IpNetwork>() {
+            Ok(v) => Some(v),
+            Err(err) => {
+                log::error!("Invalid mask {}: {}", x, err);
+                None
+            }
+        })
+        .collect()
+}
+
+// Whether two public addresses are behind the same router: the same IPv4
+// address (NAT), or the same /64 prefix for IPv6, which is one link.
+fn same_intranet(a: IpAddr, b: IpAddr) -> bool {
+    let canonical = |ip: IpAddr| match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+    match (canonical(a), canonical(b)) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a == b,
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.segments()[..4] == b.segments()[..4],
+        _ => false,
     }
 }
 
@@ -1339,7 +2175,17 @@ async fn check_relay_servers(rs0: Arc<RelayServers>, tx: Sender) {
                 .await
                 .is_ok()
             {
-                rs.lock().await.push(x);
+                // relays without status endpoint are kept, their load is unknown
+                let threshold = RELAY_SATURATION_100.load(Ordering::SeqCst) as f64 / 100.;
+                match relay_status::fetch(&x, CHECK_RELAY_TIMEOUT).await {
+                    Some(status) if status.draining => {
+                        log::info!("Relay {} draining", x);
+                    }
+                    Some(status) if status.saturation >= threshold => {
+                        log::info!("Relay {} saturated: {:?}", x, status);
+                    }
+                    _ => rs.lock().await.push(x),
+                }
             }
         }));
     }
@@ -1389,21 +2235,40 @@ async fn test_hbbs(addr: SocketAddr) -> ResultType<()> {
     }
 }
 
-#[inline]
-async fn send_rk_res(
-    socket: &mut FramedSocket,
-    addr: SocketAddr,
-    res: register_pk_response::Result,
-) -> ResultType<()> {
-    let mut msg_out = RendezvousMessage::new();
-    msg_out.set_register_pk_response(RegisterPkResponse {
-        result: res.into(),
-        ..Default::default()
-    });
-    socket.send(&msg_out, addr).await
+// SIGHUP
+fn on_hangup() {
+    settings::reload();
+    keyring::reload();
+}
+
+async fn udp_recv_loop(port: i32, rmem: usize, workers: UdpWorkers) {
+    loop {
+        match create_udp_listener(port, rmem).await {
+            Ok(mut socket) => {
+                while let Some(res) = socket.next().await {
+                    match res {
+                        Ok((bytes, addr)) => workers.dispatch(bytes, addr.into()),
+                        Err(err) => {
+                            log::error!("udp failure: {}", err);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                log::error!("Failed to create udp listener: {}", err);
+            }
+        }
+        sleep(1.).await;
+    }
 }
 
 async fn create_udp_listener(port: i32, rmem: usize) -> ResultType<FramedSocket> {
+    if let Some(socket) = systemd::take_udp(port as _) {
+        socket.set_nonblocking(true)?;
+        let socket = UdpSocket::from_std(socket)?;
+        return Ok(FramedSocket::Direct(UdpFramed::new(socket, BytesCodec::new())));
+    }
     let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port as _);
     if let Ok(s) = FramedSocket::new_reuse(&addr, true, rmem).await {
         log::debug!("listen on udp {:?}", s.local_addr());
@@ -1417,7 +2282,88 @@ async fn create_udp_listener(port: i32, rmem: usize) -> ResultType<FramedSocket>
 
 #[inline]
 async fn create_tcp_listener(port: i32) -> ResultType<TcpListener> {
+    if let Some(listener) = systemd::take_tcp(port as _) {
+        listener.set_nonblocking(true)?;
+        return Ok(TcpListener::from_std(listener)?);
+    }
     let s = listen_any(port as _).await?;
     log::debug!("listen on tcp {:?}", s.local_addr());
     Ok(s)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_intranet() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(same_intranet(ip("1.2.3.4"), ip("::ffff:1.2.3.4")));
+        assert!(!same_intranet(ip("1.2.3.4"), ip("1.2.3.5")));
+        assert!(same_intranet(ip("2001:db8:1:2::a"), ip("2001:db8:1:2:1::b")));
+        assert!(!same_intranet(ip("2001:db8:1:2::a"), ip("2001:db8:1:3::a")));
+        assert!(!same_intranet(ip("1.2.3.4"), ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn test_lan_sites() {
+        let sites = parse_lan_sites("10.1.0.0/16=10.1.0.5, 10.0.0.0/8=10.0.0.5:21117, bogus");
+        let sites = lan_sites(sites, &parse_mask("10.1.2.0/24,fd00::/8"), "10.1.2.3", "");
+        let relay = |ip: &str| {
+            let ip = ip.parse::<IpAddr>().unwrap();
+            sites.iter().find(|x| x.network.contains(ip)).map(|x| x.relay.as_str())
+        };
+        assert_eq!(relay("10.1.2.9"), Some("10.1.2.3"));
+        assert_eq!(relay("10.1.3.9"), Some("10.1.0.5"));
+        assert_eq!(relay("10.2.0.1"), Some("10.0.0.5:21117"));
+        assert_eq!(relay("fd00::1"), Some("10.1.2.3"));
+        assert_eq!(relay("192.168.0.1"), None);
+    }
+
+    #[test]
+    fn test_relay_for() {
+        let sites =
+            parse_lan_sites("10.1.0.0/16=10.1.0.5, 10.2.0.0/16=10.2.0.5, 10.3.0.0/16=10.1.0.5");
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        // the relays given to both sides of a pair
+        let relays = |a: &str, b: &str| {
+            let public = || "relay.example.com".to_owned();
+            (
+                relay_for(&sites, addr(a), public),
+                relay_for(&sites, addr(b), public),
+            )
+        };
+        let public = "relay.example.com".to_owned();
+        assert_eq!(
+            relays("10.1.0.1:1", "10.1.9.9:2"),
+            ("10.1.0.5".to_owned(), "10.1.0.5".to_owned())
+        );
+        // networks with the same relay are one site
+        assert_eq!(
+            relays("10.1.0.1:1", "10.3.0.1:2"),
+            ("10.1.0.5".to_owned(), "10.1.0.5".to_owned())
+        );
+        // site A and site B, each keeps its own relay
+        assert_eq!(
+            relays("10.1.0.1:1", "10.2.0.1:2"),
+            ("10.1.0.5".to_owned(), "10.2.0.5".to_owned())
+        );
+        // a LAN peer and an internet peer, only the latter gets the public relay
+        assert_eq!(
+            relays("10.1.0.1:1", "1.2.3.4:2"),
+            ("10.1.0.5".to_owned(), public.clone())
+        );
+        assert_eq!(
+            relays("[::ffff:10.2.0.1]:1", "1.2.3.4:2"),
+            ("10.2.0.5".to_owned(), public.clone())
+        );
+        assert_eq!(relays("1.2.3.4:1", "5.6.7.8:2"), (public.clone(), public));
+    }
+
+    #[test]
+    fn test_parse_mask() {
+        let mask = parse_mask("192.168.0.0/16, fd00::/8,bogus");
+        assert_eq!(mask.len(), 2);
+        assert!(mask[1].contains("fd12::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,685 @@
+// Typed configuration of hbbs and hbbr.
+//
+// Every option can be set, in order of precedence, on the command line (hbbr
+// only takes port, key and data-dir there), in the environment, in a TOML file
+// given with `--config`, or in .env:
+//
+//   [common]        # both servers
+//   db_url = "./db_v2.sqlite3"
+//
+//   [rendezvous]    # hbbs, or [hbbs]
+//   port = 21116
+//   relay_servers = ["relay1.example.com", "relay2.example.com"]
+//
+//   [relay]         # hbbr, or [hbbr]
+//   port = 21117
+//   limit_speed = 8
+//
+// Keys are the option names with `_` instead of `-`. Values of the file are
+// exported to the environment at startup where the option is not set yet, so
+// code that reads options with get_arg or from plain environment variables sees
+// them too. All settings are validated at startup and every problem is
+// reported at once.
+//
+// On SIGHUP (see common::listen_signal) the file is read again. Reloadable
+// settings are applied and passed to the callbacks registered with
+// `on_reload`; changes of the others are logged and take effect on restart. A
+// file with errors keeps the current settings. Reloaded values are only in the
+// snapshot returned by `get`, the environment is never changed after startup,
+// so reloadable settings must be read with `get`.
+use crate::{common::get_shared_arg, control::parse_flag};
+use hbb_common::{bail, log, ResultType};
+use ipnetwork::IpNetwork;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
+
+const COMMON: &str = "common";
+const RENDEZVOUS: &str = "rendezvous";
+const RELAY: &str = "relay";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Server {
+    Hbbs,
+    Hbbr,
+}
+
+impl Server {
+    fn sections(self) -> [&'static str; 2] {
+        match self {
+            Server::Hbbs => [COMMON, RENDEZVOUS],
+            Server::Hbbr => [COMMON, RELAY],
+        }
+    }
+}
+
+fn section_of(name: &str) -> Option<&'static str> {
+    match name {
+        "common" => Some(COMMON),
+        "rendezvous" | "hbbs" => Some(RENDEZVOUS),
+        "relay" | "hbbr" => Some(RELAY),
+        _ => None,
+    }
+}
+
+struct Field {
+    // field of Settings
+    id: &'static str,
+    section: &'static str,
+    // key in the file
+    key: &'static str,
+    // option name
+    name: &'static str,
+    reload: bool,
+}
+
+trait FromSetting: Sized {
+    fn from_setting(v: &str) -> Result<Self, String>;
+}
+
+impl FromSetting for String {
+    fn from_setting(v: &str) -> Result<Self, String> {
+        Ok(v.to_owned())
+    }
+}
+
+impl FromSetting for bool {
+    fn from_setting(v: &str) -> Result<Self, String> {
+        parse_flag(v).ok_or_else(|| "expected Y or N".to_owned())
+    }
+}
+
+// comma separated, or an array in the file
+impl FromSetting for Vec<String> {
+    fn from_setting(v: &str) -> Result<Self, String> {
+        Ok(v.split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_owned())
+            .collect())
+    }
+}
+
+macro_rules! from_str {
+    ($($t:ty),*) => {
+        $(impl FromSetting for $t {
+            fn from_setting(v: &str) -> Result<Self, String> {
+                v.trim().parse().map_err(|e: <$t as std::str::FromStr>::Err| e.to_string())
+            }
+        })*
+    };
+}
+
+from_str!(u16, u32, u64, usize, i32, f64);
+
+macro_rules! settings {
+    (@reload reload) => {
+        true
+    };
+    (@reload) => {
+        false
+    };
+    ($($field:ident: $ty:ty = $section:ident $key:literal $name:literal $($reload:ident)?;)*) => {
+        /// Effective settings, None where not set.
+        #[derive(Debug, Clone, Default, PartialEq)]
+        pub struct Settings {
+            $(pub $field: Option<$ty>,)*
+        }
+
+        const FIELDS: &[Field] = &[$(Field {
+            id: stringify!($field),
+            section: $section,
+            key: $key,
+            name: $name,
+            reload: settings!(@reload $($reload)?),
+        },)*];
+
+        impl Settings {
+            fn parse(values: &HashMap<&str, String>, errors: &mut Vec<String>) -> Self {
+                let mut res = Self::default();
+                $(if let Some(v) = values.get(stringify!($field)) {
+                    match <$ty as FromSetting>::from_setting(v) {
+                        Ok(v) => res.$field = Some(v),
+                        Err(err) => errors.push(format!("{}: invalid value {:?}: {}", $name, v, err)),
+                    }
+                })*
+                res
+            }
+
+            // (field, old value, new value) of the settings that differ
+            fn changes(&self, other: &Self) -> Vec<(&'static str, String, String)> {
+                let mut res = Vec::new();
+                $(if self.$field != other.$field {
+                    res.push((stringify!($field), show($name, &self.$field), show($name, &other.$field)));
+                })*
+                res
+            }
+
+            fn apply_reloadable(&mut self, other: &Self) {
+                $(if settings!(@reload $($reload)?) {
+                    self.$field = other.$field.clone();
+                })*
+            }
+        }
+    };
+}
+
+// field: type = section "key in the file" "option name" [reload];
+settings! {
+    data_dir: String = COMMON "data_dir" "data-dir";
+    key_file: String = COMMON "key_file" "key-file";
+    db_url: String = COMMON "db_url" "db-url";
+    max_database_connections: usize = COMMON "max_database_connections" "max-database-connections";
+    admin_port: u16 = COMMON "admin_port" "admin-port";
+    admin_token: String = COMMON "admin_token" "admin-token" reload;
+    admin_user: String = COMMON "admin_user" "admin-user" reload;
+    admin_pass: String = COMMON "admin_pass" "admin-pass" reload;
+    proxy_protocol: bool = COMMON "proxy_protocol" "proxy-protocol";
+    trusted_proxies: Vec<String> = COMMON "trusted_proxies" "trusted-proxies";
+    tls_cert: String = COMMON "tls_cert" "tls-cert";
+    tls_key: String = COMMON "tls_key" "tls-key";
+    wss_port: u16 = COMMON "wss_port" "wss-port";
+    drain_timeout: u64 = COMMON "drain_timeout" "drain-timeout" reload;
+    key_format: String = COMMON "key_format" "key-format" reload;
+    key_formats: Vec<String> = COMMON "key_formats" "key-formats" reload;
+    key_prefixes: Vec<String> = COMMON "key_prefixes" "key-prefixes" reload;
+
+    port: u16 = RENDEZVOUS "port" "port";
+    key: String = RENDEZVOUS "key" "key";
+    sign_key: String = RENDEZVOUS "sign_key" "sign-key" reload;
+    serial: i32 = RENDEZVOUS "serial" "serial" reload;
+    rendezvous_servers: Vec<String> = RENDEZVOUS "rendezvous_servers" "rendezvous-servers" reload;
+    relay_servers: Vec<String> = RENDEZVOUS "relay_servers" "relay-servers" reload;
+    software_url: String = RENDEZVOUS "software_url" "software-url" reload;
+    rmem: usize = RENDEZVOUS "rmem" "rmem";
+    udp_workers: usize = RENDEZVOUS "udp_workers" "udp-workers";
+    udp_sockets: usize = RENDEZVOUS "udp_sockets" "udp-sockets";
+    cluster_nodes: Vec<String> = RENDEZVOUS "cluster_nodes" "cluster-nodes";
+    cluster_port: u16 = RENDEZVOUS "cluster_port" "cluster-port";
+    cluster_secret: String = RENDEZVOUS "cluster_secret" "cluster-secret";
+    geo_db: String = RENDEZVOUS "geo_db" "geo-db";
+    relay_meta: String = RENDEZVOUS "relay_meta" "relay-meta";
+    relay_saturation: f64 = RENDEZVOUS "relay_saturation" "relay-saturation" reload;
+    always_use_relay: bool = RENDEZVOUS "always_use_relay" "always-use-relay" reload;
+    test_hbbs: String = RENDEZVOUS "test_hbbs" "test-hbbs";
+    allow_list: String = RENDEZVOUS "allow_list" "allow-list";
+    deny_list: String = RENDEZVOUS "deny_list" "deny-list";
+    rate_limits: Vec<String> = RENDEZVOUS "rate_limits" "rate-limits";
+    max_connections: usize = RENDEZVOUS "max_connections" "max-connections";
+    block_strikes: u32 = RENDEZVOUS "block_strikes" "block-strikes";
+    block_duration: u64 = RENDEZVOUS "block_duration" "block-duration";
+    lookup_limit: usize = RENDEZVOUS "lookup_limit" "lookup-limit";
+    online_max_ids: usize = RENDEZVOUS "online_max_ids" "online-max-ids";
+    online_auth: bool = RENDEZVOUS "online_auth" "online-auth";
+    uniform_failure: bool = RENDEZVOUS "uniform_failure" "uniform-failure";
+    mask: Vec<String> = RENDEZVOUS "mask" "mask" reload;
+    lan_sites: Vec<String> = RENDEZVOUS "lan_sites" "lan-sites" reload;
+    local_ip: String = RENDEZVOUS "local_ip" "local-ip" reload;
+    local_ip6: String = RENDEZVOUS "local_ip6" "local-ip6" reload;
+    custom_keys_file: String = RENDEZVOUS "custom_keys_file" "custom-keys-file";
+
+    // PORT is the port of hbbs for hbbr, see hbbr.rs
+    relay_port: u16 = RELAY "port" "relay-port";
+    relay_key: String = RELAY "key" "key";
+    status_port: u16 = RELAY "status_port" "status-port";
+    status_addr: String = RELAY "status_addr" "status-addr";
+    handoff_socket: String = RELAY "handoff_socket" "handoff-socket";
+    downgrade_threshold: f64 = RELAY "downgrade_threshold" "downgrade-threshold" reload;
+    downgrade_start_check: usize = RELAY "downgrade_start_check" "downgrade-start-check" reload;
+    limit_speed: f64 = RELAY "limit_speed" "limit-speed" reload;
+    total_bandwidth: f64 = RELAY "total_bandwidth" "total-bandwidth" reload;
+    single_bandwidth: f64 = RELAY "single_bandwidth" "single-bandwidth" reload;
+}
+
+fn show<T: std::fmt::Debug>(name: &str, v: &Option<T>) -> String {
+    match v {
+        Some(_)
+            if name == "key"
+                || name.contains("token")
+                || name.contains("pass")
+                || name.contains("secret") =>
+        {
+            "***".to_owned()
+        }
+        Some(v) => format!("{:?}", v),
+        None => "unset".to_owned(),
+    }
+}
+
+impl Settings {
+    /// Every semantic problem of the settings.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if matches!(self.port, Some(p) if p < 3) {
+            errors.push("port: must be at least 3".to_owned());
+        }
+        for (name, port) in [
+            ("relay-port", self.relay_port),
+            ("admin-port", self.admin_port),
+            ("status-port", self.status_port),
+            ("cluster-port", self.cluster_port),
+            ("wss-port", self.wss_port),
+        ] {
+            if port == Some(0) {
+                errors.push(format!("{}: must not be 0", name));
+            }
+        }
+        if let Some(addr) = &self.status_addr {
+            if addr.parse::<std::net::IpAddr>().is_err() {
+                errors.push(format!("status-addr: expected an IP address, got {}", addr));
+            }
+        }
+        if let Some(wss_port) = self.wss_port {
+            let ws_ports = [self.port, self.relay_port].map(|x| x.map(|p| p as u32 + 2));
+            if ws_ports.contains(&Some(wss_port as u32)) {
+                errors.push("wss-port: must differ from the WebSocket port".to_owned());
+            }
+        }
+        if let Some(dir) = &self.data_dir {
+            if !Path::new(dir).is_dir() {
+                errors.push(format!("data-dir: {} is not a directory", dir));
+            }
+        }
+        // see common::data_path
+        let is_file = |file: &str| match &self.data_dir {
+            Some(dir) => Path::new(dir).join(file).is_file(),
+            None => Path::new(file).is_file(),
+        };
+        if let Some(file) = &self.key_file {
+            if !is_file(file) {
+                errors.push(format!("key-file: {} not found", file));
+            }
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                for (name, file) in [("tls-cert", cert), ("tls-key", key)] {
+                    if !is_file(file) {
+                        errors.push(format!("{}: {} not found", name, file));
+                    }
+                }
+            }
+            (None, None) => {}
+            _ => errors.push("tls-cert and tls-key must be set together".to_owned()),
+        }
+        if self.cluster_nodes.iter().flatten().count() > 0
+            && self.cluster_secret.as_deref().unwrap_or_default().is_empty()
+        {
+            errors.push("cluster-nodes: needs cluster-secret".to_owned());
+        }
+        if self.proxy_protocol == Some(true) && self.trusted_proxies.iter().flatten().count() == 0 {
+            errors.push("proxy-protocol: needs trusted-proxies".to_owned());
+        }
+        for (name, nets) in [("trusted-proxies", &self.trusted_proxies), ("mask", &self.mask)] {
+            for x in nets.iter().flatten() {
+                if let Err(err) = x.parse::<IpNetwork>() {
+                    errors.push(format!("{}: invalid network {}: {}", name, x, err));
+                }
+            }
+        }
+        for x in self.lan_sites.iter().flatten() {
+            match x.split_once('=') {
+                Some((net, relay)) if !relay.trim().is_empty() => {
+                    if let Err(err) = net.trim().parse::<IpNetwork>() {
+                        errors.push(format!("lan-sites: invalid network {}: {}", net, err));
+                    }
+                }
+                _ => errors.push(format!("lan-sites: expected <network>=<relay>, got {}", x)),
+            }
+        }
+        let formats = self.key_format.iter().map(|x| ("key-format", x));
+        let formats = formats.chain(self.key_formats.iter().flatten().map(|x| ("key-formats", x)));
+        for (name, x) in formats {
+            if let Err(err) = x.parse::<crate::key_format::Format>() {
+                errors.push(format!("{}: {}", name, err));
+            }
+        }
+        for x in self.key_prefixes.iter().flatten() {
+            if let Err(err) = crate::key_format::check_prefix(x) {
+                errors.push(format!("key-prefixes: {}", err));
+            }
+        }
+        for x in self.rate_limits.iter().flatten() {
+            let valid = x.split_once('=').and_then(|(_, v)| v.split_once('/')).map_or(
+                false,
+                |(rate, burst)| {
+                    rate.trim().parse::<f64>().is_ok() && burst.trim().parse::<u32>().is_ok()
+                },
+            );
+            if !valid {
+                errors.push(format!(
+                    "rate-limits: expected <kind>=<per second>/<burst>, got {}",
+                    x
+                ));
+            }
+        }
+        for (name, v) in [
+            ("relay-saturation", self.relay_saturation),
+            ("downgrade-threshold", self.downgrade_threshold),
+            ("limit-speed", self.limit_speed),
+            ("total-bandwidth", self.total_bandwidth),
+            ("single-bandwidth", self.single_bandwidth),
+        ] {
+            if matches!(v, Some(v) if v.is_nan() || v <= 0.) {
+                errors.push(format!("{}: must be greater than 0", name));
+            }
+        }
+        if let Some(addr) = &self.test_hbbs {
+            if addr.to_lowercase() != "no" && addr.parse::<SocketAddr>().is_err() {
+                errors.push(format!("test-hbbs: expected an address or \"no\", got {}", addr));
+            }
+        }
+        errors
+    }
+}
+
+type Hook = Box<dyn Fn(&Settings) + Send + Sync>;
+
+#[derive(Default)]
+struct State {
+    server: Option<Server>,
+    path: String,
+    // fields whose environment variables were set from the file at startup
+    from_file: HashSet<&'static str>,
+}
+
+lazy_static::lazy_static! {
+    static ref SETTINGS: RwLock<Arc<Settings>> = Default::default();
+    static ref STATE: Mutex<State> = Default::default();
+    static ref HOOKS: Mutex<Vec<Hook>> = Default::default();
+    // environment variables set from .env, which the config file overrides
+    static ref FROM_DOTENV: Mutex<HashSet<String>> = Default::default();
+}
+
+/// The current settings.
+pub fn get() -> Arc<Settings> {
+    SETTINGS.read().unwrap().clone()
+}
+
+/// Call `f` with the new settings after each reload.
+pub fn on_reload(f: impl Fn(&Settings) + Send + Sync + 'static) {
+    HOOKS.lock().unwrap().push(Box::new(f));
+}
+
+#[inline]
+fn is_toml(path: &str) -> bool {
+    path.ends_with(".toml")
+}
+
+fn fields(sections: &[&str]) -> impl Iterator<Item = &'static Field> + '_ {
+    FIELDS.iter().filter(|f| sections.contains(&f.section))
+}
+
+// NAME-WITH-DASHES as set by init_args, and NAME_WITH_UNDERSCORES
+fn env_names(name: &str) -> [String; 2] {
+    let name = name.to_uppercase();
+    let name2 = name.replace('-', "_");
+    [name, name2]
+}
+
+// set on the command line or in the environment, not only in .env
+fn is_env_set(f: &Field) -> bool {
+    let from_dotenv = FROM_DOTENV.lock().unwrap();
+    env_names(f.name)
+        .iter()
+        .any(|x| std::env::var_os(x).is_some() && !from_dotenv.contains(x))
+}
+
+/// Export the options of .env that are not in the environment yet, called at
+/// startup after the INI config file of hbbs is exported, so an explicitly
+/// named config file wins over .env.
+pub fn load_dotenv() {
+    let Ok(v) = ini::Ini::load_from_file(".env") else {
+        return;
+    };
+    let Some(section) = v.section(None::<String>) else {
+        return;
+    };
+    let mut from_dotenv = FROM_DOTENV.lock().unwrap();
+    for (k, v) in section.iter() {
+        let names = env_names(&k.replace('_', "-"));
+        if names.iter().all(|x| std::env::var_os(x).is_none()) {
+            for name in names {
+                std::env::set_var(&name, v);
+                from_dotenv.insert(name);
+            }
+        }
+    }
+}
+
+// only at startup, before other threads read the environment
+fn set_env(f: &Field, v: &str) {
+    for name in env_names(f.name) {
+        std::env::set_var(name, v);
+    }
+}
+
+fn toml_value(v: &toml::Value) -> Option<String> {
+    match v {
+        toml::Value::String(v) => Some(v.clone()),
+        toml::Value::Integer(v) => Some(v.to_string()),
+        toml::Value::Float(v) => Some(v.to_string()),
+        toml::Value::Boolean(v) => Some(if *v { "Y" } else { "N" }.to_owned()),
+        toml::Value::Array(v) => v
+            .iter()
+            .map(|x| match x {
+                toml::Value::Array(_) | toml::Value::Table(_) => None,
+                x => toml_value(x),
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|x| x.join(",")),
+        _ => None,
+    }
+}
+
+/// Values of the config file by field, for the given sections.
+fn read_file(
+    path: &str,
+    sections: &[&str],
+    errors: &mut Vec<String>,
+) -> HashMap<&'static str, String> {
+    let mut res = HashMap::new();
+    let table = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|x| toml::from_str::<toml::Table>(&x).map_err(|e| e.to_string()))
+    {
+        Ok(v) => v,
+        Err(err) => {
+            errors.push(format!("{}: {}", path, err));
+            return res;
+        }
+    };
+    for (name, v) in table {
+        let Some(section) = section_of(&name) else {
+            errors.push(format!("unknown section [{}]", name));
+            continue;
+        };
+        let toml::Value::Table(v) = v else {
+            errors.push(format!("{}: expected a section", name));
+            continue;
+        };
+        for (key, v) in v {
+            let Some(f) = FIELDS.iter().find(|f| f.section == section && f.key == key) else {
+                errors.push(format!("[{}] {}: unknown setting", name, key));
+                continue;
+            };
+            match toml_value(&v) {
+                Some(_) if !sections.contains(&section) => {}
+                Some(v) => {
+                    res.insert(f.id, v);
+                }
+                None => errors.push(format!("[{}] {}: unsupported value {}", name, key, v)),
+            }
+        }
+    }
+    res
+}
+
+fn from_values(values: &HashMap<&str, String>, errors: &mut Vec<String>) -> Settings {
+    let settings = Settings::parse(values, errors);
+    errors.extend(settings.validate());
+    settings
+}
+
+/// Load and validate the settings of `server` from the environment and the
+/// config file `config`, if it is a .toml file.
+pub fn load(server: Server, config: &str) -> Result<Arc<Settings>, Vec<String>> {
+    let sections = server.sections();
+    let mut errors = Vec::new();
+    let mut from_file = HashSet::new();
+    if is_toml(config) {
+        let values = read_file(config, &sections, &mut errors);
+        for f in fields(&sections) {
+            if let Some(v) = values.get(f.id) {
+                if !is_env_set(f) {
+                    set_env(f, v);
+                    from_file.insert(f.id);
+                }
+            }
+        }
+    }
+    let values = fields(&sections)
+        .map(|f| (f.id, get_shared_arg(f.name)))
+        .filter(|(_, v)| !v.is_empty())
+        .collect();
+    let settings = from_values(&values, &mut errors);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let settings = Arc::new(settings);
+    *SETTINGS.write().unwrap() = settings.clone();
+    *STATE.lock().unwrap() = State {
+        server: Some(server),
+        path: if is_toml(config) {
+            config.to_owned()
+        } else {
+            "".to_owned()
+        },
+        from_file,
+    };
+    Ok(settings)
+}
+
+/// `load`, logging every problem.
+pub fn init(server: Server, config: &str) -> ResultType<Arc<Settings>> {
+    match load(server, config) {
+        Ok(v) => Ok(v),
+        Err(errors) => {
+            for err in errors.iter() {
+                log::error!("{}", err);
+            }
+            bail!("Invalid configuration, {} error(s)", errors.len());
+        }
+    }
+}
+
+/// Every problem of a config file, for both servers.
+pub fn check_file(path: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    let values = read_file(path, &[COMMON, RENDEZVOUS, RELAY], &mut errors);
+    from_values(&values, &mut errors);
+    errors
+}
+
+/// Read the config file again and apply the reloadable settings.
+pub fn reload() {
+    let state = STATE.lock().unwrap();
+    let Some(server) = state.server else {
+        return;
+    };
+    if state.path.is_empty() {
+        log::info!("No config file to reload");
+        return;
+    }
+    let sections = server.sections();
+    let mut errors = Vec::new();
+    let file = read_file(&state.path, &sections, &mut errors);
+    // the file replaces what it set before, options set otherwise still win
+    let mut values = HashMap::new();
+    for f in fields(&sections) {
+        let v = if state.from_file.contains(f.id) || !is_env_set(f) {
+            file.get(f.id).cloned()
+        } else {
+            Some(get_shared_arg(f.name)).filter(|x| !x.is_empty())
+        };
+        if let Some(v) = v {
+            values.insert(f.id, v);
+        }
+    }
+    let new = from_values(&values, &mut errors);
+    if !errors.is_empty() {
+        for err in errors {
+            log::error!("{}", err);
+        }
+        log::error!("Config {} not reloaded", state.path);
+        return;
+    }
+    let old = get();
+    let mut settings = (*old).clone();
+    settings.apply_reloadable(&new);
+    for (id, a, b) in old.changes(&new) {
+        let Some(f) = FIELDS.iter().find(|f| f.id == id) else {
+            continue;
+        };
+        if f.reload {
+            log::info!("{}: {} -> {}", f.name, a, b);
+        } else {
+            log::warn!("{}: {} -> {}, takes effect on restart", f.name, a, b);
+        }
+    }
+    let settings = Arc::new(settings);
+    *SETTINGS.write().unwrap() = settings.clone();
+    drop(state);
+    for f in HOOKS.lock().unwrap().iter() {
+        f(&settings);
+    }
+    log::info!("Config reloaded");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut values = HashMap::new();
+        values.insert("port", "21116".to_owned());
+        values.insert("relay_servers", "a:21117, b".to_owned());
+        values.insert("always_use_relay", "Y".to_owned());
+        values.insert("limit_speed", "fast".to_owned());
+        values.insert("mask", "10.0.0.0/8,bogus".to_owned());
+        let mut errors = Vec::new();
+        let settings = from_values(&values, &mut errors);
+        assert_eq!(settings.port, Some(21116));
+        assert_eq!(
+            settings.relay_servers,
+            Some(vec!["a:21117".to_owned(), "b".to_owned()])
+        );
+        assert_eq!(settings.always_use_relay, Some(true));
+        assert_eq!(settings.limit_speed, None);
+        // every problem is reported
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("limit-speed"));
+        assert!(errors[1].starts_with("mask"));
+    }
+
+    #[test]
+    fn test_toml_value() {
+        let table: toml::Table = toml::from_str(
+            "a = \"x\"\nb = 3\nc = true\nd = [\"h1\", \"h2\"]\ne = { f = 1 }",
+        )
+        .unwrap();
+        assert_eq!(toml_value(&table["a"]).as_deref(), Some("x"));
+        assert_eq!(toml_value(&table["b"]).as_deref(), Some("3"));
+        assert_eq!(toml_value(&table["c"]).as_deref(), Some("Y"));
+        assert_eq!(toml_value(&table["d"]).as_deref(), Some("h1,h2"));
+        assert_eq!(toml_value(&table["e"]), None);
+    }
+
+    #[test]
+    fn test_check_file() {
+        assert!(check_file("test_config.toml").is_empty());
+    }
+}
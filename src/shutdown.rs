@@ -0,0 +1,121 @@
+// Graceful shutdown of hbbs and hbbr.
+//
+// The first SIGTERM, SIGINT or SIGQUIT starts draining: new connections are
+// not accepted any more, /readyz answers 503, and hbbr reports `draining` in
+// its status so that hbbs stops handing it out. What is in progress (relayed
+// sessions of hbbr, pending punch hole replies of hbbs) may finish for up to
+// `drain-timeout` seconds (default 30, 0 to exit at once). A second signal
+// exits immediately.
+use axum::http::StatusCode;
+use hbb_common::{log, sleep, tokio::sync::Notify};
+use std::{
+    future::Future,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_TIMEOUT: u64 = 30;
+// how often the progress of draining is logged, in seconds
+const LOG_INTERVAL: u64 = 5;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref NOTIFY: Notify = Notify::new();
+}
+
+#[inline]
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Start draining.
+pub fn start() {
+    if !DRAINING.swap(true, Ordering::SeqCst) {
+        log::info!("Draining");
+        crate::systemd::notify("STOPPING=1");
+        NOTIFY.notify_waiters();
+    }
+}
+
+/// Resolves when draining starts.
+pub async fn draining() {
+    loop {
+        // registered before the check, a start in between is not missed
+        let notified = NOTIFY.notified();
+        if is_draining() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// A session to finish before exiting, see `active`.
+pub struct Active(());
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Count a session until the returned guard is dropped.
+pub fn active() -> Active {
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    Active(())
+}
+
+#[inline]
+pub fn active_count() -> usize {
+    ACTIVE.load(Ordering::SeqCst)
+}
+
+pub fn timeout() -> Duration {
+    Duration::from_secs(
+        crate::settings::get()
+            .drain_timeout
+            .unwrap_or(DEFAULT_TIMEOUT),
+    )
+}
+
+/// Wait until `pending` is 0, at most `timeout` if given, see `timeout()`.
+/// Returns false on timeout.
+pub async fn drain<F, Fut>(timeout: Option<Duration>, mut pending: F) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = usize>,
+{
+    match timeout {
+        Some(timeout) => log::info!("Drain timeout {}s", timeout.as_secs()),
+        None => log::info!("Draining until all is finished"),
+    }
+    let start = Instant::now();
+    let mut logged = 0;
+    loop {
+        let n = pending().await;
+        if n == 0 {
+            log::info!("Drained");
+            return true;
+        }
+        let elapsed = start.elapsed();
+        if timeout.map_or(false, |x| elapsed >= x) {
+            log::warn!("Drain timeout, {} left", n);
+            return false;
+        }
+        if elapsed.as_secs() / LOG_INTERVAL > logged {
+            logged = elapsed.as_secs() / LOG_INTERVAL;
+            log::info!("Draining, {} left", n);
+        }
+        sleep(0.2).await;
+    }
+}
+
+/// Readiness probe for load balancers, not ready while draining.
+pub async fn readyz() -> (StatusCode, &'static str) {
+    if is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "draining")
+    } else {
+        (StatusCode::OK, "ready")
+    }
+}
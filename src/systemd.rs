@@ -0,0 +1,175 @@
+// Integration with systemd: socket activation, readiness and watchdog.
+//
+// Started through a socket unit, hbbs and hbbr use the sockets passed in
+// LISTEN_FDS instead of binding their own, matched by port. systemd keeps the
+// ports open across restarts, and binds them before privileges are dropped.
+// Passed sockets for ports that are not used are closed with a warning.
+//
+// With NOTIFY_SOCKET set (Type=notify), READY=1 is sent once the listeners
+// and the database are up and STOPPING=1 when draining starts. With
+// WatchdogSec= set, WATCHDOG=1 is sent at half that interval.
+use hbb_common::{log, tokio};
+use std::{
+    net::{TcpListener, UdpSocket},
+    sync::Mutex,
+};
+
+// only passed on linux
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+enum Socket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl Socket {
+    fn port(&self) -> Option<u16> {
+        match self {
+            Socket::Tcp(x) => x.local_addr().ok().map(|x| x.port()),
+            Socket::Udp(x) => x.local_addr().ok().map(|x| x.port()),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SOCKETS: Mutex<Vec<Socket>> = Mutex::new(listen_fds());
+}
+
+// sd_listen_fds(3)
+#[cfg(target_os = "linux")]
+fn listen_fds() -> Vec<Socket> {
+    use nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag},
+        sys::socket::{getsockopt, sockopt, SockType},
+    };
+    use std::os::unix::io::{FromRawFd, RawFd};
+    const LISTEN_FDS_START: RawFd = 3;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let n = std::env::var("LISTEN_FDS").ok();
+    // for us only, not for child processes
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    let Some(n) = n.and_then(|x| x.parse::<RawFd>().ok()) else {
+        return Vec::new();
+    };
+    if pid.and_then(|x| x.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + n {
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).ok();
+        // SAFETY: the descriptors passed by systemd are owned by nobody else
+        match getsockopt(fd, sockopt::SockType) {
+            Ok(SockType::Stream) => {
+                sockets.push(Socket::Tcp(unsafe { TcpListener::from_raw_fd(fd) }))
+            }
+            Ok(SockType::Datagram) => {
+                sockets.push(Socket::Udp(unsafe { UdpSocket::from_raw_fd(fd) }))
+            }
+            _ => log::warn!("Ignored socket {} passed by systemd, not TCP or UDP", fd),
+        }
+    }
+    log::info!("{} sockets passed by systemd", sockets.len());
+    sockets
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listen_fds() -> Vec<Socket> {
+    Vec::new()
+}
+
+fn take(f: impl Fn(&Socket) -> bool) -> Option<Socket> {
+    let mut sockets = SOCKETS.lock().unwrap();
+    let i = sockets.iter().position(f)?;
+    Some(sockets.remove(i))
+}
+
+/// The TCP listener on `port` passed by systemd, if any.
+pub fn take_tcp(port: u16) -> Option<TcpListener> {
+    match take(|x| matches!(x, Socket::Tcp(_)) && x.port() == Some(port))? {
+        Socket::Tcp(x) => {
+            log::info!("Using tcp :{} passed by systemd", port);
+            Some(x)
+        }
+        Socket::Udp(_) => None,
+    }
+}
+
+/// The UDP socket on `port` passed by systemd, if any.
+pub fn take_udp(port: u16) -> Option<UdpSocket> {
+    match take(|x| matches!(x, Socket::Udp(_)) && x.port() == Some(port))? {
+        Socket::Udp(x) => {
+            log::info!("Using udp :{} passed by systemd", port);
+            Some(x)
+        }
+        Socket::Tcp(_) => None,
+    }
+}
+
+/// Send `state` to the service manager, if started by one, see sd_notify(3).
+pub fn notify(state: &str) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::{
+            linux::net::SocketAddrExt,
+            unix::net::{SocketAddr, UnixDatagram},
+        };
+
+        let Ok(path) = std::env::var("NOTIFY_SOCKET") else {
+            return;
+        };
+        let res = UnixDatagram::unbound().and_then(|socket| {
+            // '@' is the abstract namespace
+            let addr = match path.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name)?,
+                None => SocketAddr::from_pathname(&path)?,
+            };
+            socket.send_to_addr(state.as_bytes(), &addr)
+        });
+        if let Err(err) = res {
+            log::error!("Failed to notify systemd of {}: {}", state, err);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = state;
+}
+
+/// The listeners and the database are up: close the passed sockets left,
+/// notify READY=1 and start the watchdog keepalives.
+pub fn ready() {
+    for x in SOCKETS.lock().unwrap().drain(..) {
+        match x {
+            Socket::Tcp(x) => {
+                log::warn!("Closed unused tcp {:?} passed by systemd", x.local_addr())
+            }
+            Socket::Udp(x) => {
+                log::warn!("Closed unused udp {:?} passed by systemd", x.local_addr())
+            }
+        }
+    }
+    notify("READY=1");
+    let usec = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|x| x.parse::<u64>().ok())
+        .filter(|x| *x > 0);
+    let pid = std::env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|x| x.parse::<u32>().ok());
+    let Some(usec) = usec else {
+        return;
+    };
+    if pid.map_or(false, |x| x != std::process::id()) {
+        return;
+    }
+    let period = std::time::Duration::from_micros(usec / 2);
+    log::info!("systemd watchdog every {:?}", period);
+    // from the runtime, a stuck runtime gets the service restarted
+    tokio::spawn(async move {
+        let mut timer = tokio::time::interval(period);
+        loop {
+            timer.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
@@ -0,0 +1,206 @@
+// TLS (wss://) for the WebSocket listeners of hbbs and hbbr.
+//
+// With `tls-cert` and `tls-key` (PEM files) set, WebSocket connections are
+// served over TLS: on `wss-port` next to the plain WebSocket port if it is
+// set, otherwise on the WebSocket port itself. Both files are watched, new
+// connections use a renewed certificate as soon as it loads.
+//
+// hbbs takes the settings as options, hbbr from TLS_CERT, TLS_KEY and
+// WSS_PORT in the environment or .env.
+use crate::common::{check_key_file, data_path, get_shared_arg};
+use hbb_common::{
+    bail, log, timeout,
+    tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        net::TcpStream,
+    },
+    ResultType,
+};
+use notify::{RecursiveMode, Watcher};
+use std::{
+    io::BufReader,
+    path::Path,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+const HANDSHAKE_TIMEOUT: u64 = 10_000;
+
+/// A WebSocket connection before the WebSocket handshake, with or without TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Tls {
+    cert: String,
+    key: String,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl Tls {
+    /// TLS as configured, None if no certificate is set.
+    pub fn from_settings() -> ResultType<Option<Self>> {
+        let cert = get_shared_arg("tls-cert");
+        let key = get_shared_arg("tls-key");
+        match (cert.is_empty(), key.is_empty()) {
+            (true, true) => return Ok(None),
+            (false, false) => {}
+            _ => bail!("tls-cert and tls-key must be set together"),
+        }
+        let (cert, key) = (data_path(&cert), data_path(&key));
+        check_key_file(&key)?;
+        let acceptor = load(&cert, &key)?;
+        log::info!("TLS certificate loaded from {}", cert);
+        Ok(Some(Self {
+            cert,
+            key,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        }))
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> ResultType<Stream> {
+        let acceptor = self.acceptor.read().unwrap().clone();
+        let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await??;
+        Ok(Stream::Tls(Box::new(stream)))
+    }
+
+    fn reload(&self) {
+        match load(&self.cert, &self.key) {
+            Ok(acceptor) => {
+                *self.acceptor.write().unwrap() = acceptor;
+                log::info!("TLS certificate reloaded from {}", self.cert);
+            }
+            // e.g. the certificate is written before the key, wait for the next change
+            Err(err) => log::warn!("Failed to reload TLS certificate: {}", err),
+        }
+    }
+
+    /// Reload the certificate when the files change.
+    pub fn watch(&self) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = match notify::recommended_watcher(tx) {
+            Ok(w) => w,
+            Err(e) => {
+                log::error!("Failed to create file watcher: {}", e);
+                return;
+            }
+        };
+        // watch the directories: renewals usually replace the files or their symlinks
+        let mut names = Vec::new();
+        for path in [&self.cert, &self.key] {
+            let path = Path::new(path);
+            let dir = path
+                .parent()
+                .filter(|x| !x.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                log::error!("Failed to watch {}: {}", path.display(), e);
+                return;
+            }
+            names.push(path.file_name().map(|x| x.to_owned()));
+        }
+        let me = self.clone();
+        std::thread::spawn(move || {
+            let _watcher = watcher;
+            while let Ok(event) = rx.recv() {
+                match event {
+                    Ok(notify::Event {
+                        kind: notify::EventKind::Modify(_) | notify::EventKind::Create(_),
+                        paths,
+                        ..
+                    }) if paths
+                        .iter()
+                        .any(|x| names.iter().any(|n| x.file_name() == n.as_deref())) =>
+                    {
+                        me.reload();
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::error!("File watcher error: {}", e);
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Separate port for TLS WebSocket connections, None to use the WebSocket port.
+pub fn wss_port() -> Option<u16> {
+    get_shared_arg("wss-port").parse().ok().filter(|x| *x > 0)
+}
+
+fn load(cert: &str, key: &str) -> ResultType<TlsAcceptor> {
+    let mut reader = BufReader::new(std::fs::File::open(cert)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        bail!("No certificate found in {}", cert);
+    }
+    let mut reader = BufReader::new(std::fs::File::open(key)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::RSAKey(k)
+                | rustls_pemfile::Item::PKCS8Key(k)
+                | rustls_pemfile::Item::ECKey(k),
+            ) => break PrivateKey(k),
+            Some(_) => {}
+            None => bail!("No private key found in {}", key),
+        }
+    };
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
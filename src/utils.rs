@@ -1,3 +1,4 @@
+mod ctl;
 use dns_lookup::{lookup_addr, lookup_host};
 use hbb_common::{bail, ResultType};
 use sodiumoxide::crypto::sign;
@@ -14,7 +15,17 @@ fn print_help() {
 Available Commands:
     genkeypair                                   Generate a new keypair
     validatekeypair [public key] [secret key]    Validate an existing keypair
-    doctor [rustdesk-server]                     Check for server connection problems"
+    doctor [rustdesk-server]                     Check for server connection problems
+    ctl <hbbs|hbbr> <command> [args]             Send a command to a running server (ctl --help)
+    config check [file]                          Validate a TOML config file of hbbs and hbbr (default: $CONFIG)
+    serverkey stage <name>                       Generate a server key next to the primary one
+    serverkey promote <name> [retire-as]         Make a staged key primary, keep the primary as retire-as (default: previous)
+    serverkey list                               List the server keys
+                                                 (server keys are in DATA_DIR or the working directory)
+    keys import <file|-> [--dry-run]             Import licence keys from CSV or JSON, report invalid rows
+    keys export                                  Print the licence keys with their bindings
+                                                 (keys options: --format csv|json, --db <file>, --config <file>;
+                                                 the database is DB_URL or db_v2.sqlite3 in DATA_DIR)"
     );
     process::exit(0x0001);
 }
@@ -139,6 +150,117 @@ fn doctor(server_address_unclean: &str) {
     }
 }
 
+fn server_key(args: &[String]) -> ResultType<()> {
+    use hbbs::keyring;
+    match args.iter().map(|x| x.as_str()).collect::<Vec<_>>()[..] {
+        ["stage", name] => {
+            let pk = keyring::stage(name)?;
+            println!("Staged key {name}, public key: {pk}");
+            println!("Reload hbbs and hbbr (SIGHUP) to accept it");
+        }
+        ["promote", name] | ["promote", name, _] => {
+            let retire_as = args.get(2).map(|x| x.as_str()).unwrap_or("previous");
+            keyring::promote(name, retire_as)?;
+            println!("{name} is the primary key, the former one is {retire_as}");
+        }
+        ["list"] => {
+            for (name, pk) in keyring::list()? {
+                println!("{name:<16} {pk}");
+            }
+        }
+        _ => bail!("Usage: serverkey stage <name> | promote <name> [retire-as] | list"),
+    }
+    Ok(())
+}
+
+fn keys(args: &[String]) -> ResultType<()> {
+    use hbbs::{key_io, settings, Database};
+    const USAGE: &str = "Usage: keys import <file|-> [--dry-run] | export [--format csv|json] [--db <file>] [--config <file>]";
+    let mut words = Vec::new();
+    let mut dry_run = false;
+    let mut format = None;
+    let mut db = None;
+    let mut config = String::new();
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--format" | "--db" | "--config" => {
+                let Some(v) = it.next() else {
+                    bail!(USAGE);
+                };
+                match arg.as_str() {
+                    "--format" => match v.parse::<key_io::Format>() {
+                        Ok(v) => format = Some(v),
+                        Err(err) => bail!("{err}"),
+                    },
+                    "--db" => db = Some(v.clone()),
+                    _ => config = v.clone(),
+                }
+            }
+            _ => words.push(arg.as_str()),
+        }
+    }
+    // key formats, DB_URL and DATA_DIR, as hbbs has them
+    settings::init(settings::Server::Hbbs, &config)?;
+    let db = db.unwrap_or_else(Database::default_url);
+    if !std::path::Path::new(&db).exists() {
+        bail!("No database at {db}");
+    }
+    let rt = hbb_common::tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        match words[..] {
+            ["import", file] => {
+                let text = if file == "-" {
+                    let mut text = String::new();
+                    std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+                    text
+                } else {
+                    std::fs::read_to_string(file)?
+                };
+                let format = format.unwrap_or_else(|| key_io::Format::detect(&text));
+                let db = Database::new(&db).await?;
+                let report = key_io::import(&db, &text, format, dry_run).await?;
+                for row in report.rows.iter() {
+                    if let Some(err) = &row.error {
+                        println!("row {} ({}): {err}", row.row, row.key);
+                    }
+                }
+                println!(
+                    "{} of {} rows valid, {} imported{}",
+                    report.valid,
+                    report.total,
+                    report.imported,
+                    if dry_run { " (dry run)" } else { "" }
+                );
+                if report.valid < report.total {
+                    bail!("{} invalid row(s)", report.total - report.valid);
+                }
+            }
+            ["export"] => {
+                let db = Database::new(&db).await?;
+                let format = format.unwrap_or(key_io::Format::Csv);
+                print!("{}", key_io::export(&db, format).await?);
+            }
+            _ => bail!(USAGE),
+        }
+        Ok::<_, hbb_common::anyhow::Error>(())
+    })
+}
+
+fn check_config(path: &str) {
+    let errors = hbbs::settings::check_file(path);
+    if errors.is_empty() {
+        println!("{path} is VALID");
+        return;
+    }
+    for err in errors.iter() {
+        println!("ERROR: {err}");
+    }
+    println!("{path} has {} error(s)", errors.len());
+    process::exit(0x0001);
+}
+
 fn main() {
     let args: Vec<_> = env::args().collect();
     if args.len() <= 1 {
@@ -165,6 +287,28 @@ fn main() {
             }
             doctor(args[2].as_str());
         }
+        "ctl" => ctl::run(&args[1..]),
+        "config" => {
+            if args.len() <= 2 || args[2] != "check" {
+                error_then_help("Usage: config check [file]");
+            }
+            match args.get(3).cloned().or_else(|| env::var("CONFIG").ok()) {
+                Some(file) => check_config(&file),
+                None => error_then_help("Usage: config check [file], the file defaults to $CONFIG"),
+            }
+        }
+        "serverkey" => {
+            if let Err(e) = server_key(&args[2..]) {
+                println!("ERROR: {e}");
+                process::exit(0x0001);
+            }
+        }
+        "keys" => {
+            if let Err(e) = keys(&args[2..]) {
+                println!("ERROR: {e}");
+                process::exit(0x0001);
+            }
+        }
         _ => print_help(),
     }
 }
@@ -0,0 +1,131 @@
+#!/bin/bash
+
+# Run two hbbs nodes on localhost that share peer locations.
+# Register a client against node A (port 21116) and connect to it from a
+# client using node B (port 21126): the punch hole request is forwarded.
+# Both nodes run in the same directory, so they share the key and the database.
+#
+# The script checks that both nodes accept each other's announcements, that a
+# punch hole request sent to B reaches a peer registered on A only, that A
+# rejects B once B runs with another cluster-secret, and that a node without
+# cluster-secret does not start. The clients are a few lines of python3.
+
+BIN=${BIN:-./target/debug/hbbs}
+UTILS=${UTILS:-./target/debug/rustdesk-utils}
+SECRET=${SECRET:-cluster-test-secret}
+LOG_A=$(mktemp)
+LOG_B=$(mktemp)
+LOG_C=$(mktemp)
+
+echo "Starting node A on 21116 (cluster port 21120)..."
+RUST_LOG=debug $BIN -p 21116 --cluster-port 21120 --cluster-nodes 127.0.0.1:21130 \
+    --cluster-secret "$SECRET" >"$LOG_A" 2>&1 &
+PID_A=$!
+
+echo "Starting node B on 21126 (cluster port 21130)..."
+RUST_LOG=debug $BIN -p 21126 --cluster-port 21130 --cluster-nodes 127.0.0.1:21120 \
+    --cluster-secret "$SECRET" >"$LOG_B" 2>&1 &
+PID_B=$!
+
+trap 'kill $PID_A $PID_B $PID_C 2>/dev/null; rm -f $LOG_A $LOG_B $LOG_C' EXIT
+
+fail() {
+    echo "FAIL: $1"
+    exit 1
+}
+
+# a few announce intervals
+sleep 12
+kill -0 $PID_A 2>/dev/null || fail "node A exited: $(cat $LOG_A)"
+kill -0 $PID_B 2>/dev/null || fail "node B exited: $(cat $LOG_B)"
+grep -q "Ignore cluster message" "$LOG_A" "$LOG_B" && fail "a node rejected the other"
+
+# hbbs requires a licence key in punch hole requests, the nodes share the
+# database; the database is kept between runs, so the key and the peer ID are
+# new every time
+LICENCE_KEY=$(head -c 16 /dev/urandom | od -An -tx1 | tr -d ' \n')
+PEER_ID=clustertest$RANDOM
+OUT=$(printf 'key,expired_at\n%s,permanent\n' "$LICENCE_KEY" | $UTILS keys import - 2>&1)
+echo "$OUT" | grep -q "1 imported" || fail "cannot import the licence key: $OUT"
+
+echo "Registering a peer on node A, punch hole request through node B..."
+python3 - "$LICENCE_KEY" "$PEER_ID" <<'EOF' || fail "the punch hole request was not forwarded to node A"
+import os, socket, sys, time
+
+# protobuf by hand, the fields are those of hbb_common's rendezvous.proto
+def varint(n):
+    out = b""
+    while True:
+        b = n & 0x7F
+        n >>= 7
+        if n:
+            out += bytes([b | 0x80])
+        else:
+            return out + bytes([b])
+
+def field(num, value):
+    if isinstance(value, str):
+        value = value.encode()
+    return varint(num << 3 | 2) + varint(len(value)) + value
+
+def union(data):
+    # the field number of the RendezvousMessage union
+    n, shift = 0, 0
+    for b in data:
+        n |= (b & 0x7F) << shift
+        shift += 7
+        if not b & 0x80:
+            return n >> 3
+
+A, B = ("127.0.0.1", 21116), ("127.0.0.1", 21126)
+ID = sys.argv[2]
+peer = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+peer.settimeout(5)
+peer.bind(("127.0.0.1", 0))
+# RegisterPk = 15, then RegisterPeer = 6 so that A has the peer's address
+peer.sendto(field(15, field(1, ID) + field(2, os.urandom(16)) + field(3, os.urandom(32))), A)
+print("register pk:", union(peer.recvfrom(1024)[0]))
+peer.sendto(field(6, field(1, ID)), A)
+print("register peer:", union(peer.recvfrom(1024)[0]))
+# A announces its peers every 5 seconds
+time.sleep(7)
+requester = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+requester.settimeout(5)
+# PunchHoleRequest = 8 with id = 1 and licence_key = 3
+requester.sendto(field(8, field(1, ID) + field(3, sys.argv[1])), B)
+deadline = time.time() + 5
+while time.time() < deadline:
+    try:
+        data = peer.recvfrom(1024)[0]
+    except socket.timeout:
+        break
+    # PunchHole = 9, or FetchLocalAddr = 12 as both clients are on localhost
+    if union(data) in (9, 12):
+        print("forwarded:", union(data))
+        sys.exit(0)
+requester.settimeout(0.5)
+try:
+    print("answer to the requester:", union(requester.recvfrom(1024)[0]))
+except socket.timeout:
+    pass
+sys.exit(1)
+EOF
+grep -q "$PEER_ID" "$LOG_B" || fail "node B did not handle the punch hole request"
+
+echo "Restarting node B with another secret..."
+kill $PID_B
+wait $PID_B 2>/dev/null
+RUST_LOG=debug $BIN -p 21126 --cluster-port 21130 --cluster-nodes 127.0.0.1:21120 \
+    --cluster-secret "wrong-$SECRET" >"$LOG_B" 2>&1 &
+PID_B=$!
+sleep 12
+grep -q "Ignore cluster message from 127.0.0.1:21130: bad signature" "$LOG_A" ||
+    fail "node A accepted node B with another secret"
+
+echo "Starting a node without cluster-secret, it must exit..."
+$BIN -p 21146 --cluster-port 21150 --cluster-nodes 127.0.0.1:21130 >"$LOG_C" 2>&1 &
+PID_C=$!
+sleep 3
+kill -0 $PID_C 2>/dev/null && fail "node without cluster-secret is running"
+
+echo "OK"